@@ -1,134 +1,286 @@
 use std::sync::Arc;
-use tokio::sync::Mutex;
-use tokio::sync::mpsc;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 use tokio_tungstenite::connect_async;
 use tokio_tungstenite::tungstenite::protocol::Message;
 use futures_util::{StreamExt, SinkExt};
-use std::error::Error;
 use reqwest::Client;
 use reqwest::header::HeaderValue;
+use serde::de::DeserializeOwned;
 use serde_json::json;
-use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC, AsciiSet, CONTROLS};
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
 use ring::hmac::{Key, HMAC_SHA256, sign};
 use once_cell::sync::Lazy;
 use crate::crypto_utils::Credentials;
 use crate::api_utils::*;
+use crate::broker::Broker;
+use crate::error::{Error, Result};
 
+// Default watched underlyings, see Config
 pub static BASE_TICKERS: Lazy<Vec<String>> = Lazy::new(|| {
     vec!["QQQ.US".to_string(), "SPY.US".to_string()]
 });
 
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum ConnectionStatus {
-    Connected,
     Disconnected,
+    Connecting,
+    Connected,
+    Reconnecting,
+    Failed,
 }
 
 impl ConnectionStatus {
     pub fn description(&self) -> &str {
         match self {
-            ConnectionStatus::Connected => "online",
             ConnectionStatus::Disconnected => "offline",
+            ConnectionStatus::Connecting => "connecting",
+            ConnectionStatus::Connected => "online",
+            ConnectionStatus::Reconnecting => "reconnecting",
+            ConnectionStatus::Failed => "failed",
         }
     }
 }
 
 pub struct ConnectionChannels {
     pub sender_to_connector: mpsc::UnboundedSender<String>,
-    pub sender_to_ui: mpsc::UnboundedSender<String>,
+    pub sender_to_ui: mpsc::Sender<String>,
 }
 
 pub struct Connection {
     pub credentials: Credentials,
+    pub broker: Arc<dyn Broker>,
     pub channels: ConnectionChannels,
     pub query_tickers: Vec<String>,
-    pub status: ConnectionStatus,
+    pub status: watch::Receiver<ConnectionStatus>,
 }
 
 impl Connection {
-    pub fn new(credentials: Credentials, sender_to_connector: UnboundedSender<String>, sender_to_ui: UnboundedSender<String>) -> Self {
+    pub fn new(credentials: Credentials, broker: Arc<dyn Broker>, sender_to_connector: UnboundedSender<String>, sender_to_ui: mpsc::Sender<String>, status: watch::Receiver<ConnectionStatus>, query_tickers: Vec<String>) -> Self {
         Connection {
             credentials,
+            broker,
             channels: ConnectionChannels {
                 sender_to_connector,
                 sender_to_ui,
             },
-            query_tickers: BASE_TICKERS.clone(),
-            status: ConnectionStatus::Disconnected,
+            query_tickers,
+            status,
         }
     }
 }
 
+const RECONNECT_MIN_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60);
+
+// Supervised connection to the Freedom24 WebSocket.
+// On any loss of the socket a new SID is requested and the last request of every command
+// (quotes, orderBook, portfolio...) is sent again, so subscriptions survive reconnects.
+// Returns when the GUI side of the channel is closed. While sender is full the socket is not read.
 pub async fn connect_to_ff_ws(
     credentials: Credentials,
     mut receiver: UnboundedReceiver<String>,
-    sender: UnboundedSender<String>,
-    ) -> Result<(), Box<dyn Error>> {
-    let sid = get_sid_ff(credentials.clone()).await;
-    let ws_url = WS_API_FF_URL.to_string() + FF_SID + &sid;
-    let url = ws_url.as_str();
-    let (ws_stream, _) = connect_async(url).await.expect("Failed to connect.");
-    let (mut write, mut read) = ws_stream.split();
-    let mut write = Arc::new(Mutex::new(write));
-
-    // Receive messages from Freedom
+    sender: mpsc::Sender<String>,
+    status: watch::Sender<ConnectionStatus>,
+    ) -> Result<()> {
     let id = credentials.id.clone();
-    let write_clone = write.clone();
-    let sender_clone = sender.clone();
-    tokio::spawn(async move {
-        while let Some(message) = read.next().await {
-            let mut write_clone = write_clone.lock().await;
-            match message {
-                Ok(Message::Text(text)) => {
-                    sender_clone.send(text).expect("Failed to send message");
-                },
-                Ok(Message::Binary(bin)) => println!("{} Binary data received: {:?}\n", id, bin),
-                Ok(Message::Frame(frame)) => println!("{} Text message received: {}\n", id, frame),
-                Ok(Message::Ping(ping)) => write_clone.send(tokio_tungstenite::tungstenite::protocol::Message::Pong(ping)).await.expect("Ping Error"),
-                Ok(Message::Pong(_)) => println!("{} Ping answer received.\n", id),
-                Ok(Message::Close(_)) => { println!("{} Connection closed.\n", id); break; }
-                Err(err) => { println!("{} Error at {}: {}", id, chrono::Local::now(), err); break; },
+    // Last request for every command, in the order of the first request
+    let mut subscriptions: Vec<(String, String)> = Vec::new();
+    let mut delay = RECONNECT_MIN_DELAY;
+    let mut connected_before = false;
+    loop {
+        let _ = status.send(if connected_before { ConnectionStatus::Reconnecting } else { ConnectionStatus::Connecting });
+        let ws_stream = match get_sid_ff(credentials.clone()).await {
+            Ok(sid) => {
+                let ws_url = credentials.endpoints.ws_url.clone() + FF_SID + &sid;
+                connect_async(ws_url.as_str()).await.map(|(ws_stream, _)| ws_stream).map_err(Error::from)
+            }
+            Err(e) => Err(e),
+        };
+        let ws_stream = match ws_stream {
+            Ok(ws_stream) => ws_stream,
+            Err(e) => {
+                println!("{} Connection failed at {}: {}. Next attempt in {:?}", id, chrono::Local::now(), e, delay);
+                let _ = status.send(ConnectionStatus::Failed);
+                tokio::time::sleep(delay).await;
+                delay = Duration::min(delay * 2, RECONNECT_MAX_DELAY);
+                continue;
+            }
+        };
+        let _ = status.send(ConnectionStatus::Connected);
+        connected_before = true;
+        delay = RECONNECT_MIN_DELAY;
+        let (mut write, mut read) = ws_stream.split();
+
+        // Restore subscriptions
+        let mut resubscribed = true;
+        for (_, message) in subscriptions.iter() {
+            if let Err(e) = write.send(Message::Text(message.clone())).await {
+                println!("{} Failed to restore subscription: {}", id, e);
+                resubscribed = false;
+                break;
             }
-        }        
-    });
-    // Receive messages from GUI
-    let id = credentials.id.clone();
-    let mut write_clone = write.clone();
-    tokio::spawn(async move {
-        while let Some(message) = receiver.recv().await {
-            let mut write_clone = write_clone.lock().await;
-            write_clone.send(Message::Text(message)).await.expect("Failed to send message");
         }
-    });
-    Ok(())
+
+        if resubscribed {
+            loop {
+                tokio::select! {
+                    // Receive messages from Freedom
+                    message = read.next() => match message {
+                        Some(Ok(Message::Text(text))) => {
+                            // The pipeline is gone
+                            if sender.send(text).await.is_err() {
+                                let _ = write.close().await;
+                                let _ = status.send(ConnectionStatus::Disconnected);
+                                return Ok(());
+                            }
+                        },
+                        Some(Ok(Message::Binary(bin))) => println!("{} Binary data received: {:?}\n", id, bin),
+                        Some(Ok(Message::Frame(frame))) => println!("{} Text message received: {}\n", id, frame),
+                        Some(Ok(Message::Ping(ping))) => {
+                            if let Err(e) = write.send(Message::Pong(ping)).await {
+                                println!("{} Ping Error: {}", id, e);
+                                break;
+                            }
+                        },
+                        Some(Ok(Message::Pong(_))) => println!("{} Ping answer received.\n", id),
+                        Some(Ok(Message::Close(_))) | None => { println!("{} Connection closed at {}.\n", id, chrono::Local::now()); break; }
+                        Some(Err(err)) => { println!("{} Error at {}: {}", id, chrono::Local::now(), err); break; },
+                    },
+                    // Receive messages from GUI
+                    message = receiver.recv() => match message {
+                        Some(message) => {
+                            remember_subscription(&mut subscriptions, &message);
+                            if let Err(e) = write.send(Message::Text(message)).await {
+                                println!("{} Failed to send message: {}", id, e);
+                                break;
+                            }
+                        },
+                        None => {
+                            let _ = write.close().await;
+                            let _ = status.send(ConnectionStatus::Disconnected);
+                            return Ok(());
+                        }
+                    },
+                }
+            }
+        }
+        let _ = status.send(ConnectionStatus::Reconnecting);
+        tokio::time::sleep(delay).await;
+    }
+}
+
+// Keep the last request of each command to repeat it after reconnect
+fn remember_subscription(subscriptions: &mut Vec<(String, String)>, message: &str) {
+    let cmd = serde_json::from_str::<Vec<serde_json::Value>>(message)
+        .ok()
+        .and_then(|values| values.first().and_then(|v| v.as_str()).map(|cmd| cmd.to_string()));
+    if let Some(cmd) = cmd {
+        if let Some(subscription) = subscriptions.iter_mut().find(|(subscription_cmd, _)| *subscription_cmd == cmd) {
+            subscription.1 = message.to_string();
+        } else {
+            subscriptions.push((cmd, message.to_string()));
+        }
+    }
 }
 
 const FRAGMENT: &AsciiSet = &CONTROLS.add(b'+');
-pub async fn send_order (public_key: String, secret_key: String, ticker: String, action: ActionType, order: OrderType, price: f64, qty: u64, expiration: Expirations) -> Result<(), ()> {
-    let client = Client::new();
-    let url = "https://tradernet.com/api/v2/cmd/putTradeOrder";
-    let current_time = chrono::Utc::now().timestamp_millis().to_string();
-    let nonce = current_time.as_str();
-
-    let cmd = "putTradeOrder";
-    let mut params = json!({
-        "instr_name": ticker,
-        "action_id": action.ff_code(),
-        "order_type_id": order.ff_code(),
-        "qty": qty,
-        "expiration_id": expiration.ff_code()
-    });
-    if price != 0.0 {
-        params["limit_price"] = json!(price);
+
+// Signed client of the Tradernet API v2 for one account.
+// Holds one pooled HTTP client and issues strictly increasing nonces
+pub struct TradernetClient {
+    public_key: String,
+    secret_key: String,
+    cmd_url: String,
+    http_client: Client,
+    last_nonce: AtomicI64,
+}
+impl TradernetClient {
+    pub fn new(public_key: &str, secret_key: &str, cmd_url: &str) -> Self {
+        TradernetClient {
+            public_key: public_key.to_string(),
+            secret_key: secret_key.to_string(),
+            cmd_url: cmd_url.to_string(),
+            http_client: Client::new(),
+            last_nonce: AtomicI64::new(0),
+        }
+    }
+
+    // Millisecond timestamp, bumped when two requests go out in the same millisecond
+    fn next_nonce(&self) -> i64 {
+        let now = chrono::Utc::now().timestamp_millis();
+        let previous = self.last_nonce
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| Some(i64::max(now, last + 1)))
+            .unwrap_or(now);
+        i64::max(now, previous + 1)
+    }
+
+    // Signed POST request with any command. The response body is parsed into T
+    pub async fn call<T: DeserializeOwned>(&self, cmd: &str, params: serde_json::Value) -> Result<T> {
+        let url = format!("{}{}", self.cmd_url, cmd);
+        let nonce = self.next_nonce().to_string();
+        let (string_for_sign, string_for_request) = encode_cmd(&self.public_key, cmd, &nonce, &params);
+        let signature = sign_cmd(&self.secret_key, &string_for_sign);
+
+        let mut headers = reqwest::header::HeaderMap::new();
+        let content_type = reqwest::header::HeaderValue::from_static("application/x-www-form-urlencoded");
+        headers.insert(reqwest::header::CONTENT_TYPE, content_type);
+        let signature_value = HeaderValue::from_str(signature.as_str()).map_err(|e| Error::Crypto(e.to_string()))?;
+        headers.insert("X-NtApi-Sig",signature_value);
+
+        let response = self.http_client
+            .post(url)
+            .headers(headers)
+            .body(string_for_request)
+            .send()
+            .await?;
+        // println!("Status: {:?}", response.status());
+        // println!("Headers:\n{:#?}", response.headers());
+        let response_text = response.text().await?;
+        let parsed_response_text: serde_json::Value = serde_json::from_str(&response_text)?;
+        if let Some((code, message)) = broker_error(&parsed_response_text) {
+            return Err(Error::BrokerRejection { code, message });
+        }
+        Ok(serde_json::from_value(parsed_response_text)?)
     }
 
-    let string_header = format!("apiKey={}&cmd={}&nonce={}", public_key.as_str(), cmd, nonce); /* Проверить необходимость as_str() */
+    pub async fn send_order(&self, ticker: String, action: ActionType, order: OrderType, price: f64, qty: u64, expiration: Expirations) -> Result<OrderAck> {
+        let mut params = json!({
+            "instr_name": ticker,
+            "action_id": action.ff_code(),
+            "order_type_id": order.ff_code(),
+            "qty": qty,
+            "expiration_id": expiration.ff_code()
+        });
+        if price != 0.0 {
+            params["limit_price"] = json!(price);
+        }
+
+        println!("Order sending {}", chrono::Local::now());
+        let order_ack = self.call("putTradeOrder", params).await?;
+        println!("Response received {}", chrono::Local::now());
+        Ok(order_ack)
+    }
+
+    pub async fn cancel_order(&self, order_id: i64) -> Result<OrderAck> {
+        self.call("delTradeOrder", json!({ "order_id": order_id })).await
+    }
+
+    pub async fn get_positions(&self) -> Result<serde_json::Value> {
+        self.call("getPositionJson", json!({})).await
+    }
+}
+
+// Strings for signing and for the body of a v2 command. Params are sorted by key
+pub fn encode_cmd(public_key: &str, cmd: &str, nonce: &str, params: &serde_json::Value) -> (String, String) {
+    let string_header = format!("apiKey={}&cmd={}&nonce={}", public_key, cmd, nonce);
     let mut params_pairs_for_sign = Vec::new();
     let mut params_pairs_for_request = Vec::new();
     if let serde_json::Value::Object(map) = params {
         for(key, value) in map.iter() {
-            let mut value_string = match value {
+            let value_string = match value {
                 serde_json::Value::String(s) => s.clone(),
                 serde_json::Value::Number(n) => n.to_string(),
                 _ => value.to_string(),
@@ -145,32 +297,18 @@ pub async fn send_order (public_key: String, secret_key: String, ticker: String,
     let params_string_for_request = params_pairs_for_request.join("&");
 
     let string_for_sign = format!("{}&params={}",string_header, params_string_for_sign);
-    let string_for_request = format!("{}&{}",string_header, params_string_for_request);
+    let string_for_request = if params_string_for_request.is_empty() {
+        string_header
+    } else {
+        format!("{}&{}",string_header, params_string_for_request)
+    };
     // println!("For sign: {}\nFor request: {}",string_for_sign,string_for_request);
+    (string_for_sign, string_for_request)
+}
 
-    let key = Key::new(HMAC_SHA256,secret_key.as_str().as_bytes()); /* Проверить необходимость as_str() */
+// Hex HMAC-SHA256 signature for the X-NtApi-Sig header
+pub fn sign_cmd(secret_key: &str, string_for_sign: &str) -> String {
+    let key = Key::new(HMAC_SHA256, secret_key.as_bytes());
     let hmac_sha256_signature = sign(&key, string_for_sign.as_bytes());
-    let signature = hex::encode(hmac_sha256_signature.as_ref());
-
-    let mut headers = reqwest::header::HeaderMap::new();
-    let content_type = reqwest::header::HeaderValue::from_static("application/x-www-form-urlencoded");
-    headers.insert(reqwest::header::CONTENT_TYPE, content_type);
-    let signature_value = HeaderValue::from_str(signature.as_str()).expect("Failed to create signature header value");
-    headers.insert("X-NtApi-Sig",signature_value);
-
-    println!("Order sending {}", chrono::Local::now());
-    let response = client
-        .post(url)
-        .headers(headers)
-        .body(string_for_request)
-        .send()
-        .await
-        .expect("Error sending POST request");
-    println!("Response received {}", chrono::Local::now());
-    // println!("Status: {:?}", response.status());
-    // println!("Headers:\n{:#?}", response.headers());
-    // let response_text = response.text().await.unwrap();
-    // println!("Text {}", response_text);
-
-    Ok(())
-}
\ No newline at end of file
+    hex::encode(hmac_sha256_signature.as_ref())
+}
@@ -2,12 +2,31 @@ use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use crate::crypto_utils::Credentials;
+use crate::error::{Error, Result};
 
 pub const HTTPS_API_FF_URL: &str = "https://tradernet.com/api/check-login-password";
 pub const WS_API_FF_URL: &str = "wss://wss.tradernet.com/";
+pub const FF_API_V2_CMD_URL: &str = "https://tradernet.com/api/v2/cmd/";
 pub const FF_GET_SID: &str = "/api/check-login-password";
 pub const FF_SID: &str = "?SID=";
 
+// Addresses of the Tradernet API. An account can be pointed at another server, e.g. a local mock
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Endpoints {
+    pub login_url: String, // check-login-password
+    pub ws_url: String, // WebSocket feed, SID is appended
+    pub cmd_url: String, // v2 commands, cmd is appended
+}
+impl Default for Endpoints {
+    fn default() -> Self {
+        Endpoints {
+            login_url: HTTPS_API_FF_URL.to_string(),
+            ws_url: WS_API_FF_URL.to_string(),
+            cmd_url: FF_API_V2_CMD_URL.to_string(),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct AuthMessage {
     login: String,
@@ -25,19 +44,36 @@ impl AuthMessage {
 }
 
 // Receive Security ID (sid) from Freedom24
-pub async fn get_sid_ff(credentials: Credentials) -> String {
+pub async fn get_sid_ff(credentials: Credentials) -> Result<String> {
     // Creating the request
     let client = Client::new();
     let auth_message = AuthMessage::new (credentials.login.as_str(), credentials.password.as_str());
     let mut headers = reqwest::header::HeaderMap::new();
     let content_type = reqwest::header::HeaderValue::from_static("application/x-www-form-urlencoded");
     headers.insert(reqwest::header::CONTENT_TYPE, content_type);
-    let urlencoded_message = serde_urlencoded::to_string(&auth_message).unwrap();
+    let urlencoded_message = serde_urlencoded::to_string(&auth_message)?;
     // Sending POST request
-    let response = client.post(HTTPS_API_FF_URL).headers(headers).body(urlencoded_message).send().await.expect("Error sending POST request");
-    let response_text = response.text().await.unwrap();
-    let parsed_response_text: serde_json::Value = serde_json::from_str(&response_text).expect("Failed to parse json");
-    parsed_response_text["SID"].as_str().unwrap().to_string()
+    let response = client.post(&credentials.endpoints.login_url).headers(headers).body(urlencoded_message).send().await?;
+    let response_text = response.text().await?;
+    let parsed_response_text: serde_json::Value = serde_json::from_str(&response_text)?;
+    if let Some((_, message)) = broker_error(&parsed_response_text) {
+        return Err(Error::Auth(format!("{} login rejected: {}", credentials.id, message)));
+    }
+    match parsed_response_text["SID"].as_str() {
+        Some(sid) => Ok(sid.to_string()),
+        None => Err(Error::Parse(format!("No SID in response: {}", response_text))),
+    }
+}
+
+// Error code and message if the response of Tradernet is an error
+pub fn broker_error(response: &serde_json::Value) -> Option<(Option<i64>, String)> {
+    let message = response.get("errMsg")
+        .or_else(|| response.get("error"))
+        .map(|message| match message {
+            serde_json::Value::String(s) => s.clone(),
+            _ => message.to_string(),
+        })?;
+    Some((response["code"].as_i64(), message))
 }
 
 // WS requests
@@ -69,6 +105,26 @@ impl Request {
     }
 }
 
+// Broker response to an order command
+#[derive(Debug, Clone)]
+pub struct OrderAck {
+    pub order_id: Option<i64>,
+    pub response: serde_json::Value, // Parsed response body
+}
+impl OrderAck {
+    pub fn new(response: serde_json::Value) -> Self {
+        OrderAck {
+            order_id: response["order_id"].as_i64(),
+            response,
+        }
+    }
+}
+impl<'de> Deserialize<'de> for OrderAck {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        serde_json::Value::deserialize(deserializer).map(OrderAck::new)
+    }
+}
+
 // Parameters for sending orders
 #[derive(Debug, Clone, PartialEq)]
 pub enum ActionType {
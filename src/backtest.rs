@@ -0,0 +1,269 @@
+use std::collections::HashMap;
+use serde::Deserialize;
+use crate::candles::CandleBook;
+use crate::config::Config;
+use crate::market_data::{deserialize_message, MarketData};
+use crate::processed_data::{DepthUpdate, OrderBook, Position};
+use crate::recorder::RecordedMessage;
+use crate::trading_utils::{check_sl, stop_hit, upgrade_sl, SLStrategy, SLType, StopContext};
+
+// Simulated position entry
+#[derive(Debug, Clone, Deserialize)]
+pub struct SimulatedEntry {
+    pub ticker: String,
+    pub time: f64, // Seconds since the start of the recording
+    pub price: f64,
+    #[serde(default = "default_quantity")]
+    pub quantity: i32,
+    #[serde(default)]
+    pub strategy: Option<SLStrategy>, // InsuranceStops if not set
+    #[serde(default)]
+    pub upgrades: Vec<f64>, // Seconds since the start of the recording when the stop is moved one step closer, as with the upgrade button
+}
+fn default_quantity() -> i32 {
+    1
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ExitReason {
+    StopLoss(SLType), // Stop type at the moment of exit
+    EndOfData, // Position was still open when the recording ended
+}
+impl ExitReason {
+    pub fn description(&self) -> String {
+        match self {
+            ExitReason::StopLoss(sl_type) => format!("stop ({})", sl_type.description()),
+            ExitReason::EndOfData => "end of data".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TradeResult {
+    pub entry: SimulatedEntry,
+    pub exit_reason: ExitReason,
+    pub exit_price: f64,
+    pub exit_time: f64, // Seconds since the start of the recording
+    pub pnl: f64,
+    pub max_adverse_excursion: f64, // Worst unrealised loss, positive value
+    pub max_favorable_excursion: f64, // Best unrealised profit
+}
+
+#[derive(Debug, Clone)]
+pub struct BacktestReport {
+    pub trades: Vec<TradeResult>,
+    pub win_rate: f64,
+    pub expectancy: f64, // Average PnL per trade
+    pub total_pnl: f64,
+}
+impl BacktestReport {
+    fn new(trades: Vec<TradeResult>) -> Self {
+        let total_pnl: f64 = trades.iter().map(|trade| trade.pnl).sum();
+        let wins = trades.iter().filter(|trade| trade.pnl > 0.0).count();
+        let (win_rate, expectancy) = if trades.is_empty() {
+            (0.0, 0.0)
+        } else {
+            (wins as f64 / trades.len() as f64, total_pnl / trades.len() as f64)
+        };
+        Self { trades, win_rate, expectancy, total_pnl }
+    }
+    pub fn print(&self) {
+        println!("{:<30} {:>10} {:>8} {:>10} {:>10} {:>20} {:>10} {:>10} {:>10}",
+            "Ticker", "Entry at", "Entry", "Exit at", "Exit", "Exit reason", "PNL", "MAE", "MFE");
+        for trade in &self.trades {
+            println!("{:<30} {:>10.1} {:>8.2} {:>10.1} {:>10.2} {:>20} {:>10.2} {:>10.2} {:>10.2}",
+                trade.entry.ticker, trade.entry.time, trade.entry.price, trade.exit_time, trade.exit_price,
+                trade.exit_reason.description(), trade.pnl, trade.max_adverse_excursion, trade.max_favorable_excursion);
+        }
+        println!("Trades: {}, win rate: {:.1}%, expectancy: {:.2}, total PNL: {:.2}",
+            self.trades.len(), self.win_rate * 100.0, self.expectancy, self.total_pnl);
+    }
+}
+
+// Current price update taken from the recording the same way DataProcessor does: from inserted bid rows
+struct PriceUpdate {
+    time: f64, // Seconds since the start of the recording
+    ticker: String,
+    price: f64,
+    candle_low: Option<f64>, // Lower tail of the candles completed by then, as CandleBuilder builds them
+    min_step: Option<f64>, // Of the last quote
+    volatility: Option<f64>,
+}
+
+fn price_updates(messages: &[RecordedMessage], config: &Config) -> Vec<PriceUpdate> {
+    let mut updates = Vec::new();
+    let mut candle_book = CandleBook::new("");
+    let mut order_book = OrderBook::new("");
+    let mut quotes: HashMap<String, (Option<f64>, Option<f64>)> = HashMap::new();
+    for message in messages {
+        match deserialize_message(&message.data) {
+            Some(MarketData::QuoteMessage(quote_message)) => {
+                if let Some(ticker) = &quote_message.c {
+                    candle_book.add_quote(&quote_message, config.stop_parameters(ticker).candle_interval);
+                    let (min_step, volatility) = quotes.entry(ticker.clone()).or_default();
+                    *min_step = quote_message.min_step.or(*min_step);
+                    *volatility = quote_message.volatility.or(*volatility);
+                }
+            }
+            Some(MarketData::OrderBookMessage(order_book_message)) => {
+                // Prices are the best bids of the book, as DataProcessor sends them
+                if order_book.apply(&order_book_message) != DepthUpdate::Applied {
+                    continue;
+                }
+                let ticker = &order_book_message.i;
+                let Some(best_bid) = order_book.best_bid(ticker) else {
+                    continue;
+                };
+                let candle_low = candle_book.series(ticker).and_then(|series| series.tail_stop(0.0));
+                let (min_step, volatility) = quotes.get(ticker).copied().unwrap_or_default();
+                updates.push(PriceUpdate {
+                    time: message.ts as f64 / 1_000_000.0,
+                    ticker: ticker.clone(),
+                    price: best_bid,
+                    candle_low,
+                    min_step,
+                    volatility,
+                });
+            }
+            _ => {}
+        }
+    }
+    updates
+}
+
+// Run the stop-loss state machine for every entry against the recorded price stream
+pub fn run_backtest(messages: &[RecordedMessage], entries: &[SimulatedEntry], config: &Config) -> BacktestReport {
+    let updates = price_updates(messages, config);
+    // Recording times are mapped on a clock for the time in trade conditions
+    let started = chrono::Local::now();
+    let at = |time: f64| started + chrono::Duration::microseconds((time * 1_000_000.0) as i64);
+    let mut trades = Vec::new();
+    for (number, entry) in entries.iter().enumerate() {
+        let mut position = Position::new(number as i64 + 1, &entry.ticker, entry.quantity, entry.price);
+        position.sl_strategy = entry.strategy.clone().unwrap_or(SLStrategy::InsuranceStops);
+        position.opened_at = at(entry.time);
+        let stop_parameters = config.stop_parameters(&entry.ticker);
+        let mut max_adverse_excursion: f64 = 0.0;
+        let mut max_favorable_excursion: f64 = 0.0;
+        let mut exit = None;
+        let mut upgrades = entry.upgrades.clone();
+        upgrades.sort_by(f64::total_cmp);
+        let mut upgrades = upgrades.into_iter().peekable();
+        for update in updates.iter().filter(|update| update.time >= entry.time && update.ticker == entry.ticker) {
+            // Same sequence as PortfolioUpdater
+            position.current_price = update.price;
+            position.pnl = (position.current_price - position.open_price) * position.quantity as f64;
+            max_adverse_excursion = f64::max(max_adverse_excursion, -position.pnl);
+            max_favorable_excursion = f64::max(max_favorable_excursion, position.pnl);
+            position.distance_mode = stop_parameters.mode;
+            match stop_parameters.in_points(position.open_price, update.min_step, update.volatility) {
+                Some(parameters) => {
+                    let context = StopContext {
+                        parameters,
+                        rules: &config.rules,
+                        candle_stop: update.candle_low.map(|candle_low| candle_low - parameters.candle_offset),
+                        now: at(update.time),
+                    };
+                    // Upgrades take effect at the first price at or after their time
+                    while upgrades.next_if(|upgrade| *upgrade <= update.time).is_some() {
+                        (position.sl_type, position.sl_price) = upgrade_sl(&position, &context);
+                    }
+                    (position.sl_type, position.sl_price, position.close_alert) = check_sl(&position, &context);
+                }
+                // Same as PortfolioUpdater without the volatility: the stop is only checked
+                None => position.close_alert |= stop_hit(&position),
+            }
+            if position.close_alert {
+                exit = Some((ExitReason::StopLoss(position.sl_type), update.price, update.time));
+                break;
+            }
+        }
+        let (exit_reason, exit_price, exit_time) = exit.unwrap_or_else(|| {
+            let last_time = updates.last().map(|update| update.time).unwrap_or(entry.time);
+            let exit_price = if position.current_price != 0.0 { position.current_price } else { entry.price };
+            (ExitReason::EndOfData, exit_price, last_time)
+        });
+        trades.push(TradeResult {
+            entry: entry.clone(),
+            exit_reason,
+            exit_price,
+            exit_time,
+            pnl: (exit_price - entry.price) * entry.quantity as f64,
+            max_adverse_excursion,
+            max_favorable_excursion,
+        });
+    }
+    BacktestReport::new(trades)
+}
+
+pub fn load_entries(file_path: &str) -> Result<Vec<SimulatedEntry>, Box<dyn std::error::Error>> {
+    let data = std::fs::read_to_string(file_path)?;
+    Ok(serde_json::from_str(&data)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_server::order_book_messages;
+
+    fn entry(price: f64, quantity: i32) -> SimulatedEntry {
+        SimulatedEntry { ticker: "SPY.US".to_string(), time: 0.0, price, quantity, strategy: None, upgrades: Vec::new() }
+    }
+
+    fn trade(pnl: f64, max_adverse_excursion: f64, max_favorable_excursion: f64) -> TradeResult {
+        TradeResult {
+            entry: entry(500.0, 1),
+            exit_reason: ExitReason::EndOfData,
+            exit_price: 500.0 + pnl,
+            exit_time: 0.0,
+            pnl,
+            max_adverse_excursion,
+            max_favorable_excursion,
+        }
+    }
+
+    // Bids of SPY.US one second apart
+    fn recording(bids: &[f64]) -> Vec<RecordedMessage> {
+        let quotes: Vec<(f64, f64)> = bids.iter().map(|bid| (*bid, bid + 0.05)).collect();
+        order_book_messages("SPY.US", &quotes).into_iter().enumerate().map(|(number, data)| RecordedMessage {
+            id: "test".to_string(),
+            ts: number as u64 * 1_000_000,
+            data,
+        }).collect()
+    }
+
+    #[test]
+    fn report_sums_up_the_trades() {
+        let report = BacktestReport::new(vec![trade(0.3, 0.1, 0.4), trade(-0.1, 0.1, 0.0), trade(0.0, 0.0, 0.0), trade(0.2, 0.05, 0.2)]);
+        assert_eq!(report.win_rate, 0.5);
+        assert!((report.total_pnl - 0.4).abs() < 1e-9);
+        assert!((report.expectancy - 0.1).abs() < 1e-9);
+        let empty = BacktestReport::new(Vec::new());
+        assert_eq!((empty.win_rate, empty.expectancy, empty.total_pnl), (0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn excursions_are_tracked_until_the_exit() {
+        // Loss limiter 0.1 under the price with the default parameters, 499.94 from 500.04
+        let report = run_backtest(&recording(&[500.0, 500.04, 499.97, 499.9, 501.0]), &[entry(500.0, 2)], &Config::default());
+        let trade = &report.trades[0];
+        assert_eq!(trade.exit_reason, ExitReason::StopLoss(SLType::LossLimiter));
+        assert_eq!((trade.exit_price, trade.exit_time), (499.9, 3.0));
+        assert!((trade.pnl + 0.2).abs() < 1e-9);
+        assert!((trade.max_adverse_excursion - 0.2).abs() < 1e-9);
+        assert!((trade.max_favorable_excursion - 0.08).abs() < 1e-9);
+    }
+
+    #[test]
+    fn upgrade_moves_the_stop_at_its_time() {
+        let messages = recording(&[500.0, 500.05, 500.01, 500.0]);
+        let without_upgrade = run_backtest(&messages, &[entry(500.0, 1)], &Config::default());
+        assert_eq!(without_upgrade.trades[0].exit_reason, ExitReason::EndOfData);
+        // Break-even at 500.02 from the price of the second second
+        let upgraded = SimulatedEntry { upgrades: vec![1.0], ..entry(500.0, 1) };
+        let with_upgrade = run_backtest(&messages, &[upgraded], &Config::default());
+        let trade = &with_upgrade.trades[0];
+        assert_eq!(trade.exit_reason, ExitReason::StopLoss(SLType::BreakEven));
+        assert_eq!((trade.exit_price, trade.exit_time), (500.01, 2.0));
+    }
+}
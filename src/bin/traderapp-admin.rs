@@ -0,0 +1,297 @@
+// Provisioning of the encrypted vault: master key, broker credentials and users.
+// Secrets are read from files (first line) or prompted, so every command can be scripted.
+use std::collections::HashMap;
+use std::fs;
+use trader_app::crypto_utils::{self, Credentials, CREDENTIALS_FILE, USERS_FILE};
+use trader_app::error::{Error, Result};
+
+const USAGE: &str = "Usage: traderapp-admin [--users <path>] [--credentials <path>] [--master-key-file <path>] <command>
+Commands:
+  init [--force]                                  New master key and empty credentials file
+  rotate-key [--passwords <path>]                 New master key, re-wrapped for the users in the file
+  credentials list
+  credentials add <json-file> [--replace]         Credentials object or array of them
+  credentials remove <id>
+  users list
+  users add <email> <id1,id2> [--password-file <path>]
+  users remove <email>
+  users passwd <email> [--password-file <path>] [--new-password-file <path>]
+  users migrate <email> [--password-file <path>]  Rewrite the user and credentials in the current format
+The master key is read from --master-key-file, TRADERAPP_MASTER_KEY or the terminal.
+The passwords file has a line \"<email> <password>\" per user. At rotation the new key replaces --master-key-file or is printed.";
+
+struct Options {
+    users_file: String,
+    credentials_file: String,
+    master_key_file: Option<String>,
+    password_file: Option<String>,
+    new_password_file: Option<String>,
+    passwords_file: Option<String>,
+    force: bool,
+    replace: bool,
+    command: Vec<String>,
+}
+impl Options {
+    fn from_args() -> Result<Self> {
+        let mut options = Options {
+            users_file: USERS_FILE.to_string(),
+            credentials_file: CREDENTIALS_FILE.to_string(),
+            master_key_file: None,
+            password_file: None,
+            new_password_file: None,
+            passwords_file: None,
+            force: false,
+            replace: false,
+            command: Vec::new(),
+        };
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| Error::Config(format!("Missing value of {}", arg)));
+            match arg.as_str() {
+                "--users" => options.users_file = value()?,
+                "--credentials" => options.credentials_file = value()?,
+                "--master-key-file" => options.master_key_file = Some(value()?),
+                "--password-file" => options.password_file = Some(value()?),
+                "--new-password-file" => options.new_password_file = Some(value()?),
+                "--passwords" => options.passwords_file = Some(value()?),
+                "--force" => options.force = true,
+                "--replace" => options.replace = true,
+                _ if arg.starts_with("--") => return Err(Error::Config(format!("Unknown argument {}", arg))),
+                _ => options.command.push(arg),
+            }
+        }
+        Ok(options)
+    }
+
+    fn master_key(&self) -> Result<[u8; 32]> {
+        let master_key = match (&self.master_key_file, std::env::var("TRADERAPP_MASTER_KEY")) {
+            (Some(file_path), _) => first_line(file_path)?,
+            (None, Ok(master_key)) => master_key,
+            (None, Err(_)) => rpassword::prompt_password("Master key: ")?,
+        };
+        crypto_utils::decode_master_key(&master_key)
+    }
+
+    fn password(&self, email: &str) -> Result<String> {
+        match &self.password_file {
+            Some(file_path) => first_line(file_path),
+            None => Ok(rpassword::prompt_password(format!("Password for {}: ", email))?),
+        }
+    }
+
+    // New password, typed twice when prompted
+    fn new_password(&self, file_path: Option<&String>, email: &str) -> Result<String> {
+        if let Some(file_path) = file_path {
+            return first_line(file_path);
+        }
+        let password = rpassword::prompt_password(format!("New password for {}: ", email))?;
+        if rpassword::prompt_password("Repeat the password: ")? != password {
+            return Err(Error::Auth("Passwords do not match".to_string()));
+        }
+        Ok(password)
+    }
+}
+
+fn first_line(file_path: &str) -> Result<String> {
+    let data = fs::read_to_string(file_path)?;
+    Ok(data.lines().next().unwrap_or("").to_string())
+}
+
+// The key file is readable by the owner only
+fn write_secret(file_path: &str, data: &str) -> Result<()> {
+    let mut open_options = fs::OpenOptions::new();
+    open_options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut open_options, 0o600);
+    std::io::Write::write_all(&mut open_options.open(file_path)?, format!("{}\n", data).as_bytes())?;
+    Ok(())
+}
+
+fn init(options: &Options) -> Result<()> {
+    let files = [Some(&options.credentials_file), options.master_key_file.as_ref()];
+    if let Some(file_path) = files.into_iter().flatten().find(|file_path| std::path::Path::new(file_path).exists()) {
+        if !options.force {
+            return Err(Error::Config(format!("{} exists, use --force to overwrite", file_path)));
+        }
+    }
+    let master_key = crypto_utils::generate_master_key()?;
+    let master_key_base64 = base64::Engine::encode(&base64::engine::general_purpose::STANDARD, master_key);
+    crypto_utils::save_encrypted_credentials(&Vec::new(), &master_key, &options.credentials_file)?;
+    match &options.master_key_file {
+        Some(file_path) => {
+            write_secret(file_path, &master_key_base64)?;
+            println!("Master key written to {}", file_path);
+        }
+        None => println!("Master key: {}", master_key_base64),
+    }
+    println!("Created {}", options.credentials_file);
+    Ok(())
+}
+
+fn rotate_key(options: &Options) -> Result<()> {
+    let master_key = options.master_key()?;
+    let mut passwords = HashMap::new();
+    if let Some(file_path) = &options.passwords_file {
+        for line in fs::read_to_string(file_path)?.lines().filter(|line| !line.trim().is_empty()) {
+            let (email, password) = line.split_once(' ')
+                .ok_or_else(|| Error::Config(format!("Expected \"<email> <password>\" in {}", file_path)))?;
+            passwords.insert(email.to_string(), password.to_string());
+        }
+    }
+    let new_master_key = crypto_utils::generate_master_key()?;
+    let new_master_key_base64 = base64::Engine::encode(&base64::engine::general_purpose::STANDARD, new_master_key);
+    // The new key is kept aside until the vault is rotated. Without a key file it's shown before any file is replaced
+    let new_key_file = options.master_key_file.as_ref().map(|file_path| format!("{}.new", file_path));
+    match &new_key_file {
+        Some(file_path) => write_secret(file_path, &new_master_key_base64)?,
+        None => println!("New master key: {}", new_master_key_base64),
+    }
+    let rotation = match crypto_utils::rotate_master_key(&options.users_file, &options.credentials_file, &master_key, &new_master_key, &passwords) {
+        Ok(rotation) => rotation,
+        Err(e) => {
+            // The new key is dropped only if the vault is still on the old one
+            if let Some(file_path) = &new_key_file {
+                if crypto_utils::load_credentials(&master_key, &options.credentials_file).is_ok() {
+                    let _ = fs::remove_file(file_path);
+                } else {
+                    eprintln!("The credentials file may be encrypted with the new key, kept in {}", file_path);
+                }
+            }
+            return Err(e);
+        }
+    };
+    match (&new_key_file, &options.master_key_file) {
+        (Some(new_key_file), Some(file_path)) => {
+            fs::rename(new_key_file, file_path)?;
+            println!("Master key rotated, the new key is written to {}", file_path);
+        }
+        _ => println!("Master key rotated"),
+    }
+    for email in rotation.rewrapped.iter() {
+        println!("Re-wrapped {}", email);
+    }
+    for email in rotation.reenroll.iter() {
+        println!("Needs re-enrollment: {}", email);
+    }
+    Ok(())
+}
+
+fn credentials_command(options: &Options, args: &[String]) -> Result<()> {
+    let master_key = options.master_key()?;
+    let mut credentials = crypto_utils::load_credentials(&master_key, &options.credentials_file)?;
+    match args {
+        [command] if command == "list" => {
+            for credentials in credentials.iter() {
+                println!("{}\t{}\t{}{}", credentials.id, credentials.login, credentials.public_key,
+                    if credentials.paper_trading { "\tpaper" } else { "" });
+            }
+            return Ok(());
+        }
+        [command, file_path] if command == "add" => {
+            let data = fs::read_to_string(file_path)?;
+            let added: Vec<Credentials> = match serde_json::from_str(&data) {
+                Ok(added) => added,
+                Err(_) => vec![serde_json::from_str(&data)?],
+            };
+            for new_credentials in added {
+                if let Some(existing) = credentials.iter_mut().find(|credentials| credentials.id == new_credentials.id) {
+                    if !options.replace {
+                        return Err(Error::Config(format!("Credentials {} exist, use --replace", new_credentials.id)));
+                    }
+                    println!("Replaced {}", new_credentials.id);
+                    *existing = new_credentials;
+                } else {
+                    println!("Added {}", new_credentials.id);
+                    credentials.push(new_credentials);
+                }
+            }
+        }
+        [command, id] if command == "remove" => {
+            let count = credentials.len();
+            credentials.retain(|credentials| &credentials.id != id);
+            if credentials.len() == count {
+                return Err(Error::Config(format!("Credentials {} not found", id)));
+            }
+            println!("Removed {}", id);
+        }
+        _ => return Err(Error::Config(format!("Unknown credentials command\n{}", USAGE))),
+    }
+    crypto_utils::save_encrypted_credentials(&credentials, &master_key, &options.credentials_file)
+}
+
+fn users_command(options: &Options, args: &[String]) -> Result<()> {
+    let mut users = crypto_utils::load_users_from(&options.users_file)?;
+    match args {
+        [command] if command == "list" => {
+            for user in users.iter() {
+                println!("{}", user.email);
+            }
+            return Ok(());
+        }
+        [command, email, accessible_credentials] if command == "add" => {
+            let master_key = options.master_key()?;
+            // Ids must exist in the vault, the master key is checked on the way
+            let credentials = crypto_utils::load_credentials(&master_key, &options.credentials_file)?;
+            for id in accessible_credentials.split(',') {
+                if !credentials.iter().any(|credentials| credentials.id == id) {
+                    return Err(Error::Config(format!("Credentials {} not found", id)));
+                }
+            }
+            let password = options.new_password(options.password_file.as_ref(), email)?;
+            crypto_utils::register_user(&options.users_file, email, &password, &master_key, accessible_credentials)?;
+            println!("Registered {}", email);
+            return Ok(());
+        }
+        [command, email] if command == "remove" => {
+            let count = users.len();
+            users.retain(|user| &user.email != email);
+            if users.len() == count {
+                return Err(Error::Auth("User not found".to_string()));
+            }
+            println!("Removed {}", email);
+        }
+        [command, email] if command == "passwd" => {
+            let user = users.iter_mut().find(|user| &user.email == email)
+                .ok_or_else(|| Error::Auth("User not found".to_string()))?;
+            let old_password = options.password(email)?;
+            let new_password = options.new_password(options.new_password_file.as_ref(), email)?;
+            crypto_utils::change_password(user, &old_password, &new_password)?;
+            println!("Password of {} changed", email);
+        }
+        [command, email] if command == "migrate" => {
+            let password = options.password(email)?;
+            if crypto_utils::migrate_vault(&options.users_file, email, &password, &options.credentials_file)? {
+                println!("Migrated {} and {}", email, options.credentials_file);
+            } else {
+                println!("Nothing to migrate");
+            }
+            return Ok(());
+        }
+        _ => return Err(Error::Config(format!("Unknown users command\n{}", USAGE))),
+    }
+    crypto_utils::save_users(&users, &options.users_file)
+}
+
+fn run(options: &Options) -> Result<()> {
+    match options.command.split_first() {
+        Some((command, [])) if command == "init" => init(options),
+        Some((command, [])) if command == "rotate-key" => rotate_key(options),
+        Some((command, args)) if command == "credentials" => credentials_command(options, args),
+        Some((command, args)) if command == "users" => users_command(options, args),
+        _ => Err(Error::Config(format!("Unknown command\n{}", USAGE))),
+    }
+}
+
+fn main() {
+    let options = match Options::from_args() {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+    if let Err(e) = run(&options) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
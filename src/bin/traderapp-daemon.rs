@@ -0,0 +1,205 @@
+// Headless runner: connects all accounts of a user and keeps the stop-loss engine working without the GUI.
+// traderapp-daemon --email <email> [--password-file <path>] [--credentials <path>] [--config <path>] [--log <path>] [--record]
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use trader_app::api::ConnectionStatus;
+use trader_app::config::{Config, CONFIG_FILE};
+use trader_app::crypto_utils;
+use trader_app::error::{Error, Result};
+use trader_app::processed_data::OrderStatus;
+use trader_app::recorder::SessionRecorder;
+use trader_app::session::TradingSession;
+use trader_app::trading_utils::SLType;
+
+struct Options {
+    email: String,
+    password_file: Option<String>,
+    credentials_file: String,
+    config_file: String,
+    log_file: Option<String>,
+    record_session: bool,
+}
+impl Options {
+    fn from_args() -> Result<Self> {
+        let mut options = Options {
+            email: String::new(),
+            password_file: None,
+            credentials_file: crypto_utils::CREDENTIALS_FILE.to_string(),
+            config_file: CONFIG_FILE.to_string(),
+            log_file: None,
+            record_session: false,
+        };
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| Error::Config(format!("Missing value of {}", arg)));
+            match arg.as_str() {
+                "--email" => options.email = value()?,
+                "--password-file" => options.password_file = Some(value()?),
+                "--credentials" => options.credentials_file = value()?,
+                "--config" => options.config_file = value()?,
+                "--log" => options.log_file = Some(value()?),
+                "--record" => options.record_session = true,
+                _ => return Err(Error::Config(format!("Unknown argument {}", arg))),
+            }
+        }
+        if options.email.is_empty() {
+            return Err(Error::Config("--email is required".to_string()));
+        }
+        Ok(options)
+    }
+    // Password from the file (first line) or from the terminal
+    fn password(&self) -> Result<String> {
+        match &self.password_file {
+            Some(file_path) => {
+                let data = std::fs::read_to_string(file_path)?;
+                Ok(data.lines().next().unwrap_or("").to_string())
+            }
+            None => Ok(rpassword::prompt_password(format!("Password for {}: ", self.email))?),
+        }
+    }
+}
+
+// Writes timestamped lines to stdout and to the log file
+struct Logger {
+    file: Option<File>,
+}
+impl Logger {
+    fn new(file_path: Option<&str>) -> Result<Self> {
+        let file = match file_path {
+            Some(file_path) => Some(OpenOptions::new().create(true).append(true).open(file_path)?),
+            None => None,
+        };
+        Ok(Self { file })
+    }
+    fn log(&mut self, message: &str) {
+        let line = format!("{} {}", chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f"), message);
+        println!("{}", line);
+        if let Some(file) = &mut self.file {
+            if let Err(e) = writeln!(file, "{}", line) {
+                eprintln!("Failed to write log: {}", e);
+            }
+        }
+    }
+}
+
+// Last reported state, to log only the changes
+#[derive(Default)]
+struct Reporter {
+    connections: HashMap<String, ConnectionStatus>,
+    orders: HashMap<(String, i64), OrderStatus>,
+    positions: HashMap<(String, String), (i32, SLType, bool)>, // Quantity, stop type, close alert
+    queue_full: HashMap<String, u64>,
+}
+impl Reporter {
+    fn report(&mut self, session: &TradingSession, logger: &mut Logger) {
+        for connection in session.connections.read().unwrap().iter() {
+            let status = *connection.status.borrow();
+            if self.connections.insert(connection.credentials.id.clone(), status) != Some(status) {
+                logger.log(&format!("{} Connection {}", connection.credentials.id, status.description()));
+            }
+        }
+        for order_store in session.orders.read().unwrap().iter() {
+            for order in order_store.orders.iter() {
+                let key = (order_store.id.clone(), order.order_id);
+                if self.orders.insert(key, order.status) != Some(order.status) {
+                    logger.log(&format!("{} Order {} {:?} {} {} at {}: {} {}",
+                        order_store.id, order.order_id, order.side, order.quantity, order.ticker,
+                        order.fill_price.or(Some(order.price)).filter(|price| *price != 0.0).map(|price| format!("{:.2}", price)).unwrap_or_else(|| "market".to_string()),
+                        order.status.description(), order.message));
+                }
+            }
+        }
+        let mut open_positions = HashMap::new();
+        for portfolio in session.portfolios.read().unwrap().iter() {
+            for position in portfolio.portfolio.iter() {
+                let key = (portfolio.id.clone(), position.ticker.clone());
+                let state = (position.quantity, position.sl_type, position.close_alert);
+                let previous = self.positions.get(&key).copied();
+                match previous {
+                    None => logger.log(&format!("{} Position {} {} opened at {:.2}", portfolio.id, position.quantity, position.ticker, position.open_price)),
+                    Some((quantity, _, _)) if quantity != position.quantity => logger.log(&format!("{} Position {} changed to {} at {:.2}", portfolio.id, position.ticker, position.quantity, position.open_price)),
+                    _ => {}
+                }
+                if previous.map(|(_, sl_type, _)| sl_type) != Some(position.sl_type) && position.sl_type != SLType::None {
+                    logger.log(&format!("{} Stop {} for {} at {:.2}", portfolio.id, position.sl_type.description(), position.ticker, position.sl_price));
+                }
+                if position.close_alert && previous.map(|(_, _, close_alert)| close_alert) != Some(true) {
+                    logger.log(&format!("{} Close alert for {} at {:.2}", portfolio.id, position.ticker, position.current_price));
+                }
+                open_positions.insert(key, state);
+            }
+        }
+        for (id, ticker) in self.positions.keys().filter(|key| !open_positions.contains_key(*key)) {
+            logger.log(&format!("{} Position {} closed", id, ticker));
+        }
+        self.positions = open_positions;
+        // The pipeline falls behind the connection
+        for (id, stats) in session.queue_stats() {
+            if self.queue_full.insert(id.clone(), stats.full).unwrap_or(0) < stats.full {
+                logger.log(&format!("{} Queue full: {} queued, lag {} ms, max {} ms", id, stats.queued, stats.last_lag.as_millis(), stats.max_lag.as_millis()));
+            }
+        }
+    }
+}
+
+async fn run(options: Options) -> Result<()> {
+    let mut logger = Logger::new(options.log_file.as_deref())?;
+    let config = Config::load(&options.config_file)?;
+    let users = crypto_utils::load_users()?;
+    let user = users.iter().find(|user| user.email == options.email)
+        .ok_or_else(|| Error::Auth("User not found".to_string()))?;
+    let password = options.password()?;
+    let derived_key = crypto_utils::verify_password(user, &password)?;
+    let credentials = crypto_utils::unlock_credentials(user, &derived_key, &options.credentials_file)?;
+    logger.log(&format!("Vault unlocked, {} accounts", credentials.len()));
+    match crypto_utils::migrate_vault(crypto_utils::USERS_FILE, &options.email, &password, &options.credentials_file) {
+        Ok(true) => logger.log("Vault migrated to the current encryption format"),
+        Ok(false) => {}
+        Err(e) => logger.log(&format!("Failed to migrate the vault: {}", e)),
+    }
+
+    let (data_sender, mut data_receiver) = mpsc::channel(100);
+    let (errors_sender, mut errors_receiver) = mpsc::unbounded_channel();
+    let mut session = TradingSession::new(config, data_sender, errors_sender);
+    if options.record_session {
+        let file_path = format!("session_{}.rec", chrono::Local::now().format("%Y%m%d_%H%M%S"));
+        session.subscribe(Box::new(SessionRecorder::new(&file_path)?));
+        logger.log(&format!("Recording to {}", file_path));
+    }
+    session.start(&credentials);
+
+    let mut reporter = Reporter::default();
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+    loop {
+        tokio::select! {
+            _ = &mut ctrl_c => {
+                logger.log("Stopped");
+                return Ok(());
+            }
+            Some(error) = errors_receiver.recv() => logger.log(&format!("Error: {}", error)),
+            Some(message) = data_receiver.recv() => logger.log(&message),
+            _ = interval.tick() => reporter.report(&session, &mut logger),
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let options = match Options::from_args() {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("Usage: traderapp-daemon --email <email> [--password-file <path>] [--credentials <path>] [--config <path>] [--log <path>] [--record]");
+            std::process::exit(2);
+        }
+    };
+    if let Err(e) = run(options).await {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
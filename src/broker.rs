@@ -0,0 +1,79 @@
+use futures_util::future::BoxFuture;
+use tokio::sync::{mpsc, watch};
+use tokio::sync::mpsc::UnboundedReceiver;
+use crate::crypto_utils::Credentials;
+use crate::api::{connect_to_ff_ws, ConnectionStatus, TradernetClient};
+use crate::api_utils::*;
+use crate::error::Error;
+
+pub type BrokerResult<T> = crate::error::Result<T>;
+
+// Broker interface. UI and observers work with a broker account only through this trait,
+// so a simulated or an alternative broker can be plugged in instead of Freedom24
+pub trait Broker: Send + Sync {
+    // Account id the broker is bound to
+    fn id(&self) -> &str;
+    // Open a session and receive the session id (SID)
+    fn login(&self) -> BoxFuture<'_, BrokerResult<String>>;
+    // Stream market data until the receiver is closed. Requests are read from receiver,
+    // server messages are written to sender, waiting while it is full, connection state changes are published to status
+    fn connect(&self, receiver: UnboundedReceiver<String>, sender: mpsc::Sender<String>, status: watch::Sender<ConnectionStatus>) -> BoxFuture<'_, BrokerResult<()>>;
+    // Place an order. Price 0.0 means no limit price
+    fn send_order(&self, ticker: String, action: ActionType, order: OrderType, price: f64, qty: u64, expiration: Expirations) -> BoxFuture<'_, BrokerResult<OrderAck>>;
+    // Cancel a working order
+    fn cancel_order(&self, order_id: i64) -> BoxFuture<'_, BrokerResult<OrderAck>>;
+    // Replace a working limit order with a new price and quantity. The old order is cancelled first.
+    // cancelled resolves with the filled quantity of the old order once the orders stream reports it done,
+    // None if it doesn't in time. The new order is for the part of qty left unfilled
+    fn modify_order(&self, order_id: i64, ticker: String, action: ActionType, price: f64, qty: u64, cancelled: BoxFuture<'static, Option<f64>>) -> BoxFuture<'_, BrokerResult<OrderAck>> {
+        Box::pin(async move {
+            self.cancel_order(order_id).await?;
+            let Some(filled_quantity) = cancelled.await else {
+                return Err(Error::Network(format!("cancel of order {} is not confirmed, the order is not replaced", order_id)));
+            };
+            let remainder = qty.saturating_sub(filled_quantity.round() as u64);
+            if remainder == 0 {
+                return Ok(OrderAck::new(serde_json::json!({})));
+            }
+            self.send_order(ticker, action, OrderType::Limit, price, remainder, Expirations::Day).await
+        })
+    }
+    // Request the current positions of the account
+    fn portfolio(&self) -> BoxFuture<'_, BrokerResult<serde_json::Value>>;
+}
+
+// Freedom24 (Tradernet) broker
+pub struct FreedomBroker {
+    credentials: Credentials,
+    client: TradernetClient,
+}
+impl FreedomBroker {
+    pub fn new(credentials: Credentials) -> Self {
+        let client = TradernetClient::new(&credentials.public_key, &credentials.secret_key, &credentials.endpoints.cmd_url);
+        Self { credentials, client }
+    }
+}
+impl Broker for FreedomBroker {
+    fn id(&self) -> &str {
+        &self.credentials.id
+    }
+    fn login(&self) -> BoxFuture<'_, BrokerResult<String>> {
+        Box::pin(async move {
+            get_sid_ff(self.credentials.clone()).await
+        })
+    }
+    fn connect(&self, receiver: UnboundedReceiver<String>, sender: mpsc::Sender<String>, status: watch::Sender<ConnectionStatus>) -> BoxFuture<'_, BrokerResult<()>> {
+        Box::pin(async move {
+            connect_to_ff_ws(self.credentials.clone(), receiver, sender, status).await
+        })
+    }
+    fn send_order(&self, ticker: String, action: ActionType, order: OrderType, price: f64, qty: u64, expiration: Expirations) -> BoxFuture<'_, BrokerResult<OrderAck>> {
+        Box::pin(self.client.send_order(ticker, action, order, price, qty, expiration))
+    }
+    fn cancel_order(&self, order_id: i64) -> BoxFuture<'_, BrokerResult<OrderAck>> {
+        Box::pin(self.client.cancel_order(order_id))
+    }
+    fn portfolio(&self) -> BoxFuture<'_, BrokerResult<serde_json::Value>> {
+        Box::pin(self.client.get_positions())
+    }
+}
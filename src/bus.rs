@@ -0,0 +1,176 @@
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, Mutex};
+use crate::observer::{MessagePublisher, ServerMessage, ServerMessagesPublisher};
+use crate::snapshot::StateVersions;
+
+// Messages of an account waiting for the pipeline. A full queue stops reading from the connector channel,
+// and a full connector channel stops reading from the WebSocket
+pub const QUEUE_CAPACITY: usize = 1024;
+
+// Server message of an account in its queue
+struct Envelope {
+    message: ServerMessage,
+    enqueued: Instant,
+}
+
+#[derive(Default)]
+struct QueueMetrics {
+    id: String,
+    received: AtomicU64,
+    processed: AtomicU64,
+    full: AtomicU64,
+    last_lag_us: AtomicU64,
+    max_lag_us: AtomicU64,
+}
+
+// Counters of an account queue. Lag is the time from enqueueing to the end of processing
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct QueueStats {
+    pub received: u64,
+    pub processed: u64,
+    pub queued: u64,
+    pub full: u64, // Times the connector waited for a place in the queue
+    pub last_lag: Duration,
+    pub max_lag: Duration,
+}
+
+// Delivers server messages to the pipeline: one bounded queue and one worker per account,
+// so every stage sees the messages of an account in arrival order. Messages are deserialized
+// before they are queued, outside of the pipeline lock.
+// Workers of different accounts take turns on the pipeline
+#[derive(Clone)]
+pub struct EventBus {
+    pipeline: Arc<Mutex<ServerMessagesPublisher>>,
+    queues: Arc<RwLock<Vec<Arc<QueueMetrics>>>>,
+    versions: StateVersions, // Collections changed by each processed message are marked
+}
+impl EventBus {
+    pub fn new(publisher: ServerMessagesPublisher, versions: StateVersions) -> Self {
+        Self {
+            pipeline: Arc::new(Mutex::new(publisher)),
+            queues: Arc::new(RwLock::new(Vec::new())),
+            versions,
+        }
+    }
+
+    // Queue the messages of the account's connector. The connector channel should be bounded too
+    pub fn add_account(&self, id: &str, mut receiver: mpsc::Receiver<String>) {
+        let metrics = Arc::new(QueueMetrics { id: id.to_string(), ..Default::default() });
+        self.queues.write().unwrap().push(Arc::clone(&metrics));
+        let (sender, mut queue) = mpsc::channel::<Envelope>(QUEUE_CAPACITY);
+
+        let queue_metrics = Arc::clone(&metrics);
+        tokio::spawn(async move {
+            while let Some(data) = receiver.recv().await {
+                let envelope = Envelope { message: ServerMessage::new(chrono::Local::now(), data), enqueued: Instant::now() };
+                queue_metrics.received.fetch_add(1, Ordering::Relaxed);
+                let envelope = match sender.try_send(envelope) {
+                    Ok(()) => continue,
+                    Err(mpsc::error::TrySendError::Full(envelope)) => envelope,
+                    Err(mpsc::error::TrySendError::Closed(_)) => return,
+                };
+                queue_metrics.full.fetch_add(1, Ordering::Relaxed);
+                if sender.send(envelope).await.is_err() {
+                    return;
+                }
+            }
+        });
+
+        let pipeline = Arc::clone(&self.pipeline);
+        let versions = self.versions.clone();
+        let id = id.to_string();
+        tokio::spawn(async move {
+            while let Some(envelope) = queue.recv().await {
+                pipeline.lock().await.notify_subscribers(&id, &envelope.message);
+                let lag_us = envelope.enqueued.elapsed().as_micros() as u64;
+                metrics.last_lag_us.store(lag_us, Ordering::Relaxed);
+                metrics.max_lag_us.fetch_max(lag_us, Ordering::Relaxed);
+                metrics.processed.fetch_add(1, Ordering::Relaxed);
+                versions.mark_message(envelope.message.market_data.as_ref());
+            }
+        });
+    }
+
+    pub fn stats(&self) -> Vec<(String, QueueStats)> {
+        self.queues.read().unwrap().iter().map(|metrics| {
+            let received = metrics.received.load(Ordering::Relaxed);
+            let processed = metrics.processed.load(Ordering::Relaxed);
+            (metrics.id.clone(), QueueStats {
+                received,
+                processed,
+                queued: received.saturating_sub(processed),
+                full: metrics.full.load(Ordering::Relaxed),
+                last_lag: Duration::from_micros(metrics.last_lag_us.load(Ordering::Relaxed)),
+                max_lag: Duration::from_micros(metrics.max_lag_us.load(Ordering::Relaxed)),
+            })
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::observer::MessageSubscriber;
+
+    struct Collector(Arc<std::sync::Mutex<Vec<(String, String)>>>);
+    impl MessageSubscriber for Collector {
+        fn on_data(&mut self, id: &str, message: &ServerMessage) {
+            self.0.lock().unwrap().push((id.to_string(), message.data.clone()));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn messages_of_each_account_keep_arrival_order() {
+        let received = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut publisher = ServerMessagesPublisher::new();
+        publisher.subscribe(Box::new(Collector(Arc::clone(&received))));
+        let bus = EventBus::new(publisher, StateVersions::default());
+        let count = QUEUE_CAPACITY * 3;
+        let mut senders = Vec::new();
+        for id in ["1", "2"] {
+            let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+            bus.add_account(id, receiver);
+            senders.push(sender);
+        }
+        for number in 0..count {
+            for sender in senders.iter() {
+                sender.send(number.to_string()).await.unwrap();
+            }
+        }
+        while bus.stats().iter().any(|(_, stats)| stats.processed < count as u64) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let received = received.lock().unwrap();
+        for id in ["1", "2"] {
+            let numbers: Vec<usize> = received.iter().filter(|(message_id, _)| message_id == id).map(|(_, data)| data.parse().unwrap()).collect();
+            assert_eq!(numbers, (0..count).collect::<Vec<_>>());
+        }
+        assert!(bus.stats().iter().all(|(_, stats)| stats.queued == 0 && stats.received == count as u64));
+    }
+
+    // A stalled pipeline fills the queue and then the connector channel, so the connector waits
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn stalled_pipeline_stops_the_connector() {
+        let bus = EventBus::new(ServerMessagesPublisher::new(), StateVersions::default());
+        let pipeline = Arc::clone(&bus.pipeline);
+        let stalled = pipeline.lock().await;
+        let (sender, receiver) = mpsc::channel(QUEUE_CAPACITY);
+        bus.add_account("1", receiver);
+        let mut sent = 0;
+        loop {
+            match sender.try_send(sent.to_string()) {
+                Ok(()) => sent += 1,
+                Err(mpsc::error::TrySendError::Full(_)) if bus.stats()[0].1.full > 0 => break,
+                Err(mpsc::error::TrySendError::Full(_)) => tokio::time::sleep(Duration::from_millis(1)).await,
+                Err(mpsc::error::TrySendError::Closed(_)) => panic!("Queue closed"),
+            }
+            assert!(sent <= QUEUE_CAPACITY * 3, "The connector was never stopped");
+        }
+        drop(stalled);
+        while bus.stats()[0].1.processed < sent as u64 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+}
@@ -0,0 +1,162 @@
+use std::collections::{HashMap, VecDeque};
+use chrono::{NaiveDateTime, Timelike};
+use crate::market_data::QuoteMessage;
+
+// Completed candles kept per ticker
+pub const MAX_CANDLES: usize = 500;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Candle {
+    pub start: NaiveDateTime, // Exchange time of the interval start
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub close: f64,
+    pub volume: i64,
+}
+impl Candle {
+    fn new(start: NaiveDateTime, price: f64, size: i64) -> Self {
+        Candle { start, open: price, high: price, low: price, close: price, volume: size }
+    }
+    fn add(&mut self, price: f64, size: i64) {
+        self.high = f64::max(self.high, price);
+        self.low = f64::min(self.low, price);
+        self.close = price;
+        self.volume += size;
+    }
+}
+
+// Time of the last trade, "2024-10-18T15:59:59" with optional fractions of a second
+pub fn parse_trade_time(ltt: &str) -> Option<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(ltt, "%Y-%m-%dT%H:%M:%S%.f").ok()
+}
+
+// OHLCV bars of a ticker built from the last trades of its quotes.
+// A bar is completed by the first trade of a later interval
+#[derive(Debug, Clone)]
+pub struct CandleSeries {
+    pub ticker: String,
+    pub interval: u64, // Seconds
+    pub completed: VecDeque<Candle>, // Oldest first
+    pub current: Option<Candle>,
+}
+impl CandleSeries {
+    pub fn new(ticker: &str, interval: u64) -> Self {
+        CandleSeries {
+            ticker: ticker.to_string(),
+            interval: interval.max(1),
+            completed: VecDeque::new(),
+            current: None,
+        }
+    }
+    fn interval_start(&self, time: NaiveDateTime) -> NaiveDateTime {
+        let seconds = time.num_seconds_from_midnight() as u64;
+        let start = seconds - seconds % self.interval;
+        time.date().and_hms_opt(0, 0, 0).unwrap() + chrono::Duration::seconds(start as i64)
+    }
+    // Add a trade. Returns true when it completed a bar. Trades older than the current bar go into it
+    pub fn add_trade(&mut self, time: NaiveDateTime, price: f64, size: i64) -> bool {
+        let start = self.interval_start(time);
+        match &mut self.current {
+            Some(current) if start <= current.start => {
+                current.add(price, size);
+                false
+            }
+            Some(current) => {
+                self.completed.push_back(*current);
+                if self.completed.len() > MAX_CANDLES {
+                    self.completed.pop_front();
+                }
+                self.current = Some(Candle::new(start, price, size));
+                true
+            }
+            None => {
+                self.current = Some(Candle::new(start, price, size));
+                false
+            }
+        }
+    }
+    pub fn last_completed(&self) -> Option<&Candle> {
+        self.completed.back()
+    }
+    // Stop under the lower tail of the last two completed bars, None until a bar is completed
+    pub fn tail_stop(&self, offset: f64) -> Option<f64> {
+        let mut candles = self.completed.iter().rev();
+        let last = candles.next()?;
+        let low = candles.next().map_or(last.low, |previous| f64::min(last.low, previous.low));
+        Some(low - offset)
+    }
+}
+
+// Candles of the tickers quoted on an account
+#[derive(Debug, Clone)]
+pub struct CandleBook {
+    pub id: String,
+    pub series: HashMap<String, CandleSeries>,
+}
+impl CandleBook {
+    pub fn new(id: &str) -> Self {
+        CandleBook {
+            id: id.to_string(),
+            series: HashMap::new(),
+        }
+    }
+    // Add the last trade of the quote, if any. The series restarts when its interval is changed.
+    // Returns true when a bar is completed
+    pub fn add_quote(&mut self, quote_message: &QuoteMessage, interval: u64) -> bool {
+        let Some(ticker) = &quote_message.c else { return false };
+        let series = self.series.entry(ticker.clone()).or_insert_with(|| CandleSeries::new(ticker, interval));
+        if series.interval != interval.max(1) {
+            *series = CandleSeries::new(ticker, interval);
+        }
+        // Quotes carry only changed fields: a trade without its time belongs to the current bar
+        let time = quote_message.ltt.as_deref().and_then(parse_trade_time)
+            .or_else(|| series.current.map(|current| current.start));
+        let price = quote_message.ltp.or_else(|| series.current.map(|current| current.close));
+        match (time, price) {
+            (Some(time), Some(price)) if quote_message.ltp.is_some() || quote_message.ltt.is_some() => {
+                series.add_trade(time, price, quote_message.lts.unwrap_or(0) as i64)
+            }
+            _ => false,
+        }
+    }
+    pub fn series(&self, ticker: &str) -> Option<&CandleSeries> {
+        self.series.get(ticker)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(value: &str) -> NaiveDateTime {
+        parse_trade_time(value).unwrap()
+    }
+
+    #[test]
+    fn trades_are_aggregated_into_bars() {
+        let mut series = CandleSeries::new("SPY.US", 60);
+        assert!(!series.add_trade(time("2024-10-18T15:58:05"), 580.10, 100));
+        assert!(!series.add_trade(time("2024-10-18T15:58:30.250"), 580.40, 50));
+        assert!(!series.add_trade(time("2024-10-18T15:58:59"), 579.90, 10));
+        assert!(series.add_trade(time("2024-10-18T15:59:01"), 580.00, 20));
+        let candle = series.last_completed().unwrap();
+        assert_eq!(candle.start, time("2024-10-18T15:58:00"));
+        assert_eq!((candle.open, candle.high, candle.low, candle.close, candle.volume), (580.10, 580.40, 579.90, 579.90, 160));
+        assert_eq!(series.current.unwrap().start, time("2024-10-18T15:59:00"));
+    }
+
+    #[test]
+    fn tail_stop_is_under_the_lower_of_the_last_two_bars() {
+        let mut series = CandleSeries::new("SPY.US", 60);
+        assert_eq!(series.tail_stop(0.05), None);
+        for (trade_time, price) in [("2024-10-18T15:55:10", 2.00), ("2024-10-18T15:55:40", 1.90),
+            ("2024-10-18T15:56:10", 2.10), ("2024-10-18T15:56:40", 1.95), ("2024-10-18T15:57:10", 2.20)] {
+            series.add_trade(time(trade_time), price, 1);
+        }
+        assert_eq!(series.completed.len(), 2);
+        assert!((series.tail_stop(0.05).unwrap() - 1.85).abs() < 1e-9);
+        series.add_trade(time("2024-10-18T15:58:10"), 2.30, 1);
+        assert!((series.tail_stop(0.05).unwrap() - 1.90).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,214 @@
+use std::collections::BTreeMap;
+use std::fs;
+use serde::{Deserialize, Serialize};
+use crate::api::BASE_TICKERS;
+use crate::api_utils::Endpoints;
+use crate::crypto_utils::{commit_temp, write_temp};
+use crate::error::{Error, Result};
+use crate::stop_rules::RuleSet;
+use crate::trading_utils::underlying_ticker;
+
+pub const CONFIG_FILE: &str = "config.json";
+
+// Unit of the stop distances
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Default)]
+pub enum DistanceMode {
+    #[default]
+    Points, // Price points
+    Percent, // Percent of the open price (premium)
+    Steps, // Minimum price increments of the ticker
+    Volatility, // Daily moves of the open price at the ticker's volatility
+}
+impl DistanceMode {
+    pub fn description(&self) -> &str {
+        match self {
+            DistanceMode::Points => "points",
+            DistanceMode::Percent => "%",
+            DistanceMode::Steps => "steps",
+            DistanceMode::Volatility => "volatility",
+        }
+    }
+    pub const ALL: [DistanceMode; 4] = [
+        DistanceMode::Points,
+        DistanceMode::Percent,
+        DistanceMode::Steps,
+        DistanceMode::Volatility,
+    ];
+}
+
+// Price increment of options, for tickers whose quote has no min_step yet
+pub const DEFAULT_MIN_STEP: f64 = 0.01;
+// Trading days in a year, to scale the annual volatility to a day
+const TRADING_DAYS: f64 = 252.0;
+
+// Stop-loss offsets, in the units of the mode
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(default)]
+pub struct StopParameters {
+    pub mode: DistanceMode,
+    pub loss_limit: f64, // Distance of the loss limiter from the price
+    pub break_even_trigger: f64, // Profit that moves the stop to break-even
+    pub break_even_offset: f64, // Stop above the open price at break-even
+    pub trailing_trigger: f64, // Profit that starts the trailing stop
+    pub candle_interval: u64, // Seconds of the candles of candle tails stops
+    pub candle_offset: f64, // Stop under the candle tail
+}
+impl Default for StopParameters {
+    fn default() -> Self {
+        StopParameters {
+            mode: DistanceMode::Points,
+            loss_limit: 0.1,
+            break_even_trigger: 0.11,
+            break_even_offset: 0.02,
+            trailing_trigger: 0.2,
+            candle_interval: 60,
+            candle_offset: 0.02,
+        }
+    }
+}
+
+impl StopParameters {
+    // Offsets in price points for a position opened at open_price.
+    // None in the volatility mode until the quote of the ticker brings its volatility: the offsets mean nothing in another unit
+    pub fn in_points(&self, open_price: f64, min_step: Option<f64>, volatility: Option<f64>) -> Option<StopParameters> {
+        let unit = match self.mode {
+            DistanceMode::Points => 1.0,
+            DistanceMode::Percent => open_price / 100.0,
+            DistanceMode::Steps => min_step.filter(|min_step| *min_step > 0.0).unwrap_or(DEFAULT_MIN_STEP),
+            DistanceMode::Volatility => open_price * volatility.filter(|volatility| *volatility > 0.0)? / 100.0 / TRADING_DAYS.sqrt(),
+        };
+        Some(StopParameters {
+            mode: DistanceMode::Points,
+            loss_limit: self.loss_limit * unit,
+            break_even_trigger: self.break_even_trigger * unit,
+            break_even_offset: self.break_even_offset * unit,
+            trailing_trigger: self.trailing_trigger * unit,
+            candle_interval: self.candle_interval,
+            candle_offset: self.candle_offset * unit,
+        })
+    }
+}
+
+// Defaults of the GUI, applied at start
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(default)]
+pub struct UiSettings {
+    pub window_width: f32,
+    pub window_height: f32,
+    pub days_to_expiration: i64,
+}
+impl Default for UiSettings {
+    fn default() -> Self {
+        UiSettings {
+            window_width: 1150.0,
+            window_height: 1400.0,
+            days_to_expiration: 2,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct Config {
+    pub tickers: Vec<String>, // Watched underlyings
+    pub stops: StopParameters, // For tickers without their own parameters
+    pub ticker_stops: BTreeMap<String, StopParameters>, // By position ticker or by underlying
+    pub rules: BTreeMap<String, RuleSet>, // Custom stop strategies by name
+    pub endpoints: Endpoints, // For accounts without their own endpoints
+    pub ui: UiSettings,
+}
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            tickers: BASE_TICKERS.clone(),
+            stops: StopParameters::default(),
+            ticker_stops: BTreeMap::new(),
+            rules: BTreeMap::new(),
+            endpoints: Endpoints::default(),
+            ui: UiSettings::default(),
+        }
+    }
+}
+impl Config {
+    // Read the config. A missing file is created with the defaults
+    pub fn load(file_path: &str) -> Result<Self> {
+        match fs::read_to_string(file_path) {
+            Ok(data) => {
+                let config: Config = serde_json::from_str(&data)
+                    .map_err(|e| Error::Config(format!("{} is not a valid config: {}", file_path, e)))?;
+                config.validate()?;
+                Ok(config)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let config = Config::default();
+                config.save(file_path)?;
+                Ok(config)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+    // Rule sets must be usable by positions
+    pub fn validate(&self) -> Result<()> {
+        self.rules.iter().try_for_each(|(name, rule_set)| rule_set.validate(name))
+    }
+    // Written through a temp file, a crash while saving leaves the old config
+    pub fn save(&self, file_path: &str) -> Result<()> {
+        let data = serde_json::to_string_pretty(self)?;
+        let temp_path = write_temp(file_path, &data)?;
+        commit_temp(&temp_path, file_path)
+    }
+    // Parameters of the ticker itself, of its underlying (QQQ.US for +QQQ.18OCT2024.P480) or the default ones
+    pub fn stop_parameters(&self, ticker: &str) -> StopParameters {
+        self.ticker_stops.get(ticker)
+            .or_else(|| self.ticker_stops.get(&underlying_ticker(ticker)))
+            .copied()
+            .unwrap_or(self.stops)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn distances_are_converted_to_points() {
+        let parameters = |mode| StopParameters { mode, loss_limit: 10.0, break_even_offset: 2.0, ..StopParameters::default() };
+        let points = parameters(DistanceMode::Percent).in_points(6.0, None, None).unwrap();
+        assert!((points.loss_limit - 0.6).abs() < 1e-9 && (points.break_even_offset - 0.12).abs() < 1e-9);
+        assert_eq!(points.mode, DistanceMode::Points);
+        let points = parameters(DistanceMode::Steps).in_points(0.3, Some(0.05), None).unwrap();
+        assert!((points.loss_limit - 0.5).abs() < 1e-9);
+        assert!((parameters(DistanceMode::Steps).in_points(0.3, None, None).unwrap().loss_limit - 0.1).abs() < 1e-9);
+        // 25% a year is about 1.57% a day
+        let points = parameters(DistanceMode::Volatility).in_points(2.0, None, Some(25.0)).unwrap();
+        assert!((points.loss_limit - 10.0 * 2.0 * 0.25 / 252f64.sqrt()).abs() < 1e-9);
+        assert_eq!(parameters(DistanceMode::Points).in_points(2.0, None, None).unwrap().loss_limit, 10.0);
+    }
+
+    #[test]
+    fn volatility_distances_wait_for_the_volatility() {
+        let parameters = StopParameters { mode: DistanceMode::Volatility, loss_limit: 0.5, ..StopParameters::default() };
+        // 0.5σ is not taken as 0.5% of the premium
+        assert_eq!(parameters.in_points(2.0, Some(0.01), None), None);
+        assert_eq!(parameters.in_points(2.0, Some(0.01), Some(0.0)), None);
+        assert!(parameters.in_points(2.0, Some(0.01), Some(40.0)).is_some());
+    }
+
+    #[test]
+    fn stop_parameters_of_the_ticker_then_of_the_underlying() {
+        let parameters = |loss_limit| StopParameters { loss_limit, ..StopParameters::default() };
+        let config = Config {
+            ticker_stops: BTreeMap::from([
+                ("+QQQ.18OCT2024.P480".to_string(), parameters(0.3)),
+                ("SPY.US".to_string(), parameters(0.5)),
+            ]),
+            ..Config::default()
+        };
+        assert_eq!(config.stop_parameters("+QQQ.18OCT2024.P480").loss_limit, 0.3);
+        // Parameters of one option are not shared with the other options or the underlying
+        assert_eq!(config.stop_parameters("+QQQ.18OCT2024.P475"), config.stops);
+        assert_eq!(config.stop_parameters("QQQ.US"), config.stops);
+        assert_eq!(config.stop_parameters("+SPY.18OCT2024.C580").loss_limit, 0.5);
+        assert_eq!(config.stop_parameters("SPY.US").loss_limit, 0.5);
+    }
+}
@@ -1,11 +1,24 @@
+use std::collections::HashMap;
 use std::fs;
-use std::io::Write;
-use ring::aead::{self, Aad, LessSafeKey, UnboundKey, Nonce, AES_256_GCM};
-use argon2::{Argon2, PasswordHasher, password_hash::SaltString};
+use ring::aead::{Aad, LessSafeKey, UnboundKey, Nonce, AES_256_GCM};
+use ring::rand::{SecureRandom, SystemRandom};
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier, password_hash::SaltString};
 use argon2::password_hash::rand_core::OsRng;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
 use serde::{Deserialize, Serialize};
+use crate::error::{Error, Result};
+use crate::api_utils::Endpoints;
 
-const SALT: &str = "YzBmN2Q4ZjZkOTIwZjMyZTg5YTI5N2Mw";
+// Salt of the users created before per-user salts
+const LEGACY_SALT: &str = "YzBmN2Q4ZjZkOTIwZjMyZTg5YTI5N2Mw";
+// Ciphertext layout: version, nonce, ciphertext with tag. Legacy ciphertext has no header and a zero nonce
+const CIPHERTEXT_VERSION: u8 = 1;
+const NONCE_LENGTH: usize = 12;
+const SALT_LENGTH: usize = 16;
+
+pub const USERS_FILE: &str = "users.json";
+pub const CREDENTIALS_FILE: &str = "credentials.json.enc";
 
 #[derive(Serialize, Deserialize)]
 // App user
@@ -14,6 +27,9 @@ pub struct User {
     pub password_hash: String,
     pub encrypted_master_key: String,
     pub accessible_credentials: String,
+    // Base64 salt of the derived key. Empty for legacy users
+    #[serde(default)]
+    pub salt: String,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -24,110 +40,331 @@ pub struct Credentials {
     pub password: String,
     pub public_key: String,
     pub secret_key: String,
+    // Orders of the account are simulated by the paper-trading broker
+    #[serde(default)]
+    pub paper_trading: bool,
+    // Tradernet servers of the account, production if not set
+    #[serde(default)]
+    pub endpoints: Endpoints,
 }
 
-//  Function for creating the key based on password
-pub fn derive_key_from_password(password: &str) -> [u8; 32] {
-    let salt = SaltString::encode_b64(SALT.as_bytes()).unwrap();
+//  Function for creating the key based on password and the salt of the user
+pub fn derive_key_from_password(password: &str, salt: &str) -> Result<[u8; 32]> {
+    let salt = if salt.is_empty() {
+        SaltString::encode_b64(LEGACY_SALT.as_bytes()).map_err(|e| Error::Crypto(e.to_string()))?.to_string()
+    } else {
+        salt.to_string()
+    };
     let argon2 = Argon2::default();
     let mut derived_key = [0u8; 32];
     argon2
-        .hash_password_into(password.as_bytes(), salt.as_str().as_bytes(), &mut derived_key)
-        .expect("Can't hash password");
-    derived_key
+        .hash_password_into(password.as_bytes(), salt.as_bytes(), &mut derived_key)
+        .map_err(|e| Error::Crypto(format!("Can't hash password: {}", e)))?;
+    Ok(derived_key)
+}
+
+fn random_bytes<const N: usize>() -> Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    SystemRandom::new().fill(&mut bytes).map_err(|_| Error::Crypto("Random generator failed".to_string()))?;
+    Ok(bytes)
 }
 
-pub fn encrypt_data(data: &str, key: &[u8; 32]) -> Vec<u8> {
-    let unbound_key = UnboundKey::new(&AES_256_GCM, key).unwrap();
+pub fn encrypt_data(data: &str, key: &[u8; 32]) -> Result<Vec<u8>> {
+    let unbound_key = UnboundKey::new(&AES_256_GCM, key).map_err(|_| Error::Crypto("Invalid encryption key".to_string()))?;
     let key = LessSafeKey::new(unbound_key);
-    let nonce = Nonce::assume_unique_for_key([0; 12]);
+    let nonce_bytes = random_bytes::<NONCE_LENGTH>()?;
     let mut in_out = data.as_bytes().to_vec();
-    key.seal_in_place_append_tag(nonce, Aad::empty(), &mut in_out).unwrap();
-    in_out
+    key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce_bytes), Aad::empty(), &mut in_out).map_err(|_| Error::Crypto("Encryption failed".to_string()))?;
+    let mut encrypted_data = Vec::with_capacity(1 + NONCE_LENGTH + in_out.len());
+    encrypted_data.push(CIPHERTEXT_VERSION);
+    encrypted_data.extend_from_slice(&nonce_bytes);
+    encrypted_data.extend_from_slice(&in_out);
+    Ok(encrypted_data)
+}
+
+pub fn decrypt_data(encrypted_data: &[u8], key: &[u8; 32]) -> Result<String> {
+    decrypt_versioned(encrypted_data, key).map(|(data, _)| data)
 }
 
-pub fn decrypt_data(encrypted_data: &[u8], key: &[u8; 32]) -> String {
-    let unbound_key = UnboundKey::new(&AES_256_GCM, key).unwrap();
+// Decrypted data and whether it was in the legacy format.
+// Legacy data starting with the version byte is told apart by the failed tag check
+fn decrypt_versioned(encrypted_data: &[u8], key: &[u8; 32]) -> Result<(String, bool)> {
+    let unbound_key = UnboundKey::new(&AES_256_GCM, key).map_err(|_| Error::Crypto("Invalid decryption key".to_string()))?;
     let key = LessSafeKey::new(unbound_key);
-    let nonce = Nonce::assume_unique_for_key([0; 12]);
-    let mut binding = encrypted_data.to_vec();
-    let decrypted_data = key.open_in_place(nonce, Aad::empty(), &mut binding).unwrap();
-    String::from_utf8(decrypted_data.to_vec()).unwrap()
+    let open = |nonce: [u8; NONCE_LENGTH], ciphertext: &[u8]| {
+        let mut binding = ciphertext.to_vec();
+        key.open_in_place(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut binding).ok().map(|data| data.to_vec())
+    };
+    let mut decrypted = None;
+    if encrypted_data.len() > NONCE_LENGTH && encrypted_data[0] == CIPHERTEXT_VERSION {
+        let nonce: [u8; NONCE_LENGTH] = encrypted_data[1..=NONCE_LENGTH].try_into().unwrap_or_default();
+        decrypted = open(nonce, &encrypted_data[1 + NONCE_LENGTH..]).map(|data| (data, false));
+    }
+    if decrypted.is_none() {
+        decrypted = open([0; NONCE_LENGTH], encrypted_data).map(|data| (data, true));
+    }
+    let (decrypted_data, legacy) = decrypted.ok_or_else(|| Error::Crypto("Decryption failed: wrong key or corrupted data".to_string()))?;
+    Ok((String::from_utf8(decrypted_data).map_err(|e| Error::Parse(e.to_string()))?, legacy))
 }
 
-pub fn load_users() -> Vec<User> {
-    let path = "users.json";
+pub fn load_users() -> Result<Vec<User>> {
+    load_users_from(USERS_FILE)
+}
 
+pub fn load_users_from(path: &str) -> Result<Vec<User>> {
     if !std::path::Path::new(path).exists() {
         // File not found. Creating the new one.
         let empty_data = "[]";
-        std::fs::write(path, empty_data).expect("Unable to create users json file");
-        return Vec::new();
+        std::fs::write(path, empty_data).map_err(|e| Error::Config(format!("Unable to create users json file: {}", e)))?;
+        return Ok(Vec::new());
     }
 
-    let data = fs::read_to_string(path).unwrap_or_else(|_| {
-        std::fs::write(path, "[]").expect("Unable to write users json file");
-        "[]".to_string()
-    });
-    serde_json::from_str(&data).unwrap_or_else(|_| {
-        std::fs::write(path, "[]").expect("Unable to write users json file");
-        Vec::new()
-    })
+    let data = fs::read_to_string(path).map_err(|e| Error::Config(format!("Unable to read users json file: {}", e)))?;
+    serde_json::from_str(&data).map_err(|e| Error::Config(format!("Unable to parse users json file: {}", e)))
+}
+
+pub fn save_users(users: &[User], path: &str) -> Result<()> {
+    let data = serde_json::to_string_pretty(users)?;
+    let temp_path = write_temp(path, &data)?;
+    commit_temp(&temp_path, path)
+}
+
+// Files are written next to the target and renamed over it, so a failed write leaves the old file intact.
+// The data is synced before the rename, so a crash can't leave an empty file in place of the old one
+pub(crate) fn write_temp(path: &str, data: &str) -> Result<String> {
+    let temp_path = format!("{}.tmp", path);
+    let write = || -> std::io::Result<()> {
+        let mut file = fs::File::create(&temp_path)?;
+        std::io::Write::write_all(&mut file, data.as_bytes())?;
+        file.sync_all()
+    };
+    write().map_err(|e| Error::Config(format!("Unable to write {}: {}", temp_path, e)))?;
+    Ok(temp_path)
+}
+
+pub(crate) fn commit_temp(temp_path: &str, path: &str) -> Result<()> {
+    fs::rename(temp_path, path).map_err(|e| Error::Config(format!("Unable to replace {}: {}", path, e)))
+}
+
+// Replace several files by their temp files (temp path, path) in order. The replaced files are kept as .bak
+// until all are committed; if a rename fails, the files committed before it are restored and the new ones removed
+fn commit_temps(files: &[(String, String)]) -> Result<()> {
+    let backup_path = |path: &str| format!("{}.bak", path);
+    let remove_temps = |files: &[(String, String)]| files.iter().for_each(|(temp_path, _)| { let _ = fs::remove_file(temp_path); });
+    let existed: Vec<bool> = files.iter().map(|(_, path)| std::path::Path::new(path).exists()).collect();
+    for (index, (_, path)) in files.iter().enumerate().filter(|(index, _)| existed[*index]) {
+        if let Err(e) = fs::copy(path, backup_path(path)) {
+            files[..index].iter().for_each(|(_, path)| { let _ = fs::remove_file(backup_path(path)); });
+            remove_temps(files);
+            return Err(Error::Config(format!("Unable to back up {}: {}", path, e)));
+        }
+    }
+    for (index, (temp_path, path)) in files.iter().enumerate() {
+        if let Err(e) = commit_temp(temp_path, path) {
+            let mut restore_errors = Vec::new();
+            for ((_, committed_path), existed) in files[..index].iter().zip(&existed).rev() {
+                let restored = if *existed {
+                    fs::rename(backup_path(committed_path), committed_path)
+                } else {
+                    fs::remove_file(committed_path)
+                };
+                if let Err(restore_error) = restored {
+                    restore_errors.push(format!("Unable to restore {}: {}", committed_path, restore_error));
+                }
+            }
+            // Restored backups are renamed back already. The backup of a file that couldn't be restored is left for the user
+            remove_temps(&files[index..]);
+            files[index..].iter().for_each(|(_, path)| { let _ = fs::remove_file(backup_path(path)); });
+            if restore_errors.is_empty() {
+                return Err(e);
+            }
+            return Err(Error::Config(format!("{}. {}", e, restore_errors.join(". "))));
+        }
+    }
+    for (_, path) in files {
+        let _ = fs::remove_file(backup_path(path));
+    }
+    Ok(())
+}
+
+// Replace the files (path, data) together: either all are replaced or none
+fn replace_files(files: &[(&str, String)]) -> Result<()> {
+    let mut temps = Vec::new();
+    for (path, data) in files {
+        match write_temp(path, data) {
+            Ok(temp_path) => temps.push((temp_path, path.to_string())),
+            Err(e) => {
+                temps.iter().for_each(|(temp_path, _)| { let _ = fs::remove_file(temp_path); });
+                return Err(e);
+            }
+        }
+    }
+    commit_temps(&temps)
+}
+
+// New random master key for the credentials file
+pub fn generate_master_key() -> Result<[u8; 32]> {
+    random_bytes::<32>()
+}
+
+// Master key from its human view (Base64)
+pub fn decode_master_key(master_key_base64: &str) -> Result<[u8; 32]> {
+    let master_key = BASE64.decode(master_key_base64.trim())?;
+    master_key.as_slice().try_into().map_err(|_| Error::Crypto("Invalid master key length".to_string()))
+}
+
+// Check the password of the user. Returns the key derived from the password
+pub fn verify_password(user: &User, password: &str) -> Result<[u8; 32]> {
+    let parsed_hash = PasswordHash::new(user.password_hash.as_str()).map_err(|e| Error::Config(format!("Invalid password hash of {}: {}", user.email, e)))?;
+    Argon2::default()
+        .verify_password(password.as_bytes(), &parsed_hash)
+        .map_err(|_| Error::Auth("Wrong password".to_string()))?;
+    derive_key_from_password(password, &user.salt)
 }
 
-pub fn register_user(email: &str, password: &str, accessible_credentials: &str) {
+// Decrypt the master key and the credentials available to the user
+pub fn unlock_credentials(user: &User, derived_key: &[u8; 32], file_path: &str) -> Result<Vec<Credentials>> {
+    let encrypted_master_key = BASE64.decode(&user.encrypted_master_key)?;
+    let decrypted_master_key = decrypt_data(&encrypted_master_key, derived_key)?; // Master key. Human view
+    let master_key = decode_master_key(&decrypted_master_key)?;
+    let encrypted_accessible_credentials = BASE64.decode(&user.accessible_credentials)?;
+    let accessible_credentials = decrypt_data(&encrypted_accessible_credentials, derived_key)?; // List of accessible credentials.
+
+    let credentials = load_credentials(&master_key, file_path)?;
+    Ok(filter_credentials(credentials, accessible_credentials))
+}
+
+// User with the master key and the list of accessible credentials ids ("id1,id2") encrypted by the password
+pub fn new_user(email: &str, password: &str, master_key: &[u8; 32], accessible_credentials: &str) -> Result<User> {
     let salt = SaltString::generate(&mut OsRng);
     let argon2 = Argon2::default();
     // Hashing password
     let password_hash = argon2
         .hash_password(password.as_bytes(), &salt)
-        .unwrap()
+        .map_err(|e| Error::Crypto(e.to_string()))?
         .to_string();
-    // Generating derived_key for master-key encoding
-    let derived_key = derive_key_from_password(password);
-    // Asking for the master_key enter in console
-    print!("User {}. Please enter the master_key:", email);
-    std::io::stdout().flush().unwrap();
-    let mut master_key_input = String::new();
-    std::io::stdin().read_line(&mut master_key_input).expect("Failed to read input");
-    let master_key_input = master_key_input.trim();
+    // Generating derived_key for master-key encoding with a salt of its own
+    let key_salt = BASE64.encode(random_bytes::<SALT_LENGTH>()?);
+    let derived_key = derive_key_from_password(password, &key_salt)?;
     // Encoding master_key using derived_key
-    let encrypted_master_key = encrypt_data(master_key_input, &derived_key);
-    let encrypted_master_key_base64 = base64::encode(&encrypted_master_key);
+    let encrypted_master_key = encrypt_data(&BASE64.encode(master_key), &derived_key)?;
+    let encrypted_master_key_base64 = BASE64.encode(&encrypted_master_key);
     // Encoding accessible_credentials using derived_key
-    let encrypted_accessible_credentials = encrypt_data(accessible_credentials, &derived_key);
-    let encrypted_accessible_credentials_base64 = base64::encode(&encrypted_accessible_credentials);
-    
-    let new_user = User {
+    let encrypted_accessible_credentials = encrypt_data(accessible_credentials, &derived_key)?;
+    let encrypted_accessible_credentials_base64 = BASE64.encode(&encrypted_accessible_credentials);
+
+    Ok(User {
         email: email.to_string(),
         password_hash,
         encrypted_master_key: encrypted_master_key_base64,
         accessible_credentials: encrypted_accessible_credentials_base64,
-    };
-    let mut users = load_users();
-    users.push(new_user);
+        salt: key_salt,
+    })
+}
 
-    let data = serde_json::to_string_pretty(&users).unwrap();
-    fs::write("users.json", data).expect("Unable to write json file");
+pub fn register_user(users_file: &str, email: &str, password: &str, master_key: &[u8; 32], accessible_credentials: &str) -> Result<()> {
+    let mut users = load_users_from(users_file)?;
+    if users.iter().any(|user| user.email == email) {
+        return Err(Error::Config(format!("User {} already exists", email)));
+    }
+    users.push(new_user(email, password, master_key, accessible_credentials)?);
+    save_users(&users, users_file)
 }
 
-pub fn load_credentials(master_key: &[u8; 32], file_path: &str) -> Vec<Credentials> {
-    let encrypted_data_base64 = fs::read_to_string(file_path).expect("Unable to read file");
-    let encrypted_data = base64::decode(&encrypted_data_base64).expect("Unable to decode base64");
-    let decrypted_data = decrypt_data(&encrypted_data, master_key);
-    let credentials: Vec<Credentials> = serde_json::from_str(&decrypted_data).unwrap();
-    credentials
+// Re-wrap the master key and the accessible credentials with the new password
+pub fn change_password(user: &mut User, old_password: &str, new_password: &str) -> Result<()> {
+    let derived_key = verify_password(user, old_password)?;
+    let master_key = decode_master_key(&decrypt_data(&BASE64.decode(&user.encrypted_master_key)?, &derived_key)?)?;
+    let accessible_credentials = decrypt_data(&BASE64.decode(&user.accessible_credentials)?, &derived_key)?;
+    *user = new_user(&user.email, new_password, &master_key, &accessible_credentials)?;
+    Ok(())
 }
 
-pub fn save_encrypted_credentials(credentials: &Vec<Credentials>, master_key: &[u8; 32], file_path: &str) {
+// Rewrite the user and the credentials file in the current format. Returns true if anything was migrated.
+// Both files are replaced together, so a failure leaves the vault as it was
+pub fn migrate_vault(users_file: &str, email: &str, password: &str, credentials_file: &str) -> Result<bool> {
+    let mut users = load_users_from(users_file)?;
+    let user = users.iter_mut().find(|user| user.email == email)
+        .ok_or_else(|| Error::Auth("User not found".to_string()))?;
+    let derived_key = verify_password(user, password)?;
+    let (decrypted_master_key, master_key_legacy) = decrypt_versioned(&BASE64.decode(&user.encrypted_master_key)?, &derived_key)?;
+    let master_key = decode_master_key(&decrypted_master_key)?;
+    let (accessible_credentials, accessible_credentials_legacy) = decrypt_versioned(&BASE64.decode(&user.accessible_credentials)?, &derived_key)?;
+    let user_migrated = user.salt.is_empty() || master_key_legacy || accessible_credentials_legacy;
+    let (credentials, credentials_legacy) = read_credentials(&master_key, credentials_file)?;
+    let mut files = Vec::new();
+    if user_migrated {
+        *user = new_user(email, password, &master_key, &accessible_credentials)?;
+        files.push((users_file, serde_json::to_string_pretty(&users)?));
+    }
+    if credentials_legacy {
+        files.push((credentials_file, encode_credentials(&credentials, &master_key)?));
+    }
+    replace_files(&files)?;
+    Ok(user_migrated || credentials_legacy)
+}
+
+pub fn load_credentials(master_key: &[u8; 32], file_path: &str) -> Result<Vec<Credentials>> {
+    read_credentials(master_key, file_path).map(|(credentials, _)| credentials)
+}
+
+// Credentials and whether the file is in the legacy format
+fn read_credentials(master_key: &[u8; 32], file_path: &str) -> Result<(Vec<Credentials>, bool)> {
+    let encrypted_data_base64 = fs::read_to_string(file_path).map_err(|e| Error::Config(format!("Unable to read {}: {}", file_path, e)))?;
+    let encrypted_data = BASE64.decode(encrypted_data_base64.trim())?;
+    let (decrypted_data, legacy) = decrypt_versioned(&encrypted_data, master_key)?;
+    let credentials: Vec<Credentials> = serde_json::from_str(&decrypted_data)?;
+    Ok((credentials, legacy))
+}
+
+pub fn save_encrypted_credentials(credentials: &Vec<Credentials>, master_key: &[u8; 32], file_path: &str) -> Result<()> {
+    let temp_path = write_temp(file_path, &encode_credentials(credentials, master_key)?)?;
+    commit_temp(&temp_path, file_path)
+}
+
+fn encode_credentials(credentials: &Vec<Credentials>, master_key: &[u8; 32]) -> Result<String> {
     // Serialize structure to JSON
-    let json_data = serde_json::to_string(credentials).expect("Failed to serialize credentials");
+    let json_data = serde_json::to_string(credentials)?;
     // Encrypt JSON
-    let encrypted_json = encrypt_data(json_data.as_str(), master_key);
+    let encrypted_json = encrypt_data(json_data.as_str(), master_key)?;
     // Coding encrypted data to Base64
-    let encrypted_json_base64 = base64::encode(&encrypted_json);
-    // Save encrypted data to file
-    fs::write(file_path, encrypted_json_base64).expect("Unable to write encrypted data to file");
+    Ok(BASE64.encode(&encrypted_json))
+}
+
+// Users of a master key rotation
+pub struct KeyRotation {
+    pub rewrapped: Vec<String>, // Users with the new master key
+    pub reenroll: Vec<String>, // Users without a supplied password. They can't unlock the vault until registered again
+}
+
+// Re-encrypt the credentials file with new_master_key and re-wrap it for the users with known passwords (email -> password).
+// Both files are replaced only when everything is re-encrypted. The users file goes first: the old one is restored
+// if the credentials file can't be replaced, so on error the vault is still unlocked by master_key
+pub fn rotate_master_key(users_file: &str, credentials_file: &str, master_key: &[u8; 32], new_master_key: &[u8; 32], passwords: &HashMap<String, String>) -> Result<KeyRotation> {
+    let credentials = load_credentials(master_key, credentials_file)?;
+    let mut users = load_users_from(users_file)?;
+    if let Some(email) = passwords.keys().find(|email| !users.iter().any(|user| &&user.email == email)) {
+        return Err(Error::Auth(format!("User {} not found", email)));
+    }
+    let mut rotation = KeyRotation { rewrapped: Vec::new(), reenroll: Vec::new() };
+    for user in users.iter_mut() {
+        let Some(password) = passwords.get(&user.email) else {
+            rotation.reenroll.push(user.email.clone());
+            continue;
+        };
+        let derived_key = verify_password(user, password)?;
+        let user_master_key = decode_master_key(&decrypt_data(&BASE64.decode(&user.encrypted_master_key)?, &derived_key)?)?;
+        if &user_master_key != master_key {
+            // Lost access at an earlier rotation
+            rotation.reenroll.push(user.email.clone());
+            continue;
+        }
+        let accessible_credentials = decrypt_data(&BASE64.decode(&user.accessible_credentials)?, &derived_key)?;
+        *user = new_user(&user.email, password, new_master_key, &accessible_credentials)?;
+        rotation.rewrapped.push(user.email.clone());
+    }
+    replace_files(&[(users_file, serde_json::to_string_pretty(&users)?), (credentials_file, encode_credentials(&credentials, new_master_key)?)])?;
+    Ok(rotation)
 }
 
 pub fn filter_credentials (credentials: Vec<Credentials>, accessible_ids: String) -> Vec<Credentials> {
@@ -136,4 +373,160 @@ pub fn filter_credentials (credentials: Vec<Credentials>, accessible_ids: String
         .into_iter()
         .filter(|c| accessible_ids_vec.contains(&c.id.as_str()))
         .collect()
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Empty directory of the test under the system temp directory
+    fn test_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("traderapp-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.to_string_lossy().to_string()
+    }
+
+    fn test_credentials(id: &str) -> Credentials {
+        Credentials {
+            id: id.to_string(),
+            login: format!("{}@example.com", id),
+            password: "password".to_string(),
+            public_key: "public".to_string(),
+            secret_key: "secret".to_string(),
+            paper_trading: false,
+            endpoints: Endpoints::default(),
+        }
+    }
+
+    // Sealed with a zero nonce and no header, as before the versioned format
+    fn legacy_encrypt(data: &str, key: &[u8; 32]) -> Vec<u8> {
+        let key = LessSafeKey::new(UnboundKey::new(&AES_256_GCM, key).unwrap());
+        let mut in_out = data.as_bytes().to_vec();
+        key.seal_in_place_append_tag(Nonce::assume_unique_for_key([0; NONCE_LENGTH]), Aad::empty(), &mut in_out).unwrap();
+        in_out
+    }
+
+    #[test]
+    fn same_plaintext_gets_different_ciphertexts() {
+        let key = generate_master_key().unwrap();
+        let first = encrypt_data("secret", &key).unwrap();
+        let second = encrypt_data("secret", &key).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(first[0], CIPHERTEXT_VERSION);
+        assert_eq!(decrypt_versioned(&first, &key).unwrap(), ("secret".to_string(), false));
+        assert_eq!(decrypt_data(&second, &key).unwrap(), "secret");
+    }
+
+    #[test]
+    fn legacy_ciphertext_is_decrypted() {
+        let key = [0x11; 32];
+        let encrypted_data = BASE64.decode("n7E8DoWNp7ttf9pkuIv48qyDIZnD53cKBLzNtx+h4dbA").unwrap();
+        assert_eq!(decrypt_versioned(&encrypted_data, &key).unwrap(), (r#"[{"id":"legacy"}]"#.to_string(), true));
+        assert!(decrypt_data(&encrypted_data, &[0x22; 32]).is_err());
+    }
+
+    #[test]
+    fn legacy_vault_is_migrated_once() {
+        let dir = test_dir("migration");
+        let users_file = format!("{}/{}", dir, USERS_FILE);
+        let credentials_file = format!("{}/{}", dir, CREDENTIALS_FILE);
+        let master_key = generate_master_key().unwrap();
+        let derived_key = derive_key_from_password("alice", "").unwrap();
+        let legacy_user = User {
+            email: "alice@example.com".to_string(),
+            password_hash: Argon2::default().hash_password(b"alice", &SaltString::generate(&mut OsRng)).unwrap().to_string(),
+            encrypted_master_key: BASE64.encode(legacy_encrypt(&BASE64.encode(master_key), &derived_key)),
+            accessible_credentials: BASE64.encode(legacy_encrypt("a", &derived_key)),
+            salt: String::new(),
+        };
+        save_users(&[legacy_user], &users_file).unwrap();
+        let credentials_json = serde_json::to_string(&vec![test_credentials("a")]).unwrap();
+        fs::write(&credentials_file, BASE64.encode(legacy_encrypt(&credentials_json, &master_key))).unwrap();
+
+        assert!(migrate_vault(&users_file, "alice@example.com", "alice", &credentials_file).unwrap());
+        let users = load_users_from(&users_file).unwrap();
+        assert!(!users[0].salt.is_empty());
+        assert_eq!(BASE64.decode(&users[0].encrypted_master_key).unwrap()[0], CIPHERTEXT_VERSION);
+        let (_, legacy) = read_credentials(&master_key, &credentials_file).unwrap();
+        assert!(!legacy);
+        let derived_key = verify_password(&users[0], "alice").unwrap();
+        assert_eq!(unlock_credentials(&users[0], &derived_key, &credentials_file).unwrap()[0].id, "a");
+
+        let users_data = fs::read_to_string(&users_file).unwrap();
+        let credentials_data = fs::read_to_string(&credentials_file).unwrap();
+        assert!(!migrate_vault(&users_file, "alice@example.com", "alice", &credentials_file).unwrap());
+        assert_eq!(fs::read_to_string(&users_file).unwrap(), users_data);
+        assert_eq!(fs::read_to_string(&credentials_file).unwrap(), credentials_data);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn master_key_rotation_rewraps_known_users() {
+        let dir = test_dir("rotation");
+        let users_file = format!("{}/{}", dir, USERS_FILE);
+        let credentials_file = format!("{}/{}", dir, CREDENTIALS_FILE);
+        let master_key = generate_master_key().unwrap();
+        save_encrypted_credentials(&vec![test_credentials("a"), test_credentials("b")], &master_key, &credentials_file).unwrap();
+        register_user(&users_file, "alice@example.com", "alice", &master_key, "a,b").unwrap();
+        register_user(&users_file, "bob@example.com", "bob", &master_key, "b").unwrap();
+
+        let new_master_key = generate_master_key().unwrap();
+        let passwords = HashMap::from([("alice@example.com".to_string(), "alice".to_string())]);
+        let rotation = rotate_master_key(&users_file, &credentials_file, &master_key, &new_master_key, &passwords).unwrap();
+        assert_eq!(rotation.rewrapped, vec!["alice@example.com"]);
+        assert_eq!(rotation.reenroll, vec!["bob@example.com"]);
+
+        // Only the new key opens the vault, the re-wrapped user gets the same credentials
+        assert!(load_credentials(&master_key, &credentials_file).is_err());
+        assert_eq!(load_credentials(&new_master_key, &credentials_file).unwrap().len(), 2);
+        let users = load_users_from(&users_file).unwrap();
+        let derived_key = verify_password(&users[0], "alice").unwrap();
+        let ids: Vec<String> = unlock_credentials(&users[0], &derived_key, &credentials_file).unwrap().into_iter().map(|credentials| credentials.id).collect();
+        assert_eq!(ids, vec!["a", "b"]);
+        let derived_key = verify_password(&users[1], "bob").unwrap();
+        assert!(unlock_credentials(&users[1], &derived_key, &credentials_file).is_err());
+        // No temp files or backups are left
+        let mut files: Vec<String> = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name().to_string_lossy().to_string()).collect();
+        files.sort();
+        assert_eq!(files, vec![CREDENTIALS_FILE, USERS_FILE]);
+
+        // A failed rotation leaves both files on the current key
+        let passwords = HashMap::from([("alice@example.com".to_string(), "wrong".to_string())]);
+        assert!(rotate_master_key(&users_file, &credentials_file, &new_master_key, &generate_master_key().unwrap(), &passwords).is_err());
+        assert!(load_credentials(&new_master_key, &credentials_file).is_ok());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn failed_commit_restores_the_files_committed_before() {
+        let dir = test_dir("commit");
+        let first = format!("{}/first", dir);
+        let second = format!("{}/second", dir);
+        fs::write(&first, "old").unwrap();
+        let first_temp = write_temp(&first, "new").unwrap();
+        // The second temp file is missing, its rename fails
+        assert!(commit_temps(&[(first_temp, first.clone()), (format!("{}.tmp", second), second.clone())]).is_err());
+        assert_eq!(fs::read_to_string(&first).unwrap(), "old");
+        assert!(!std::path::Path::new(&second).exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn failed_commit_removes_new_files_temps_and_backups() {
+        let dir = test_dir("rollback");
+        let existing = format!("{}/existing", dir);
+        let new = format!("{}/new", dir);
+        let blocked = format!("{}/blocked", dir);
+        fs::write(&existing, "old").unwrap();
+        // The second rename fails: a file can't be renamed over a non-empty directory
+        fs::create_dir_all(format!("{}/inside", blocked)).unwrap();
+        let files = [(new.as_str(), "new".to_string()), (blocked.as_str(), "new".to_string()), (existing.as_str(), "new".to_string())];
+        assert!(replace_files(&files).is_err());
+        assert_eq!(fs::read_to_string(&existing).unwrap(), "old");
+        let mut names: Vec<String> = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name().to_string_lossy().to_string()).collect();
+        names.sort();
+        assert_eq!(names, vec!["blocked", "existing"]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
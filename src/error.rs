@@ -0,0 +1,63 @@
+use std::fmt;
+
+// Crate-wide error
+#[derive(Debug)]
+pub enum Error {
+    Network(String), // Connection, HTTP or WebSocket failure
+    Auth(String), // Wrong password, unknown user, rejected broker login
+    BrokerRejection { code: Option<i64>, message: String }, // Request rejected by the broker, with Tradernet error code
+    Crypto(String), // Key derivation, encryption or decryption failure
+    Config(String), // Missing or unreadable files and settings
+    Parse(String), // Unexpected data format
+    InvalidInput(String), // Value entered by the user that can't be applied, e.g. a stop above the price
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Network(message) => write!(f, "Network error: {}", message),
+            Error::Auth(message) => write!(f, "Authorisation error: {}", message),
+            Error::BrokerRejection { code: Some(code), message } => write!(f, "Rejected by broker (code {}): {}", code, message),
+            Error::BrokerRejection { code: None, message } => write!(f, "Rejected by broker: {}", message),
+            Error::Crypto(message) => write!(f, "Crypto error: {}", message),
+            Error::Config(message) => write!(f, "Configuration error: {}", message),
+            Error::Parse(message) => write!(f, "Parse error: {}", message),
+            Error::InvalidInput(message) => write!(f, "Invalid input: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Network(e.to_string())
+    }
+}
+impl From<tokio_tungstenite::tungstenite::Error> for Error {
+    fn from(e: tokio_tungstenite::tungstenite::Error) -> Self {
+        Error::Network(e.to_string())
+    }
+}
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Parse(e.to_string())
+    }
+}
+impl From<serde_urlencoded::ser::Error> for Error {
+    fn from(e: serde_urlencoded::ser::Error) -> Self {
+        Error::Parse(e.to_string())
+    }
+}
+impl From<base64::DecodeError> for Error {
+    fn from(e: base64::DecodeError) -> Self {
+        Error::Parse(e.to_string())
+    }
+}
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Config(e.to_string())
+    }
+}
@@ -0,0 +1,58 @@
+// Structs for market data updates
+pub mod market_data;
+pub mod processed_data;
+
+// Traits for pattern Observer
+pub mod observer;
+pub use observer::{MessagePublisher, MessageSubscriber};
+
+// Candles aggregated from the last trades of quotes
+pub mod candles;
+
+// Ordered per-account queues feeding the pipeline
+pub mod bus;
+
+// Immutable state snapshots for the UI
+pub mod snapshot;
+
+// Recording and replay of server messages
+pub mod recorder;
+
+// Offline stop-loss backtesting
+pub mod backtest;
+
+// Security and authorisation functions
+pub mod crypto_utils;
+
+// Errors
+pub mod error;
+
+// Tickers, stop parameters, endpoints and UI defaults
+pub mod config;
+
+// API functions
+pub mod api;
+
+// API tools and structures
+pub mod api_utils;
+
+// Broker abstraction
+pub mod broker;
+
+// Paper-trading broker
+pub mod paper_broker;
+
+// Order sending and tracking
+pub mod orders;
+
+// Shared state and processing pipeline of all accounts
+pub mod session;
+
+// Local Tradernet imitation for tests
+#[cfg(test)]
+pub mod mock_server;
+
+pub mod trading_utils;
+
+// Declarative stop-loss rule sets
+pub mod stop_rules;
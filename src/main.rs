@@ -1,105 +1,278 @@
-// Structs for market data updates
-mod market_data;
-mod processed_data;
-
-// Traits for pattern Observer
-mod observer;
-
-use std::arch::is_aarch64_feature_detected;
-pub use observer::{MessagePublisher, MessageSubscriber};
-
-// Security and authorisation functions
-pub mod crypto_utils;
-use crypto_utils::{User, Credentials};
-
-// API functions
-pub mod api;
-use api::{connect_to_ff_ws};
-
-// API tools and structures
-pub mod api_utils;
-use api_utils::Request;
-
-pub mod trading_utils;
-
 use eframe::egui::{self, menu};
-use egui::{RichText, ComboBox};
-use serde::{Deserialize, Serialize};
+use egui::RichText;
 use tokio::sync::mpsc;
-use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
-use ring::rand::SecureRandom;
-use std::sync::{Arc, RwLock};
-use std::sync::atomic::{AtomicI64, Ordering};
-use futures_util::task::Spawn;
-use crate::api::{send_order, Connection, ConnectionStatus, BASE_TICKERS};
-use crate::api_utils::*;
-use crate::observer::{ConsoleOutputSubscriber, DataDeserializer, MessagesToFileSubscriber, ServerMessagesPublisher, DataProcessor, PortfolioUpdater, QuotesRequester};
-use crate::processed_data::{OrderBook, Portfolio, QuoteBook};
-use crate::trading_utils::{upgrade_sl, SLStrategy, TickerOptions};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use trader_app::{backtest, crypto_utils, error, recorder};
+use trader_app::api::ConnectionStatus;
+use trader_app::api_utils::*;
+use trader_app::config::{Config, DistanceMode, StopParameters, CONFIG_FILE};
+use trader_app::crypto_utils::User;
+use trader_app::error::Error;
+use trader_app::processed_data::{OrderStatus, Side};
+use trader_app::recorder::{ReplaySource, ReplaySpeed, SessionRecorder};
+use trader_app::session::TradingSession;
+use trader_app::trading_utils::{underlying_ticker, ManualStop, SLStrategy, UnderlyingStop};
 
 struct MyApp {
     email_input: String,
     password_input: String,
     is_authenticated: bool,
     users: Vec<User>,
-    credentials: Vec<Credentials>,
-    connections: Arc<RwLock<Vec<Connection>>>,
-    order_books: Arc<RwLock<Vec<OrderBook>>>,
-    quotes: Arc<RwLock<Vec<QuoteBook>>>,
-    portfolios: Arc<RwLock<Vec<Portfolio>>>,
-    tickers: Arc<RwLock<Vec<TickerOptions>>>,
+    session: TradingSession,
+    order_edits: HashMap<(String, i64), (f64, f64)>, // New price and quantity of orders being modified
+    stop_edits: HashMap<(String, String), (f64, bool)>, // Manual stop of positions and whether it is an offset from the open price
+    underlying_edits: HashMap<(String, String), f64>, // Level of underlying stops of positions
+    strategies: Arc<Vec<SLStrategy>>, // Built-in ones and the rule sets of the config, updated with the config
 
-    server_messages_publisher: ServerMessagesPublisher,
-    data_deserializer: DataDeserializer,
-    data_processor: DataProcessor,
-    portfolio_updater: PortfolioUpdater,
-    quotes_requester: QuotesRequester,
-    
     data_receiver: mpsc::Receiver<String>,
     display_data: String,
 
-    days_to_expiration: Arc<AtomicI64>,
+    record_session: bool,
+    settings: Option<SettingsDraft>,
 
     error_message: String,
+    errors: Vec<String>,
+    errors_receiver: mpsc::UnboundedReceiver<String>,
+}
+
+const MAX_DISPLAYED_ERRORS: usize = 5;
+
+fn strategies(config: &Config) -> Arc<Vec<SLStrategy>> {
+    Arc::new(SLStrategy::ALL.iter().cloned()
+        .chain(config.rules.keys().map(|name| SLStrategy::Rules(name.clone())))
+        .collect())
 }
 
-impl Default for MyApp {
-    fn default() -> Self {
-        let mut tickers = Vec::new();
-        for ticker in BASE_TICKERS.iter() {
-            tickers.push(TickerOptions::new(ticker.to_string()));
+// Config being edited in the Configuration window
+struct SettingsDraft {
+    config: Config,
+    tickers_input: String, // Comma separated underlyings
+    new_stops_ticker: String, // Ticker for new stop parameters
+}
+impl SettingsDraft {
+    fn new(config: Config) -> Self {
+        Self {
+            tickers_input: config.tickers.join(", "),
+            config,
+            new_stops_ticker: String::new(),
         }
+    }
+}
+
+impl MyApp {
+    fn new(config: Config) -> Self {
         let (data_sender, data_receiver) = mpsc::channel(100);
-        let connections = Arc::new(RwLock::new(Vec::new()));
-        let order_books = Arc::new(RwLock::new(Vec::new()));
-        let quotes = Arc::new(RwLock::new(Vec::new()));
-        let portfolios = Arc::new(RwLock::new(Vec::new()));
-        let tickers = Arc::new(RwLock::new(tickers));
-        let days_to_expiration = Arc::new(AtomicI64::new(2));
+        let (errors_sender, errors_receiver) = mpsc::unbounded_channel();
+        let (users, error_message) = match crypto_utils::load_users() {
+            Ok(users) => (users, String::new()),
+            Err(e) => (Vec::new(), e.to_string()),
+        };
         Self {
             email_input: String::new(),
             password_input: String::new(),
             is_authenticated: false,
-            users: crypto_utils::load_users(),
-            credentials: Vec::new(),
-            connections: Arc::clone(&connections),
-            order_books: Arc::clone(&order_books),
-            quotes: Arc::clone(&quotes),
-            portfolios: Arc::clone(&portfolios),
-            tickers: Arc::clone(&tickers),
-            server_messages_publisher: ServerMessagesPublisher::new(),
-            data_deserializer: DataDeserializer::new(data_sender.clone()),
-            data_processor: DataProcessor::new(data_sender, Arc::clone(&order_books), Arc::clone(&quotes), Arc::clone(&tickers), Arc::clone(&days_to_expiration)),
-            portfolio_updater: PortfolioUpdater::new(Arc::clone(&portfolios)),
-            quotes_requester: QuotesRequester::new(Arc::clone(&connections)),
+            users,
+            session: TradingSession::new(config.clone(), data_sender, errors_sender),
+            order_edits: HashMap::new(),
+            stop_edits: HashMap::new(),
+            underlying_edits: HashMap::new(),
+            strategies: strategies(&config),
             data_receiver,
             display_data: String::new(),
-            days_to_expiration: Arc::clone(&days_to_expiration),
-            error_message: String::new(),
+            record_session: false,
+            settings: None,
+            error_message,
+            errors: Vec::new(),
+            errors_receiver,
+        }
+    }
+}
+
+impl MyApp {
+    // Unlock credentials of the user and connect to all accessible accounts
+    fn login(&mut self) -> error::Result<()> {
+        // Search user by email
+        let user = self.users.iter().find(|u| u.email == self.email_input)
+            .ok_or_else(|| Error::Auth("User not found".to_string()))?;
+        // Check password
+        let derived_key = crypto_utils::verify_password(user, &self.password_input)?;
+        let credentials = crypto_utils::unlock_credentials(user, &derived_key, crypto_utils::CREDENTIALS_FILE)?;
+        // Vaults written before random nonces and per-user salts are rewritten on the first login
+        match crypto_utils::migrate_vault(crypto_utils::USERS_FILE, &self.email_input, &self.password_input, crypto_utils::CREDENTIALS_FILE) {
+            Ok(true) => self.users = crypto_utils::load_users()?,
+            Ok(false) => {}
+            Err(e) => self.report_error(format!("Failed to migrate the vault: {}", e)),
+        }
+
+        // self.session.subscribe(Box::new(ConsoleOutputSubscriber));
+        // self.session.subscribe(Box::new(MessagesToFileSubscriber::new("messages.log".to_string())));
+        if self.record_session {
+            let file_path = format!("session_{}.rec", chrono::Local::now().format("%Y%m%d_%H%M%S"));
+            match SessionRecorder::new(&file_path) {
+                Ok(recorder) => self.session.subscribe(Box::new(recorder)),
+                Err(e) => self.report_error(format!("Failed to start recording to {}: {}", file_path, e)),
+            }
+        }
+        self.session.start(&credentials);
+        Ok(())
+    }
+    // Diagnostics and errors of the session repaint the UI, which drains them on the next frame
+    fn repaint_on_messages(&mut self, ctx: &egui::Context) {
+        let (data_sender, data_receiver) = mpsc::channel(100);
+        let mut data_source = std::mem::replace(&mut self.data_receiver, data_receiver);
+        let data_ctx = ctx.clone();
+        tokio::spawn(async move {
+            while let Some(data) = data_source.recv().await {
+                if data_sender.send(data).await.is_err() {
+                    break;
+                }
+                data_ctx.request_repaint();
+            }
+        });
+        let (errors_sender, errors_receiver) = mpsc::unbounded_channel();
+        let mut errors_source = std::mem::replace(&mut self.errors_receiver, errors_receiver);
+        let errors_ctx = ctx.clone();
+        tokio::spawn(async move {
+            while let Some(error) = errors_source.recv().await {
+                if errors_sender.send(error).is_err() {
+                    break;
+                }
+                errors_ctx.request_repaint();
+            }
+        });
+    }
+    // Show an error in the errors panel
+    fn report_error(&mut self, message: String) {
+        eprintln!("{}", message);
+        self.errors.push(format!("{} {}", chrono::Local::now().format("%H:%M:%S"), message));
+        if self.errors.len() > MAX_DISPLAYED_ERRORS {
+            self.errors.remove(0);
+        }
+    }
+    // Configuration window. Saved config is written to the file and applied to the session
+    fn show_settings(&mut self, ctx: &egui::Context) {
+        let Some(mut draft) = self.settings.take() else {
+            return;
+        };
+        let mut open = true;
+        let mut save = false;
+        let mut cancel = false;
+        egui::Window::new("Configuration").open(&mut open).show(ctx, |ui| {
+            ui.heading(egui::RichText::new("Watched underlyings").strong());
+            ui.horizontal(|ui| {
+                ui.label("Tickers:");
+                ui.text_edit_singleline(&mut draft.tickers_input);
+            });
+            ui.separator();
+
+            ui.heading(egui::RichText::new("Stop parameters").strong());
+            ui.horizontal(|ui| {
+                ui.add_sized(egui::Vec2::new(150.0, 20.0), egui::Label::new(egui::RichText::new("Ticker").strong()));
+                ui.add_sized(egui::Vec2::new(90.0, 20.0), egui::Label::new(egui::RichText::new("Unit").strong()));
+                ui.add_sized(egui::Vec2::new(80.0, 20.0), egui::Label::new(egui::RichText::new("Loss limit").strong()));
+                ui.add_sized(egui::Vec2::new(80.0, 20.0), egui::Label::new(egui::RichText::new("BE trigger").strong()));
+                ui.add_sized(egui::Vec2::new(80.0, 20.0), egui::Label::new(egui::RichText::new("BE offset").strong()));
+                ui.add_sized(egui::Vec2::new(80.0, 20.0), egui::Label::new(egui::RichText::new("Trailing trigger").strong()));
+                ui.add_sized(egui::Vec2::new(80.0, 20.0), egui::Label::new(egui::RichText::new("Candle, s").strong()));
+                ui.add_sized(egui::Vec2::new(80.0, 20.0), egui::Label::new(egui::RichText::new("Tail offset").strong()));
+            });
+            ui.horizontal(|ui| {
+                ui.add_sized(egui::Vec2::new(150.0, 20.0), egui::Label::new("default"));
+                stop_parameters_row(ui, "default", &mut draft.config.stops);
+            });
+            let mut removed = None;
+            for (ticker, parameters) in draft.config.ticker_stops.iter_mut() {
+                ui.horizontal(|ui| {
+                    ui.add_sized(egui::Vec2::new(150.0, 20.0), egui::Label::new(ticker));
+                    stop_parameters_row(ui, ticker, parameters);
+                    if ui.button("Remove").clicked() {
+                        removed = Some(ticker.clone());
+                    }
+                });
+            }
+            if let Some(ticker) = removed {
+                draft.config.ticker_stops.remove(&ticker);
+            }
+            ui.horizontal(|ui| {
+                ui.add_sized(egui::Vec2::new(150.0, 20.0), egui::TextEdit::singleline(&mut draft.new_stops_ticker));
+                if ui.button("Add").clicked() && !draft.new_stops_ticker.trim().is_empty() {
+                    let stops = draft.config.stops;
+                    draft.config.ticker_stops.insert(draft.new_stops_ticker.trim().to_string(), stops);
+                    draft.new_stops_ticker.clear();
+                }
+            });
+            ui.separator();
+
+            ui.heading(egui::RichText::new("Endpoints").strong());
+            ui.horizontal(|ui| {
+                ui.add_sized(egui::Vec2::new(80.0, 20.0), egui::Label::new("Login"));
+                ui.add_sized(egui::Vec2::new(400.0, 20.0), egui::TextEdit::singleline(&mut draft.config.endpoints.login_url));
+            });
+            ui.horizontal(|ui| {
+                ui.add_sized(egui::Vec2::new(80.0, 20.0), egui::Label::new("WebSocket"));
+                ui.add_sized(egui::Vec2::new(400.0, 20.0), egui::TextEdit::singleline(&mut draft.config.endpoints.ws_url));
+            });
+            ui.horizontal(|ui| {
+                ui.add_sized(egui::Vec2::new(80.0, 20.0), egui::Label::new("Commands"));
+                ui.add_sized(egui::Vec2::new(400.0, 20.0), egui::TextEdit::singleline(&mut draft.config.endpoints.cmd_url));
+            });
+            ui.separator();
+
+            ui.heading(egui::RichText::new("Interface").strong());
+            ui.horizontal(|ui| {
+                ui.label("Window size:");
+                ui.add(egui::DragValue::new(&mut draft.config.ui.window_width).speed(10.0).range(400.0..=4000.0));
+                ui.add(egui::DragValue::new(&mut draft.config.ui.window_height).speed(10.0).range(300.0..=4000.0));
+            });
+            ui.add(egui::Slider::new(&mut draft.config.ui.days_to_expiration, 0..=5).text("Days to Expiration"));
+            ui.label("Endpoints and interface defaults are used at the next start.");
+            ui.separator();
+
+            ui.horizontal(|ui| {
+                save = ui.button("Save").clicked();
+                cancel = ui.button("Cancel").clicked();
+            });
+        });
+        if save {
+            draft.config.tickers = draft.tickers_input
+                .split(',')
+                .map(|ticker| ticker.trim().to_string())
+                .filter(|ticker| !ticker.is_empty())
+                .collect();
+            match draft.config.save(CONFIG_FILE) {
+                Ok(()) => {
+                    self.strategies = strategies(&draft.config);
+                    self.session.apply_config(draft.config);
+                }
+                Err(e) => {
+                    self.report_error(format!("Failed to save configuration: {}", e));
+                    self.settings = Some(draft);
+                }
+            }
+        } else if open && !cancel {
+            self.settings = Some(draft);
         }
     }
 }
 
+fn stop_parameters_row(ui: &mut egui::Ui, ticker: &str, parameters: &mut StopParameters) {
+    egui::ComboBox::from_id_salt(("distance mode", ticker))
+        .width(80.0)
+        .selected_text(parameters.mode.description())
+        .show_ui(ui, |ui| {
+            for mode in DistanceMode::ALL {
+                ui.selectable_value(&mut parameters.mode, mode, mode.description());
+            }
+        });
+    for value in [&mut parameters.loss_limit, &mut parameters.break_even_trigger, &mut parameters.break_even_offset, &mut parameters.trailing_trigger] {
+        ui.add_sized(egui::Vec2::new(80.0, 20.0), egui::DragValue::new(value).speed(0.01).range(0.0..=f64::MAX).fixed_decimals(2));
+    }
+    ui.add_sized(egui::Vec2::new(80.0, 20.0), egui::DragValue::new(&mut parameters.candle_interval).speed(1.0).range(1..=86400));
+    ui.add_sized(egui::Vec2::new(80.0, 20.0), egui::DragValue::new(&mut parameters.candle_offset).speed(0.01).range(0.0..=f64::MAX).fixed_decimals(2));
+}
+
 impl eframe::App for MyApp {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
         if self.is_authenticated {
@@ -107,14 +280,37 @@ impl eframe::App for MyApp {
                 menu::bar(ui, |ui| {
                     ui.menu_button("Settings", |ui| {
                         ui.label("Expiration");
-                        let mut value = self.days_to_expiration.load(Ordering::Relaxed) as i64;
+                        let mut value = self.session.days_to_expiration.load(Ordering::Relaxed) as i64;
                         ui.add(
                             egui::Slider::new(&mut value, 0..=5).text("Days to Expiration"),
                         );
-                        self.days_to_expiration.store(value, Ordering::Relaxed);
+                        self.session.days_to_expiration.store(value, Ordering::Relaxed);
+                        if ui.button("Configuration...").clicked() {
+                            self.settings = Some(SettingsDraft::new(self.session.config.read().unwrap().clone()));
+                            ui.close_menu();
+                        }
                     });
                 });
             });
+            self.show_settings(ctx);
+            while let Ok(message) = self.errors_receiver.try_recv() {
+                self.report_error(message);
+            }
+            if !self.errors.is_empty() {
+                egui::TopBottomPanel::bottom("Errors").show(ctx, |ui| {
+                    ui.horizontal(|ui| {
+                        ui.heading(egui::RichText::new("Errors").strong());
+                        if ui.button("Clear").clicked() {
+                            self.errors.clear();
+                        }
+                    });
+                    for error in self.errors.iter() {
+                        ui.label(RichText::new(error).color(egui::Color32::LIGHT_RED));
+                    }
+                });
+            }
+            // The state is read from the latest snapshot, the pipeline keeps the locks to itself
+            let snapshot = self.session.snapshot();
             egui::CentralPanel::default().show(ctx, |ui| {
                 while let Ok(new_data) = self.data_receiver.try_recv() {
                     self.display_data = new_data;
@@ -124,39 +320,44 @@ impl eframe::App for MyApp {
                     ui.horizontal(|ui| {
                         ui.add_sized(egui::Vec2::new(50.0, 20.0), egui::Label::new("status"));
                         ui.add_sized(egui::Vec2::new(50.0, 20.0), egui::Label::new("id"));
+                        ui.add_sized(egui::Vec2::new(50.0, 20.0), egui::Label::new("mode"));
                         ui.add_sized(egui::Vec2::new(150.0, 20.0), egui::Label::new("Login"));
                         ui.add_sized(egui::Vec2::new(150.0, 20.0), egui::Label::new("Password"));
                         ui.add_sized(egui::Vec2::new(180.0, 20.0), egui::Label::new("Public key"));
                         ui.add_sized(egui::Vec2::new(180.0, 20.0), egui::Label::new("Secret key"));
+                        ui.add_sized(egui::Vec2::new(120.0, 20.0), egui::Label::new("Queue / lag"));
                     });
-                    let connections_read = self.connections.read().unwrap();
-                    for connection in connections_read.iter() {
-                        let connection_status = connection.status.clone();
+                    for connection in snapshot.connections.iter() {
+                        let connection_status = connection.status;
                         ui.separator();
                         ui.horizontal(|ui| {
-                            match connection_status {
-                                ConnectionStatus::Connected => {
-                                    ui.add_sized(
-                                        egui::Vec2::new(50.0, 20.0),
-                                        egui::Label::new(egui::RichText::new(connection_status.description()).color(egui::Color32::GREEN)),
-                                    )}
-                                ConnectionStatus::Disconnected => {
-                                    ui.add_sized(
-                                        egui::Vec2::new(50.0, 20.0),
-                                        egui::Label::new(egui::RichText::new(connection_status.description()).color(egui::Color32::RED)),
-                                    )}
+                            let status_color = match connection_status {
+                                ConnectionStatus::Connected => egui::Color32::GREEN,
+                                ConnectionStatus::Connecting | ConnectionStatus::Reconnecting => egui::Color32::YELLOW,
+                                ConnectionStatus::Disconnected | ConnectionStatus::Failed => egui::Color32::RED,
                             };
+                            ui.add_sized(
+                                egui::Vec2::new(50.0, 20.0),
+                                egui::Label::new(egui::RichText::new(connection_status.description()).color(status_color)),
+                            );
                             ui.add_sized(egui::Vec2::new(50.0, 20.0), egui::Label::new(&connection.credentials.id));
+                            if connection.credentials.paper_trading {
+                                ui.add_sized(egui::Vec2::new(50.0, 20.0), egui::Label::new(egui::RichText::new("paper").color(egui::Color32::YELLOW)));
+                            } else {
+                                ui.add_sized(egui::Vec2::new(50.0, 20.0), egui::Label::new("live"));
+                            }
                             ui.add_sized(egui::Vec2::new(150.0, 20.0), egui::Label::new(&connection.credentials.login));
                             ui.add_sized(egui::Vec2::new(150.0, 20.0), egui::Label::new("**********"));
                             ui.add_sized(egui::Vec2::new(180.0, 20.0), egui::Label::new(&connection.credentials.public_key));
                             ui.add_sized(egui::Vec2::new(180.0, 20.0), egui::Label::new("**********"));
+                            if let Some((_, stats)) = snapshot.queue_stats.iter().find(|(id, _)| id == &connection.credentials.id) {
+                                ui.add_sized(egui::Vec2::new(120.0, 20.0), egui::Label::new(format!("{} / {} ms", stats.queued, stats.last_lag.as_millis())));
+                            }
                         });
 
                         let option_label_size = egui::vec2(150.0, 20.0);
                         let ticker_label_size = egui::vec2(50.0, 20.0);
-                        let tickers_read = self.tickers.read().unwrap();
-                        for row in tickers_read.iter() {
+                        for row in snapshot.tickers.iter() {
                             let short_option_text = RichText::new(row.short_option.clone());
                             let ticker_text = RichText::new(row.ticker.clone()).strong();
                             let long_option_text = RichText::new(row.long_option.clone());
@@ -164,8 +365,7 @@ impl eframe::App for MyApp {
                             let button_style_short = egui::Button::new(button_text_short).fill(egui::Color32::DARK_RED);
                             let button_text_long = RichText::new("LONG").color(egui::Color32::WHITE).strong();
                             let button_style_long = egui::Button::new(button_text_long).fill(egui::Color32::DARK_GREEN);
-                            let public_key = connection.credentials.public_key.clone();
-                            let secret_key = connection.credentials.secret_key.clone();
+                            let broker = Arc::clone(&connection.broker);
                             ui.horizontal(|ui| {
                                 let mut ticket_for_order = "".to_string();
                                 ui.add_sized(option_label_size, egui::Label::new(short_option_text));
@@ -177,13 +377,8 @@ impl eframe::App for MyApp {
                                     ticket_for_order = row.long_option.clone();
                                 }
                                 ui.add_sized(option_label_size, egui::Label::new(long_option_text));
-                                if ticket_for_order != "" {
-                                    tokio::spawn(async move {
-                                        if let Err(e) = send_order(public_key, secret_key, ticket_for_order, ActionType::Buy, OrderType::Market, 0.0, 1, Expirations::Day).await
-                                        {
-                                            eprintln!("Failed to send short order {:?}", e)
-                                        }
-                                    });
+                                if !ticket_for_order.is_empty() {
+                                    self.session.order_tracker.send_order(broker, ticket_for_order, ActionType::Buy, OrderType::Market, 0.0, 1, None);
                                 }
                             });
                         }
@@ -194,9 +389,8 @@ impl eframe::App for MyApp {
                 // Display Portfolios
                 ui.separator();
                 ui.heading(egui::RichText::new("Portfolios").strong());
-                let mut portfolios = self.portfolios.write().unwrap();
-                let credentials = self.credentials.clone();
-                for portfolio in portfolios.iter_mut() {
+                let strategies = Arc::clone(&self.strategies);
+                for portfolio in snapshot.portfolios.iter() {
                     ui.label(format!("Account id: {}", portfolio.id));
 
                     ui.horizontal(|ui| {
@@ -213,8 +407,9 @@ impl eframe::App for MyApp {
                         ui.add_sized(egui::Vec2::new(60.0, 20.0), egui::Label::new(egui::RichText::new("Close alert").strong()));
                         ui.add_sized(egui::Vec2::new(80.0, 20.0), egui::Label::new(egui::RichText::new("Manual close").strong()));
                     });
-                    for mut row in portfolio.portfolio.iter_mut() {
+                    for row in portfolio.portfolio.iter() {
                         let mut close_alert = row.close_alert;
+                        let mut sl_strategy = row.sl_strategy.clone();
                         ui.horizontal(|ui| {
                             ui.add_sized(egui::Vec2::new(100.0, 20.0), egui::Label::new(format!("{}", row.position_id)));
                             ui.add_sized(egui::Vec2::new(150.0, 20.0), egui::Label::new(egui::RichText::new(format!("{}", row.ticker)).strong()));
@@ -228,10 +423,10 @@ impl eframe::App for MyApp {
                                 egui::ComboBox::from_label("")
                                     .selected_text(row.sl_strategy.description())
                                     .show_ui(ui, |ui| {
-                                        for strategy in SLStrategy::ALL.iter() {
+                                        for strategy in strategies.iter() {
                                             ui.selectable_value(
-                                                &mut row.sl_strategy,
-                                                *strategy,
+                                                &mut sl_strategy,
+                                                strategy.clone(),
                                                 strategy.description(),
                                             );
                                         }
@@ -240,9 +435,12 @@ impl eframe::App for MyApp {
                             // );
                             // ui.add_sized(egui::Vec2::new(70.0, 20.0), egui::Label::new(egui::RichText::new(format!("{}", row.sl_strategy.description())).strong()));
 
-                            ui.add_sized(egui::Vec2::new(70.0, 20.0), egui::Label::new(egui::RichText::new(format!("{}", row.sl_type.description())).strong()));
+                            ui.add_sized(egui::Vec2::new(70.0, 20.0), egui::Label::new(egui::RichText::new(row.sl_type.description()).strong()))
+                                .on_hover_text(format!("Distances in {}", row.distance_mode.description()));
                             if ui.button("SLUpgrade").clicked() {
-                                (row.sl_type, row.sl_price) = upgrade_sl(&row);
+                                if let Err(e) = self.session.upgrade_stop(&portfolio.id, &row.ticker) {
+                                    self.report_error(e.to_string());
+                                }
                             }
                             ui.add_sized(egui::Vec2::new(60.0, 20.0), egui::Label::new(egui::RichText::new(format!("{:.2}", row.sl_price)).strong()));
                             ui.add_sized(egui::Vec2::new(60.0, 20.0), egui::Label::new(egui::RichText::new(format!("{}", row.close_alert)).strong()));
@@ -250,20 +448,157 @@ impl eframe::App for MyApp {
                                 close_alert = true;
                             }
                         });
-                        if close_alert && !row.closing  {
-                            row.closing = true;
-                            if let Some(creds) = credentials.iter().find (|creds| creds.id == portfolio.id) {
-                                // println!("{} / {}", creds.public_key, creds.secret_key);
-                                let public_key = creds.public_key.clone();
-                                let secret_key = creds.secret_key.clone();
-                                let ticket_for_order = row.ticker.clone();
-                                let qty_for_order = row.quantity.clone() as u64;
-                                tokio::spawn(async move {
-                                    if let Err(e) = send_order(public_key, secret_key, ticket_for_order, ActionType::Sell, OrderType::Market, 0.0, qty_for_order, Expirations::Day).await
-                                    {
-                                        eprintln!("Failed to send short order {:?}", e)
-                                    }
+                        // Manual stop, applied when the drag ends, on Enter or with Apply
+                        let edit_key = (portfolio.id.clone(), row.ticker.clone());
+                        if row.sl_strategy == SLStrategy::ManualStops {
+                            let (value, offset) = self.stop_edits.entry(edit_key).or_insert((row.sl_price, false));
+                            let mut apply = false;
+                            ui.horizontal(|ui| {
+                                ui.add_space(100.0);
+                                ui.label("Stop:");
+                                let response = ui.add(egui::DragValue::new(value).speed(0.01).range(0.0..=f64::MAX).fixed_decimals(2));
+                                apply = response.drag_stopped() || response.lost_focus();
+                                let was_offset = *offset;
+                                ui.selectable_value(offset, false, "price");
+                                ui.selectable_value(offset, true, "below open");
+                                if *offset != was_offset {
+                                    *value = f64::max(row.open_price - *value, 0.0);
+                                }
+                                apply |= ui.button("Apply").clicked();
+                                ui.push_id(("stop changes", row.position_id), |ui| {
+                                    ui.collapsing(format!("Stop changes ({})", row.stop_changes.len()), |ui| {
+                                        for change in row.stop_changes.iter() {
+                                            ui.label(format!("{} {}: {:.2} -> {:.2} ({})",
+                                                change.time.format("%H:%M:%S"), change.reason, change.from, change.to, change.sl_type.description()));
+                                        }
+                                    });
                                 });
+                            });
+                            let stop = if *offset { ManualStop::Offset(*value) } else { ManualStop::Price(*value) };
+                            if apply && stop.price(row.open_price) != row.sl_price {
+                                if let Err(e) = self.session.set_manual_stop(&portfolio.id, &row.ticker, stop) {
+                                    self.report_error(e.to_string());
+                                }
+                            }
+                        } else {
+                            self.stop_edits.remove(&edit_key);
+                        }
+                        // Underlying stop of options: trigger level and the distance of the underlying to it
+                        if row.ticker.starts_with('+') {
+                            let underlying = underlying_ticker(&row.ticker);
+                            let underlying_price = row.underlying_price.or_else(|| snapshot.quotes.iter()
+                                .find(|quote_book| quote_book.id == portfolio.id)
+                                .and_then(|quote_book| quote_book.quote(&underlying))
+                                .and_then(|quote| quote.last_trade));
+                            let level = self.underlying_edits.entry((portfolio.id.clone(), row.ticker.clone()))
+                                .or_insert_with(|| row.underlying_stop.as_ref().map(|underlying_stop| underlying_stop.level).or(underlying_price).unwrap_or(0.0));
+                            let mut update = None;
+                            ui.horizontal(|ui| {
+                                ui.add_space(100.0);
+                                ui.label(format!("{}: {}", underlying, underlying_price.map_or("N/A".to_string(), |price| format!("{:.2}", price))));
+                                let trigger = UnderlyingStop::new(&row.ticker, row.quantity, *level).trigger;
+                                ui.label(format!("close {}", trigger.description()));
+                                ui.add(egui::DragValue::new(level).speed(0.05).range(0.0..=f64::MAX).fixed_decimals(2));
+                                if ui.button("Set").clicked() {
+                                    update = Some(Some(*level));
+                                }
+                                if let Some(underlying_stop) = &row.underlying_stop {
+                                    if ui.button("Remove").clicked() {
+                                        update = Some(None);
+                                    }
+                                    ui.label(format!("trigger {} {:.2}", underlying_stop.trigger.description(), underlying_stop.level));
+                                    if let Some(price) = underlying_price {
+                                        let distance = underlying_stop.distance(price);
+                                        ui.label(RichText::new(format!("distance {:.2} ({:.2}%)", distance, distance / price * 100.0)).strong());
+                                    }
+                                }
+                            });
+                            if let Some(level) = update {
+                                if let Err(e) = self.session.set_underlying_stop(&portfolio.id, &row.ticker, level) {
+                                    self.report_error(e.to_string());
+                                }
+                            }
+                        }
+                        if sl_strategy != row.sl_strategy {
+                            self.session.set_sl_strategy(&portfolio.id, &row.ticker, sl_strategy);
+                        }
+                        if close_alert && !row.closing  {
+                            self.session.close_position(&portfolio.id, &row.ticker);
+                        }
+                    }
+                    ui.separator();
+                }
+
+                // Display Orders
+                ui.heading(egui::RichText::new("Orders").strong());
+                for order_store in snapshot.orders.iter() {
+                    let broker = snapshot.connections.iter().find(|connection| connection.broker.id() == order_store.id).map(|connection| Arc::clone(&connection.broker));
+                    ui.label(format!("Account id: {}", order_store.id));
+                    ui.horizontal(|ui| {
+                        ui.add_sized(egui::Vec2::new(100.0, 20.0), egui::Label::new(egui::RichText::new("Order ID").strong()));
+                        ui.add_sized(egui::Vec2::new(150.0, 20.0), egui::Label::new(egui::RichText::new("Ticker").strong()));
+                        ui.add_sized(egui::Vec2::new(50.0, 20.0), egui::Label::new(egui::RichText::new("Side").strong()));
+                        ui.add_sized(egui::Vec2::new(60.0, 20.0), egui::Label::new(egui::RichText::new("Quantity").strong()));
+                        ui.add_sized(egui::Vec2::new(60.0, 20.0), egui::Label::new(egui::RichText::new("Filled").strong()));
+                        ui.add_sized(egui::Vec2::new(70.0, 20.0), egui::Label::new(egui::RichText::new("Price").strong()));
+                        ui.add_sized(egui::Vec2::new(70.0, 20.0), egui::Label::new(egui::RichText::new("Fill price").strong()));
+                        ui.add_sized(egui::Vec2::new(100.0, 20.0), egui::Label::new(egui::RichText::new("Status").strong()));
+                        ui.add_sized(egui::Vec2::new(150.0, 20.0), egui::Label::new(egui::RichText::new("Time").strong()));
+                    });
+                    for row in order_store.orders.iter().rev() {
+                        ui.horizontal(|ui| {
+                            ui.add_sized(egui::Vec2::new(100.0, 20.0), egui::Label::new(format!("{}", row.order_id)));
+                            ui.add_sized(egui::Vec2::new(150.0, 20.0), egui::Label::new(egui::RichText::new(&row.ticker).strong()));
+                            match row.side {
+                                Side::Buy => ui.add_sized(egui::Vec2::new(50.0, 20.0), egui::Label::new(egui::RichText::new("Buy").color(egui::Color32::DARK_GREEN))),
+                                Side::Sell => ui.add_sized(egui::Vec2::new(50.0, 20.0), egui::Label::new(egui::RichText::new("Sell").color(egui::Color32::DARK_RED))),
+                            };
+                            ui.add_sized(egui::Vec2::new(60.0, 20.0), egui::Label::new(format!("{}", row.quantity)));
+                            ui.add_sized(egui::Vec2::new(60.0, 20.0), egui::Label::new(format!("{}", row.filled_quantity)));
+                            ui.add_sized(egui::Vec2::new(70.0, 20.0), egui::Label::new(if row.price != 0.0 { format!("{:.2}", row.price) } else { "market".to_string() }));
+                            ui.add_sized(egui::Vec2::new(70.0, 20.0), egui::Label::new(row.fill_price.map(|price| format!("{:.2}", price)).unwrap_or_default()));
+                            let status_color = match row.status {
+                                OrderStatus::Filled => egui::Color32::GREEN,
+                                OrderStatus::Rejected => egui::Color32::RED,
+                                OrderStatus::Cancelled => egui::Color32::GRAY,
+                                _ => egui::Color32::YELLOW,
+                            };
+                            let status_label = ui.add_sized(egui::Vec2::new(100.0, 20.0), egui::Label::new(egui::RichText::new(row.status.description()).color(status_color)));
+                            if !row.message.is_empty() {
+                                status_label.on_hover_text(&row.message);
+                            }
+                            ui.add_sized(egui::Vec2::new(150.0, 20.0), egui::Label::new(row.fill_time.clone().unwrap_or_else(|| row.status_time.clone())));
+                            if let Some(broker) = &broker {
+                                if row.is_working() && ui.button("Cancel").clicked() {
+                                    self.session.order_tracker.cancel_order(Arc::clone(broker), row.order_id);
+                                }
+                                let edit_key = (order_store.id.clone(), row.order_id);
+                                if row.is_modifiable() && !self.order_edits.contains_key(&edit_key) && ui.button("Modify").clicked() {
+                                    // The whole quantity, the broker subtracts the fills when it replaces the order
+                                    self.order_edits.insert(edit_key, (row.price, row.quantity));
+                                }
+                            }
+                        });
+                        // Modification of the order
+                        let edit_key = (order_store.id.clone(), row.order_id);
+                        if let (Some(broker), Some((price, quantity))) = (&broker, self.order_edits.get_mut(&edit_key)) {
+                            let mut finished = false;
+                            ui.horizontal(|ui| {
+                                ui.add_space(100.0);
+                                ui.label("New price:");
+                                ui.add(egui::DragValue::new(price).speed(0.01).range(0.01..=f64::MAX).fixed_decimals(2));
+                                ui.label("Total quantity:");
+                                ui.add(egui::DragValue::new(quantity).speed(1.0).range(1.0..=f64::MAX).fixed_decimals(0));
+                                if ui.button("Apply").clicked() {
+                                    self.session.order_tracker.modify_order(Arc::clone(broker), row, *price, *quantity as u64);
+                                    finished = true;
+                                }
+                                if ui.button("Discard").clicked() {
+                                    finished = true;
+                                }
+                            });
+                            if finished || !row.is_modifiable() {
+                                self.order_edits.remove(&edit_key);
                             }
                         }
                     }
@@ -272,8 +607,7 @@ impl eframe::App for MyApp {
 
                 // Display Order Books
                 ui.heading(egui::RichText::new("Order books").strong());
-                let order_books = self.order_books.read().unwrap();
-                for order_book in order_books.iter() {
+                for order_book in snapshot.order_books.iter() {
                     ui.label(format!("Account id: {}", order_book.id));
                     ui.horizontal(|ui| {
                         ui.add_sized(egui::Vec2::new(150.0, 20.0), egui::Label::new(egui::RichText::new("Ticker").strong()));
@@ -284,31 +618,24 @@ impl eframe::App for MyApp {
                         ui.add_sized(egui::Vec2::new(100.0, 20.0), egui::Label::new(egui::RichText::new("Message number").strong()));
                     });
                     for (ticker, block) in &order_book.order_book {
-                        for row in &block.buy_rows {
-                            ui.horizontal(|ui| {
-                                ui.add_sized(egui::Vec2::new(150.0, 20.0), egui::Label::new(format!("{}", ticker)));
-                                ui.add_sized(
-                                    egui::Vec2::new(100.0, 20.0), 
-                                    egui::Label::new(egui::RichText::new("Buy").color(egui::Color32::DARK_GREEN)),
-                                );
-                                ui.add_sized(egui::Vec2::new(100.0, 20.0), egui::Label::new(format!("{:.2}", row.price)));
-                                ui.add_sized(egui::Vec2::new(100.0, 20.0), egui::Label::new(format!("{}", row.quantity)));
-                                ui.add_sized(egui::Vec2::new(100.0, 20.0), egui::Label::new(format!("{}", row.position)));
-                                ui.add_sized(egui::Vec2::new(100.0, 20.0), egui::Label::new(format!("{}", row.message_number)));
-                            });
+                        if block.is_stale() {
+                            ui.label(egui::RichText::new(format!("{}: stale, waiting for a snapshot", ticker)).color(egui::Color32::GRAY));
+                            continue;
                         }
-                        for row in &block.sell_rows {
-                            ui.horizontal(|ui| {
-                                ui.add_sized(egui::Vec2::new(150.0, 20.0), egui::Label::new(format!("{}", ticker)));
-                                ui.add_sized(
-                                    egui::Vec2::new(100.0, 20.0),
-                                    egui::Label::new(egui::RichText::new("Sell").color(egui::Color32::DARK_RED)),
-                                );
-                                ui.add_sized(egui::Vec2::new(100.0, 20.0), egui::Label::new(format!("{:.2}", row.price)));
-                                ui.add_sized(egui::Vec2::new(100.0, 20.0), egui::Label::new(format!("{}", row.quantity)));
-                                ui.add_sized(egui::Vec2::new(100.0, 20.0), egui::Label::new(format!("{}", row.position)));
-                                ui.add_sized(egui::Vec2::new(100.0, 20.0), egui::Label::new(format!("{}", row.message_number)));
-                            });
+                        for (side, name, color) in [(Side::Buy, "Buy", egui::Color32::DARK_GREEN), (Side::Sell, "Sell", egui::Color32::DARK_RED)] {
+                            for row in block.rows(&side) {
+                                ui.horizontal(|ui| {
+                                    ui.add_sized(egui::Vec2::new(150.0, 20.0), egui::Label::new(format!("{}", ticker)));
+                                    ui.add_sized(
+                                        egui::Vec2::new(100.0, 20.0),
+                                        egui::Label::new(egui::RichText::new(name).color(color)),
+                                    );
+                                    ui.add_sized(egui::Vec2::new(100.0, 20.0), egui::Label::new(format!("{:.2}", row.price)));
+                                    ui.add_sized(egui::Vec2::new(100.0, 20.0), egui::Label::new(format!("{}", row.quantity)));
+                                    ui.add_sized(egui::Vec2::new(100.0, 20.0), egui::Label::new(format!("{}", row.position)));
+                                    ui.add_sized(egui::Vec2::new(100.0, 20.0), egui::Label::new(format!("{}", row.message_number)));
+                                });
+                            }
                         }
                     }
                 }
@@ -316,8 +643,7 @@ impl eframe::App for MyApp {
                 // Display Quotes
                 ui.separator();
                 ui.heading(egui::RichText::new("Quotes").strong());
-                let quotes = self.quotes.read().unwrap();
-                for quotes_book in quotes.iter() {
+                for quotes_book in snapshot.quotes.iter() {
                     ui.label(format!("Account id: {}", quotes_book.id));
                     ui.horizontal(|ui| {
                         ui.add_sized(egui::Vec2::new(150.0, 20.0), egui::Label::new(egui::RichText::new("Ticker").strong()));
@@ -345,75 +671,15 @@ impl eframe::App for MyApp {
                 ui.text_edit_singleline(&mut self.email_input);
                 ui.label("Password:");
                 ui.add(egui::TextEdit::singleline(&mut self.password_input).password(true));
+                ui.checkbox(&mut self.record_session, "Record session");
 
                 if ui.button("Login").clicked() {
-                    // Search user by email
-                    if let Some(user) = self.users.iter().find(|u| u.email == self.email_input) {
-                        let parsed_hash = PasswordHash::new(user.password_hash.as_str()).unwrap();
-                        // Check password
-                        if Argon2::default()
-                            .verify_password(self.password_input.as_bytes(), &parsed_hash)
-                            .is_ok()
-                        {
+                    match self.login() {
+                        Ok(()) => {
                             self.is_authenticated = true;
                             self.error_message.clear();
-                            
-                            // self.server_messages_publisher.subscribe(Box::new(ConsoleOutputSubscriber));
-                            // self.server_messages_publisher.subscribe(Box::new(MessagesToFileSubscriber::new("messages.log".to_string())));
-                            self.server_messages_publisher.subscribe(Box::new(self.data_deserializer.clone()));
-                            self.data_deserializer.subscribe(Box::new(self.data_processor.clone()));
-                            self.data_processor.subscribe(Box::new(self.portfolio_updater.clone()));
-                            self.data_processor.subscribe(Box::new(self.quotes_requester.clone()));
-                            
-                            let derived_key = crypto_utils::derive_key_from_password(&self.password_input);
-                            let encrypted_master_key = base64::decode(&user.encrypted_master_key).expect("Failed to decode encrypted_master_key");
-                            let decrypted_master_key = crypto_utils::decrypt_data(&encrypted_master_key, &derived_key); // Master key. Human view
-                            let master_key = base64::decode(&decrypted_master_key).expect("Failed to decode decrypted_master_key");
-                            let master_key_slice: &[u8; 32] = master_key.as_slice().try_into().expect("Invalid master key length");
-                            let encrypted_accessible_credentials = base64::decode(&user.accessible_credentials).expect("Failed to decode encrypted_accessible_credentials");
-                            let accessible_credentials = crypto_utils::decrypt_data(&encrypted_accessible_credentials, &derived_key); // List of accessible credentials.
-
-                            self.credentials = crypto_utils::load_credentials(master_key_slice, "credentials.json.enc");
-                            self.credentials = crypto_utils::filter_credentials(self.credentials.clone(), accessible_credentials);
-                            for credentials in self.credentials.iter() {
-                                let (sender_to_connector, connector_receiver) = mpsc::unbounded_channel();
-                                let (sender_to_ui, mut ui_receiver) = mpsc::unbounded_channel();
-                                let mut connection = Connection::new(credentials.clone(), sender_to_connector.clone(), sender_to_ui.clone());
-
-                                // initialising connections with broker
-                                let mut connections_write = self.connections.write().unwrap();
-                                let tickers_for_initial_requests = connection.query_tickers.clone();
-                                connections_write.push(connection);
-                                let credentials_clone = credentials.clone();
-                                tokio::spawn(async move {
-                                    if let Err(e) = connect_to_ff_ws(credentials_clone.clone(), connector_receiver, sender_to_ui.clone()).await {
-                                        eprintln!("Failed to connect to {}, {}", credentials_clone.id.clone(), e)
-                                    }
-                                });
-                                // Receiving messages from connector
-                                let credentials_clone = credentials.clone();
-                                let mut publisher = self.server_messages_publisher.clone();
-                                tokio::spawn(async move {
-                                    while let Some(message) = ui_receiver.recv().await {
-                                        publisher.notify_subscribers(&credentials_clone.id.clone(), chrono::Local::now(), &message);
-                                    }
-                                });
-                                // Sending initial requests
-                                let mut sender = sender_to_connector.clone();
-                                let quotes_message = Request::quotes(tickers_for_initial_requests.clone()).message();
-                                let order_book_message = Request::order_book(tickers_for_initial_requests.clone()).message();
-                                let portfolio_message = Request::portfolio().message();
-                                tokio::spawn(async move {
-                                    sender.send(quotes_message).unwrap();
-                                    sender.send(order_book_message).unwrap();
-                                    sender.send(portfolio_message).unwrap();
-                                });
-                            }
-                        } else {
-                            self.error_message = "Wrong password".to_string();
                         }
-                    } else {
-                        self.error_message = "User not found".to_string();
+                        Err(e) => self.error_message = e.to_string(),
                     }
                     self.password_input.clear();
                 }
@@ -425,20 +691,74 @@ impl eframe::App for MyApp {
                 }
             });
         }
-        ctx.request_repaint();
     }
 }
 
 #[tokio::main]
 async fn main() {
+    // Offline backtest: TraderApp --backtest <recording> <entries.json>
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() == 4 && args[1] == "--backtest" {
+        let messages = match recorder::load_recording(&args[2]) {
+            Ok(messages) => messages,
+            Err(e) => { eprintln!("Failed to load recording {}: {}", args[2], e); return; }
+        };
+        let entries = match backtest::load_entries(&args[3]) {
+            Ok(entries) => entries,
+            Err(e) => { eprintln!("Failed to load entries {}: {}", args[3], e); return; }
+        };
+        let config = Config::load(CONFIG_FILE).unwrap_or_else(|e| {
+            eprintln!("{}. Default stop parameters are used", e);
+            Config::default()
+        });
+        backtest::run_backtest(&messages, &entries, &config).print();
+        return;
+    }
+    // Offline replay: TraderApp --replay <recording>. Prints the stops of the positions at the end, no orders are sent
+    if args.len() == 3 && args[1] == "--replay" {
+        let mut source = match ReplaySource::from_file(&args[2], ReplaySpeed::Stepped) {
+            Ok(source) => source,
+            Err(e) => { eprintln!("Failed to load recording {}: {}", args[2], e); return; }
+        };
+        let config = Config::load(CONFIG_FILE).unwrap_or_else(|e| {
+            eprintln!("{}. Default stop parameters are used", e);
+            Config::default()
+        });
+        let (data_sender, _data_receiver) = mpsc::channel(100);
+        let (errors_sender, _errors_receiver) = mpsc::unbounded_channel();
+        let mut session = TradingSession::new(config, data_sender, errors_sender);
+        session.replay(&mut source).await;
+        for portfolio in session.portfolios.read().unwrap().iter() {
+            for position in portfolio.portfolio.iter() {
+                println!("{} {} {} at {:.2}: {} stop {:.2}{}", portfolio.id, position.ticker, position.quantity, position.current_price,
+                    position.sl_type.description(), position.sl_price, if position.close_alert { ", close alert" } else { "" });
+            }
+        }
+        return;
+    }
+
+    let (config, config_error) = match Config::load(CONFIG_FILE) {
+        Ok(config) => (config, None),
+        Err(e) => (Config::default(), Some(e.to_string())),
+    };
     let options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default()
-            .with_inner_size([1150.0, 1400.0]),
+            .with_inner_size([config.ui.window_width, config.ui.window_height]),
         ..Default::default()    
     };
+    let mut app = MyApp::new(config);
+    if let Some(e) = config_error {
+        app.error_message = e;
+    }
     eframe::run_native(
         "TraderApp",
         options,
-        Box::new(|_cc| Ok(Box::new(MyApp::default()))),
-    ).expect("TODO: panic message");
+        Box::new(|cc| {
+            // Repaint when the state changes
+            let ctx = cc.egui_ctx.clone();
+            app.session.on_snapshot(Box::new(move || ctx.request_repaint()));
+            app.repaint_on_messages(&cc.egui_ctx);
+            Ok(Box::new(app))
+        }),
+    ).unwrap_or_else(|e| eprintln!("Failed to start the application: {}", e));
 }
\ No newline at end of file
@@ -7,6 +7,7 @@ pub enum MarketData {
     OrderBookMessage(OrderBookMessage),
     QuoteMessage(QuoteMessage),
     PortfolioMessage(PortfolioMessage),
+    OrdersMessage(Vec<OrderMessage>),
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -49,11 +50,11 @@ pub struct QuoteMessage {
     ltc: Option<String>, // Designations of price change (\'\' – no changes, \'D\' - down, \'U\' - up)
     pub ltp: Option<f64>, // Last trade price
     ltr: Option<String>, // Exchange of the latest trade
-    lts: Option<i32>, // Last trade size
+    pub lts: Option<i32>, // Last trade size
     pub ltt: Option<String>, // Time of last trade
     market_status: Option<String>,
     maxtp: Option<f64>, // Maximum trade price per day
-    min_step: Option<f64>, // Minimum price increment
+    pub min_step: Option<f64>, // Minimum price increment
     mintp: Option<f64>, // Minimum trade price per day
     mrg: Option<String>,
     mtd: Option<String>, // Payment Date
@@ -86,7 +87,7 @@ pub struct QuoteMessage {
     virt_base_instr: Option<String>,
     vlt: Option<f64>, // Trading volume per day in currency
     vol: Option<i32>, // Trade volume per day, in pcs
-    volatility: Option<f64>,
+    pub volatility: Option<f64>, // Annual, in percent
     x_agg_futures: Option<String>,
     x_curr: Option<String>,
     x_curr_val: Option<f64>,
@@ -117,7 +118,7 @@ pub struct OrderBookMessage {
     pub del: Vec<DeleteEntry>, // Market Depth strings to delete
     pub ins: Vec<InsertEntry>, // New strings in market depth 
     pub upd: Vec<UpdateEntry>, // Market depth data strings to update
-    cnt: i32, // Depth of market data
+    pub cnt: i32, // Depth of market data
     x: i32,
 }
 #[derive(Debug, Serialize, Deserialize)]
@@ -193,6 +194,30 @@ pub struct PositionEntry {
     // close_price: f64, // Position closing price
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct OrderMessage {
+    pub id: i64, // Order id
+    pub date: Option<String>, // Date of the order
+    pub stat: Option<i32>, // Order status code
+    pub stat_d: Option<String>, // Date of the status change
+    pub instr: Option<String>, // Ticker
+    pub oper: Option<i32>, // Operation: 1 - buy, 2 - buy on margin, 3 - sell, 4 - sell short
+    #[serde(rename = "type")]
+    pub type_: Option<i32>, // Order type: 1 - market, 2 - limit
+    pub p: Option<f64>, // Order price
+    pub q: Option<f64>, // Quantity
+    pub leaves_qty: Option<f64>, // Quantity not filled yet
+    #[serde(default)]
+    pub trade: Vec<OrderTradeEntry>, // Trades of the order
+}
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct OrderTradeEntry {
+    pub id: Option<i64>, // Trade id
+    pub p: f64, // Trade price
+    pub q: f64, // Trade quantity
+    pub date: Option<String>, // Trade date
+}
+
 pub fn deserialize_message (message: &str) -> Option<MarketData> {
     let raw_values: Vec<Value> = serde_json::from_str(message).ok()?;
     let message_type = raw_values.get(0).and_then(|v| v.as_str())?;
@@ -214,6 +239,18 @@ pub fn deserialize_message (message: &str) -> Option<MarketData> {
                 .ok()
                 .map(MarketData::PortfolioMessage)
         }
+        "orders" => {
+            // A list of orders or a single order update
+            let orders = if data.is_array() {
+                serde_json::from_value::<Vec<OrderMessage>>(data.clone())
+            } else {
+                serde_json::from_value::<OrderMessage>(data.clone()).map(|order| vec![order])
+            };
+            orders
+                .map_err(|e| println!("Deserialization error: {:?}", e))
+                .ok()
+                .map(MarketData::OrdersMessage)
+        }
         _ => None,
     }
 }
\ No newline at end of file
@@ -0,0 +1,729 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use futures_util::{SinkExt, StreamExt};
+use percent_encoding::percent_decode_str;
+use serde_json::json;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request as WsRequest, Response as WsResponse};
+use tokio_tungstenite::tungstenite::protocol::Message;
+use crate::api::sign_cmd;
+use crate::api_utils::Endpoints;
+use crate::crypto_utils::Credentials;
+
+// Local imitation of Tradernet for tests: check-login-password, signed v2 commands
+// and the WebSocket feed. Orders are filled at once at the price set with set_fill_price,
+// unless they are rejected or held with reject_next_order and hold_limit_orders
+pub struct MockServer {
+    pub endpoints: Endpoints,
+    state: Arc<Mutex<MockState>>,
+}
+
+#[derive(Default)]
+struct MockState {
+    credentials: Option<Credentials>,
+    sid: String,
+    last_nonce: i64,
+    fill_price: f64,
+    reject_next_order: bool,
+    hold_limit_orders: bool,
+    fill_on_cancel: i32,
+    next_order_id: i64,
+    positions: Vec<MockPosition>,
+    orders: Vec<serde_json::Value>,
+    commands: Vec<(String, serde_json::Value)>, // Accepted v2 commands with params
+    ws_requests: Vec<String>, // Messages received from WebSocket clients
+    clients: Vec<mpsc::UnboundedSender<String>>,
+}
+
+#[derive(Clone)]
+struct MockPosition {
+    id: i64,
+    ticker: String,
+    quantity: i32,
+    open_price: f64,
+}
+
+// One step of a scripted scenario
+pub enum ScenarioStep {
+    Send(String), // Message to all WebSocket clients
+    Wait(Duration),
+    WaitForRequest(String), // Wait until a client sends a request with this command
+}
+
+impl MockServer {
+    // Start the server on free local ports for one account
+    pub async fn start(credentials: &Credentials) -> std::io::Result<Self> {
+        let http_listener = TcpListener::bind("127.0.0.1:0").await?;
+        let ws_listener = TcpListener::bind("127.0.0.1:0").await?;
+        let http_address = http_listener.local_addr()?;
+        let ws_address = ws_listener.local_addr()?;
+        let state = Arc::new(Mutex::new(MockState {
+            credentials: Some(credentials.clone()),
+            sid: format!("mock-sid-{}", credentials.id),
+            next_order_id: 1000,
+            ..Default::default()
+        }));
+
+        let http_state = Arc::clone(&state);
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = http_listener.accept().await {
+                tokio::spawn(handle_http(stream, Arc::clone(&http_state)));
+            }
+        });
+        let ws_state = Arc::clone(&state);
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = ws_listener.accept().await {
+                tokio::spawn(handle_ws(stream, Arc::clone(&ws_state)));
+            }
+        });
+
+        Ok(Self {
+            endpoints: Endpoints {
+                login_url: format!("http://{}/api/check-login-password", http_address),
+                ws_url: format!("ws://{}/", ws_address),
+                cmd_url: format!("http://{}/api/v2/cmd/", http_address),
+            },
+            state,
+        })
+    }
+    pub fn sid(&self) -> String {
+        self.state.lock().unwrap().sid.clone()
+    }
+    pub fn set_fill_price(&self, price: f64) {
+        self.state.lock().unwrap().fill_price = price;
+    }
+    // The next order is accepted and then rejected in the orders stream, without a fill
+    pub fn reject_next_order(&self) {
+        self.state.lock().unwrap().reject_next_order = true;
+    }
+    // Limit orders stay working until cancelled
+    pub fn hold_limit_orders(&self) {
+        self.state.lock().unwrap().hold_limit_orders = true;
+    }
+    // The next cancel first fills this quantity of the order, as a trade racing the cancel
+    pub fn fill_on_cancel(&self, quantity: i32) {
+        self.state.lock().unwrap().fill_on_cancel = quantity;
+    }
+    // Trade part of a held order now. The rest stays working
+    pub fn fill_held_order(&self, order_id: i64, quantity: i32) {
+        let mut state = self.state.lock().unwrap();
+        let Some(index) = state.orders.iter().position(|order| order["id"].as_i64() == Some(order_id) && is_working(order)) else {
+            return;
+        };
+        let messages = trade_held_order(&mut state, index, quantity);
+        state.clients.retain(|client| messages.iter().all(|message| client.send(message.clone()).is_ok()));
+    }
+    pub fn commands(&self) -> Vec<(String, serde_json::Value)> {
+        self.state.lock().unwrap().commands.clone()
+    }
+    pub fn ws_requests(&self) -> Vec<String> {
+        self.state.lock().unwrap().ws_requests.clone()
+    }
+    // Send a message to all connected WebSocket clients
+    pub fn send(&self, message: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.clients.retain(|client| client.send(message.to_string()).is_ok());
+    }
+    pub async fn play(&self, scenario: Vec<ScenarioStep>) {
+        for step in scenario {
+            match step {
+                ScenarioStep::Send(message) => self.send(&message),
+                ScenarioStep::Wait(duration) => tokio::time::sleep(duration).await,
+                ScenarioStep::WaitForRequest(cmd) => {
+                    while !self.ws_requests().iter().any(|request| request_cmd(request).as_deref() == Some(cmd.as_str())) {
+                        tokio::time::sleep(Duration::from_millis(10)).await;
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Feed messages in the Tradernet format
+// Inserts the best bid and ask of a depth 10 subscription, earlier levels are not deleted
+// Depth of one level a side moving through the (bid, ask) quotes: the snapshot of the subscription,
+// then deltas that delete the previous levels and insert the new ones
+pub fn order_book_messages(ticker: &str, quotes: &[(f64, f64)]) -> Vec<String> {
+    let mut previous: Option<(f64, f64)> = None;
+    quotes.iter().enumerate().map(|(number, (bid, ask))| {
+        let del: Vec<serde_json::Value> = previous.iter()
+            .flat_map(|(previous_bid, previous_ask)| [json!({ "p": previous_bid, "k": 1 }), json!({ "p": previous_ask, "k": 1 })])
+            .collect();
+        previous = Some((*bid, *ask));
+        json!(["b", {
+            "n": number + 1,
+            "i": ticker,
+            "del": del,
+            "ins": [
+                { "p": bid, "s": "B", "q": 100, "k": 1 },
+                { "p": ask, "s": "S", "q": 100, "k": 1 }
+            ],
+            "upd": [],
+            "cnt": 10,
+            "x": 0
+        }]).to_string()
+    }).collect()
+}
+pub fn quote_message(ticker: &str, bid: f64, ask: f64) -> String {
+    json!(["q", { "c": ticker, "bbp": bid, "bap": ask, "ltp": bid }]).to_string()
+}
+
+fn portfolio_message(positions: &[MockPosition]) -> String {
+    let pos: Vec<serde_json::Value> = positions.iter().map(|position| json!({
+        "i": position.ticker,
+        "q": position.quantity,
+        "acc_pos_id": position.id,
+        "price_a": position.open_price,
+    })).collect();
+    json!(["portfolio", { "loaded": true, "m_id": "mock", "acc": [], "pos": pos }]).to_string()
+}
+
+fn request_cmd(message: &str) -> Option<String> {
+    serde_json::from_str::<Vec<serde_json::Value>>(message)
+        .ok()
+        .and_then(|values| values.first().and_then(|v| v.as_str()).map(|cmd| cmd.to_string()))
+}
+
+async fn handle_http(mut stream: TcpStream, state: Arc<Mutex<MockState>>) {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 4096];
+    // Headers
+    let header_end = loop {
+        if let Some(position) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            break position + 4;
+        }
+        match stream.read(&mut chunk).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => buffer.extend_from_slice(&chunk[..n]),
+        }
+    };
+    let head = String::from_utf8_lossy(&buffer[..header_end]).to_string();
+    let mut lines = head.lines();
+    let path = lines.next().and_then(|line| line.split(' ').nth(1)).unwrap_or("").to_string();
+    let headers: HashMap<String, String> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+        .collect();
+    // Body
+    let content_length: usize = headers.get("content-length").and_then(|length| length.parse().ok()).unwrap_or(0);
+    while buffer.len() < header_end + content_length {
+        match stream.read(&mut chunk).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => buffer.extend_from_slice(&chunk[..n]),
+        }
+    }
+    let body = String::from_utf8_lossy(&buffer[header_end..header_end + content_length]).to_string();
+
+    let response = if path.ends_with("/api/check-login-password") {
+        login_response(&body, &state)
+    } else if let Some(cmd) = path.split("/api/v2/cmd/").nth(1) {
+        cmd_response(cmd, &body, headers.get("x-ntapi-sig").map(|s| s.as_str()), &state)
+    } else {
+        json!({ "errMsg": "Unknown path", "code": 404 })
+    };
+    let response = response.to_string();
+    let http_response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.len(), response
+    );
+    let _ = stream.write_all(http_response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+fn login_response(body: &str, state: &Arc<Mutex<MockState>>) -> serde_json::Value {
+    let form: HashMap<String, String> = serde_urlencoded::from_str(body).unwrap_or_default();
+    let state = state.lock().unwrap();
+    match &state.credentials {
+        Some(credentials) if form.get("login") == Some(&credentials.login) && form.get("password") == Some(&credentials.password) => {
+            json!({ "SID": state.sid })
+        }
+        _ => json!({ "errMsg": "Wrong login or password", "code": 1 }),
+    }
+}
+
+// Check the signature the same way Tradernet does: params sorted by key, values decoded
+fn cmd_response(cmd: &str, body: &str, signature: Option<&str>, state: &Arc<Mutex<MockState>>) -> serde_json::Value {
+    let mut fields = HashMap::new();
+    let mut params = Vec::new();
+    for pair in body.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let value = percent_decode_str(value).decode_utf8_lossy().to_string();
+        match key.strip_prefix("params[").and_then(|key| key.strip_suffix(']')) {
+            Some(param) => params.push((param.to_string(), value)),
+            None => { fields.insert(key.to_string(), value); }
+        }
+    }
+    params.sort();
+    let params_string = params.iter().map(|(key, value)| format!("{}={}", key, value)).collect::<Vec<_>>().join("&");
+    let string_for_sign = format!(
+        "apiKey={}&cmd={}&nonce={}&params={}",
+        fields.get("apiKey").cloned().unwrap_or_default(),
+        fields.get("cmd").cloned().unwrap_or_default(),
+        fields.get("nonce").cloned().unwrap_or_default(),
+        params_string
+    );
+
+    let mut state = state.lock().unwrap();
+    let Some(credentials) = state.credentials.clone() else {
+        return json!({ "errMsg": "No account", "code": 2 });
+    };
+    if fields.get("apiKey") != Some(&credentials.public_key) || fields.get("cmd").map(|s| s.as_str()) != Some(cmd) {
+        return json!({ "errMsg": "Unknown API key", "code": 2 });
+    }
+    if signature != Some(sign_cmd(&credentials.secret_key, &string_for_sign).as_str()) {
+        return json!({ "errMsg": "Wrong signature", "code": 3 });
+    }
+    let nonce: i64 = fields.get("nonce").and_then(|nonce| nonce.parse().ok()).unwrap_or(0);
+    if nonce <= state.last_nonce {
+        return json!({ "errMsg": "Nonce is too small", "code": 4 });
+    }
+    state.last_nonce = nonce;
+    let params: HashMap<String, String> = params.into_iter().collect();
+    state.commands.push((cmd.to_string(), json!(params)));
+
+    match cmd {
+        "putTradeOrder" => put_trade_order(&mut state, &params),
+        "delTradeOrder" => del_trade_order(&mut state, &params),
+        "getPositionJson" => json!({ "result": { "ps": { "pos": state.positions.iter().map(|position| json!({
+            "i": position.ticker, "q": position.quantity, "price_a": position.open_price,
+        })).collect::<Vec<_>>() } } }),
+        _ => json!({ "errMsg": format!("Unknown command {}", cmd), "code": 5 }),
+    }
+}
+
+// Fill the order at the fill price and publish the order and portfolio updates
+fn put_trade_order(state: &mut MockState, params: &HashMap<String, String>) -> serde_json::Value {
+    let ticker = params.get("instr_name").cloned().unwrap_or_default();
+    let quantity: i32 = params.get("qty").and_then(|qty| qty.parse().ok()).unwrap_or(0);
+    let action: i32 = params.get("action_id").and_then(|action| action.parse().ok()).unwrap_or(0);
+    if ticker.is_empty() || quantity <= 0 {
+        return json!({ "errMsg": "Wrong order parameters", "code": 6 });
+    }
+    let price = params.get("limit_price").and_then(|price| price.parse().ok()).unwrap_or(state.fill_price);
+    let signed_quantity = if action == 1 || action == 2 { quantity } else { -quantity };
+    state.next_order_id += 1;
+    let order_id = state.next_order_id;
+    let now = chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
+    // Rejected orders and held limit orders get no fill
+    let unfilled_status = if std::mem::take(&mut state.reject_next_order) {
+        Some(70)
+    } else if state.hold_limit_orders && params.contains_key("limit_price") {
+        Some(10)
+    } else {
+        None
+    };
+    if let Some(status) = unfilled_status {
+        let order = json!({
+            "id": order_id,
+            "date": now,
+            "stat": status,
+            "stat_d": now,
+            "instr": ticker,
+            "oper": action,
+            "p": price,
+            "q": quantity,
+            "leaves_qty": quantity,
+            "trade": [],
+        });
+        state.orders.push(order.clone());
+        let message = json!(["orders", [order]]).to_string();
+        state.clients.retain(|client| client.send(message.clone()).is_ok());
+        return json!({ "order_id": order_id });
+    }
+
+    let portfolio = fill_position(state, order_id, &ticker, signed_quantity, price);
+    let order = json!({
+        "id": order_id,
+        "date": now,
+        "stat": 21,
+        "stat_d": now,
+        "instr": ticker,
+        "oper": action,
+        "type": params.get("order_type_id").and_then(|order_type| order_type.parse::<i32>().ok()),
+        "p": price,
+        "q": quantity,
+        "leaves_qty": 0,
+        "trade": [{ "id": order_id, "p": price, "q": quantity, "date": now }],
+    });
+    state.orders.push(order.clone());
+    let messages = [json!(["orders", [order]]).to_string(), portfolio];
+    state.clients.retain(|client| messages.iter().all(|message| client.send(message.clone()).is_ok()));
+    json!({ "order_id": order_id })
+}
+
+// Add a fill to the position of the ticker. Returns the portfolio update
+fn fill_position(state: &mut MockState, position_id: i64, ticker: &str, signed_quantity: i32, price: f64) -> String {
+    let changed = match state.positions.iter_mut().find(|position| position.ticker == ticker) {
+        Some(position) => {
+            let new_quantity = position.quantity + signed_quantity;
+            if signed_quantity.signum() == position.quantity.signum() {
+                position.open_price = (position.open_price * position.quantity as f64 + price * signed_quantity as f64) / new_quantity as f64;
+            } else if new_quantity.signum() != position.quantity.signum() {
+                position.open_price = price;
+            }
+            position.quantity = new_quantity;
+            position.clone()
+        }
+        None => {
+            let position = MockPosition { id: position_id, ticker: ticker.to_string(), quantity: signed_quantity, open_price: price };
+            state.positions.push(position.clone());
+            position
+        }
+    };
+    // Closed positions are reported once with zero quantity
+    state.positions.retain(|position| position.quantity != 0);
+    portfolio_message(&[changed])
+}
+
+// Held orders are working, partially filled ones too
+fn is_working(order: &serde_json::Value) -> bool {
+    order["stat"] == 10 || order["stat"] == 2
+}
+
+// Trade up to quantity of the held order at its limit price. Returns the order and portfolio updates
+fn trade_held_order(state: &mut MockState, index: usize, quantity: i32) -> Vec<String> {
+    let mut order = state.orders[index].clone();
+    let order_id = order["id"].as_i64().unwrap_or(0);
+    let leaves_quantity = order["leaves_qty"].as_i64().unwrap_or(0) as i32;
+    let fill = quantity.min(leaves_quantity);
+    if fill <= 0 {
+        return Vec::new();
+    }
+    let now = chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
+    let price = order["p"].as_f64().unwrap_or(state.fill_price);
+    let ticker = order["instr"].as_str().unwrap_or_default().to_string();
+    let action = order["oper"].as_i64().unwrap_or(0);
+    let signed_fill = if action == 1 || action == 2 { fill } else { -fill };
+    if let Some(trades) = order["trade"].as_array_mut() {
+        trades.push(json!({ "id": order_id, "p": price, "q": fill, "date": now }));
+    }
+    order["leaves_qty"] = json!(leaves_quantity - fill);
+    order["stat"] = json!(2);
+    state.orders[index] = order.clone();
+    vec![json!(["orders", [order]]).to_string(), fill_position(state, order_id, &ticker, signed_fill, price)]
+}
+
+// Cancel a held order. A fill set with fill_on_cancel is traded first, as if it came just before the cancel
+fn del_trade_order(state: &mut MockState, params: &HashMap<String, String>) -> serde_json::Value {
+    let order_id: i64 = params.get("order_id").and_then(|id| id.parse().ok()).unwrap_or(0);
+    let Some(index) = state.orders.iter().position(|order| order["id"].as_i64() == Some(order_id) && is_working(order)) else {
+        return json!({ "errMsg": format!("Order {} is not working", order_id), "code": 7 });
+    };
+    let fill = std::mem::take(&mut state.fill_on_cancel);
+    let mut messages = trade_held_order(state, index, fill);
+    let mut order = state.orders[index].clone();
+    order["stat"] = json!(31);
+    order["stat_d"] = json!(chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string());
+    messages.push(json!(["orders", [order.clone()]]).to_string());
+    state.orders[index] = order;
+    state.clients.retain(|client| messages.iter().all(|message| client.send(message.clone()).is_ok()));
+    json!({ "order_id": order_id })
+}
+
+async fn handle_ws(stream: TcpStream, state: Arc<Mutex<MockState>>) {
+    let sid = state.lock().unwrap().sid.clone();
+    // Signature required by tungstenite
+    #[allow(clippy::result_large_err)]
+    let check_sid = |request: &WsRequest, response: WsResponse| -> Result<WsResponse, ErrorResponse> {
+        let query = request.uri().query().unwrap_or("");
+        if query == format!("SID={}", sid) {
+            Ok(response)
+        } else {
+            let mut error = ErrorResponse::new(Some("Wrong SID".to_string()));
+            *error.status_mut() = tokio_tungstenite::tungstenite::http::StatusCode::FORBIDDEN;
+            Err(error)
+        }
+    };
+    let Ok(ws_stream) = tokio_tungstenite::accept_hdr_async(stream, check_sid).await else {
+        return;
+    };
+    let (mut write, mut read) = ws_stream.split();
+    let (sender, mut receiver) = mpsc::unbounded_channel::<String>();
+    state.lock().unwrap().clients.push(sender.clone());
+    tokio::spawn(async move {
+        while let Some(message) = receiver.recv().await {
+            if write.send(Message::Text(message)).await.is_err() {
+                break;
+            }
+        }
+    });
+    while let Some(Ok(message)) = read.next().await {
+        let Message::Text(text) = message else {
+            continue;
+        };
+        let mut state = state.lock().unwrap();
+        state.ws_requests.push(text.clone());
+        // Account state is sent in answer to the subscription
+        let answer = match request_cmd(&text).as_deref() {
+            Some("portfolio") => Some(portfolio_message(&state.positions)),
+            Some("orders") => Some(json!(["orders", state.orders]).to_string()),
+            _ => None,
+        };
+        if let Some(answer) = answer {
+            let _ = sender.send(answer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, RwLock};
+    use super::*;
+    use crate::api::{ConnectionStatus, TradernetClient};
+    use crate::api_utils::*;
+    use crate::config::Config;
+    use crate::error::Error;
+    use crate::processed_data::{OrderStatus, Portfolio, Position};
+    use crate::session::TradingSession;
+    use crate::trading_utils::SLType;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn test_credentials() -> Credentials {
+        Credentials {
+            id: "mock".to_string(),
+            login: "trader@example.com".to_string(),
+            password: "password".to_string(),
+            public_key: "public".to_string(),
+            secret_key: "secret".to_string(),
+            paper_trading: false,
+            endpoints: Endpoints::default(),
+        }
+    }
+
+    // Poll until the condition holds or fail the test
+    async fn eventually<F: FnMut() -> bool>(what: &str, mut condition: F) {
+        let started = std::time::Instant::now();
+        while !condition() {
+            assert!(started.elapsed() < TIMEOUT, "Timed out waiting for {}", what);
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    fn position(portfolios: &Arc<RwLock<Vec<Portfolio>>>, ticker: &str) -> Option<Position> {
+        portfolios.read().unwrap().iter()
+            .flat_map(|portfolio| portfolio.portfolio.iter())
+            .find(|position| position.ticker == ticker)
+            .cloned()
+    }
+
+    #[tokio::test]
+    async fn login_checks_password() {
+        let credentials = test_credentials();
+        let server = MockServer::start(&credentials).await.unwrap();
+        let credentials = Credentials { endpoints: server.endpoints.clone(), ..credentials };
+
+        let sid = get_sid_ff(credentials.clone()).await.unwrap();
+        assert_eq!(sid, server.sid());
+
+        let wrong_password = Credentials { password: "wrong".to_string(), ..credentials };
+        assert!(matches!(get_sid_ff(wrong_password).await, Err(Error::Auth(_))));
+    }
+
+    #[tokio::test]
+    async fn commands_are_signed_with_increasing_nonces() {
+        let credentials = test_credentials();
+        let server = MockServer::start(&credentials).await.unwrap();
+        server.set_fill_price(10.5);
+
+        let client = TradernetClient::new(&credentials.public_key, &credentials.secret_key, &server.endpoints.cmd_url);
+        for _ in 0..3 {
+            client.get_positions().await.unwrap();
+        }
+        let order_ack = client.send_order("AAPL.US".to_string(), ActionType::Buy, OrderType::Limit, 10.25, 3, Expirations::Day).await.unwrap();
+        assert_eq!(order_ack.order_id, Some(1001));
+        let commands = server.commands();
+        assert_eq!(commands.len(), 4);
+        assert_eq!(commands[3].0, "putTradeOrder");
+        assert_eq!(commands[3].1["limit_price"], "10.25");
+
+        let wrong_secret = TradernetClient::new(&credentials.public_key, "other", &server.endpoints.cmd_url);
+        match wrong_secret.get_positions().await {
+            Err(Error::BrokerRejection { code, .. }) => assert_eq!(code, Some(3)),
+            other => panic!("Expected rejection, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    // login -> subscribe -> order -> portfolio update -> stop-loss close
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn order_and_stop_loss_close() {
+        let credentials = test_credentials();
+        let server = MockServer::start(&credentials).await.unwrap();
+        let credentials = Credentials { endpoints: server.endpoints.clone(), ..credentials };
+        let ticker = "SPY.US";
+
+        // Login and subscribe
+        let (data_sender, _data_receiver) = mpsc::channel(100);
+        let (errors_sender, mut errors_receiver) = mpsc::unbounded_channel();
+        let mut session = TradingSession::new(Config::default(), data_sender, errors_sender);
+        session.start(&[credentials]);
+        tokio::time::timeout(TIMEOUT, server.play(vec![
+            ScenarioStep::WaitForRequest("quotes".to_string()),
+            ScenarioStep::WaitForRequest("orderBook".to_string()),
+            ScenarioStep::WaitForRequest("orders".to_string()),
+        ])).await.unwrap();
+        let (broker, status) = {
+            let connections = session.connections.read().unwrap();
+            let status = *connections[0].status.borrow();
+            (Arc::clone(&connections[0].broker), status)
+        };
+        assert_eq!(status, ConnectionStatus::Connected);
+        let portfolios = Arc::clone(&session.portfolios);
+        let orders = Arc::clone(&session.orders);
+        let order_tracker = session.order_tracker.clone();
+
+        // Open a position
+        server.set_fill_price(500.0);
+        order_tracker.send_order(Arc::clone(&broker), ticker.to_string(), ActionType::Buy, OrderType::Market, 0.0, 10, None);
+        eventually("position", || position(&portfolios, ticker).map(|position| position.quantity) == Some(10)).await;
+        eventually("filled order", || orders.read().unwrap().iter()
+            .flat_map(|order_store| order_store.orders.iter())
+            .any(|order| order.status == OrderStatus::Filled)).await;
+
+        // The first price sets the loss limiter, the drop below it raises the close alert
+        let order_book = order_book_messages(ticker, &[(500.0, 500.05), (499.85, 499.9)]);
+        server.send(&order_book[0]);
+        eventually("loss limiter", || position(&portfolios, ticker).map(|position| position.sl_type) == Some(SLType::LossLimiter)).await;
+        server.set_fill_price(499.85);
+        server.send(&order_book[1]);
+
+        // StopLossCloser closes the position with one market order
+        eventually("closed position", || position(&portfolios, ticker).is_none()).await;
+        let orders_sent: Vec<_> = server.commands().into_iter().filter(|(cmd, _)| cmd == "putTradeOrder").collect();
+        assert_eq!(orders_sent.len(), 2);
+        assert_eq!(orders_sent[1].1["action_id"], "3");
+        assert_eq!(orders_sent[1].1["qty"], "10");
+        assert!(errors_receiver.try_recv().is_err());
+    }
+
+    // A rejected close leaves the closing state, and the next price sends the close again
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn rejected_close_is_sent_again() {
+        let credentials = test_credentials();
+        let server = MockServer::start(&credentials).await.unwrap();
+        let credentials = Credentials { endpoints: server.endpoints.clone(), ..credentials };
+        let ticker = "SPY.US";
+
+        let (data_sender, _data_receiver) = mpsc::channel(100);
+        let (errors_sender, _errors_receiver) = mpsc::unbounded_channel();
+        let mut session = TradingSession::new(Config::default(), data_sender, errors_sender);
+        session.start(&[credentials]);
+        tokio::time::timeout(TIMEOUT, server.play(vec![
+            ScenarioStep::WaitForRequest("orderBook".to_string()),
+            ScenarioStep::WaitForRequest("orders".to_string()),
+        ])).await.unwrap();
+        let broker = Arc::clone(&session.connections.read().unwrap()[0].broker);
+        let portfolios = Arc::clone(&session.portfolios);
+        let orders = Arc::clone(&session.orders);
+
+        server.set_fill_price(500.0);
+        session.order_tracker.send_order(Arc::clone(&broker), ticker.to_string(), ActionType::Buy, OrderType::Market, 0.0, 10, None);
+        eventually("position", || position(&portfolios, ticker).map(|position| position.quantity) == Some(10)).await;
+        let order_book = order_book_messages(ticker, &[(500.0, 500.05), (499.85, 499.9), (499.8, 499.85)]);
+        server.send(&order_book[0]);
+        eventually("loss limiter", || position(&portfolios, ticker).map(|position| position.sl_type) == Some(SLType::LossLimiter)).await;
+
+        // The first close is rejected
+        server.reject_next_order();
+        server.set_fill_price(499.85);
+        server.send(&order_book[1]);
+        eventually("rejected close", || orders.read().unwrap().iter()
+            .flat_map(|order_store| order_store.orders.iter())
+            .any(|order| order.status == OrderStatus::Rejected)).await;
+
+        // The close is sent again on the next message, the rejection or the next price
+        server.send(&order_book[2]);
+        eventually("closed position", || position(&portfolios, ticker).is_none()).await;
+        let orders_sent: Vec<_> = server.commands().into_iter().filter(|(cmd, _)| cmd == "putTradeOrder").collect();
+        assert_eq!(orders_sent.len(), 3);
+        assert_eq!(orders_sent[2].1["action_id"], "3");
+        assert_eq!(orders_sent[2].1["qty"], "10");
+    }
+
+    // A trade racing the cancel: the replacement is placed after the cancel is confirmed, for the unfilled rest
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn modification_replaces_the_unfilled_rest() {
+        let credentials = test_credentials();
+        let server = MockServer::start(&credentials).await.unwrap();
+        let credentials = Credentials { endpoints: server.endpoints.clone(), ..credentials };
+        let ticker = "SPY.US";
+
+        let (data_sender, _data_receiver) = mpsc::channel(100);
+        let (errors_sender, mut errors_receiver) = mpsc::unbounded_channel();
+        let mut session = TradingSession::new(Config::default(), data_sender, errors_sender);
+        session.start(&[credentials]);
+        tokio::time::timeout(TIMEOUT, server.play(vec![
+            ScenarioStep::WaitForRequest("orders".to_string()),
+        ])).await.unwrap();
+        let broker = Arc::clone(&session.connections.read().unwrap()[0].broker);
+        let portfolios = Arc::clone(&session.portfolios);
+        let orders = Arc::clone(&session.orders);
+        let working_order = || orders.read().unwrap().iter()
+            .flat_map(|order_store| order_store.orders.iter())
+            .find(|order| order.status == OrderStatus::Accepted)
+            .cloned();
+
+        server.hold_limit_orders();
+        session.order_tracker.send_order(Arc::clone(&broker), ticker.to_string(), ActionType::Buy, OrderType::Limit, 500.0, 10, None);
+        eventually("working order", || working_order().is_some()).await;
+        let order = working_order().unwrap();
+
+        server.fill_on_cancel(4);
+        session.order_tracker.modify_order(Arc::clone(&broker), &order, 499.5, 10);
+        eventually("replacement", || server.commands().iter().filter(|(cmd, _)| cmd == "putTradeOrder").count() == 2).await;
+        let commands = server.commands();
+        let cancel = commands.iter().position(|(cmd, _)| cmd == "delTradeOrder").unwrap();
+        let replacement = commands.iter().rposition(|(cmd, _)| cmd == "putTradeOrder").unwrap();
+        assert!(cancel < replacement);
+        assert_eq!(commands[replacement].1["qty"], "6");
+        assert_eq!(commands[replacement].1["limit_price"], "499.5");
+        eventually("partial fill", || position(&portfolios, ticker).map(|position| position.quantity) == Some(4)).await;
+        let old_order = orders.read().unwrap()[0].get(order.order_id).cloned().unwrap();
+        assert_eq!((old_order.status, old_order.filled_quantity), (OrderStatus::Cancelled, 4.0));
+        assert!(errors_receiver.try_recv().is_err());
+    }
+
+    // Fills before the modification are subtracted once: the dialog passes the whole quantity
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn modification_of_a_partially_filled_order() {
+        let credentials = test_credentials();
+        let server = MockServer::start(&credentials).await.unwrap();
+        let credentials = Credentials { endpoints: server.endpoints.clone(), ..credentials };
+        let ticker = "SPY.US";
+
+        let (data_sender, _data_receiver) = mpsc::channel(100);
+        let (errors_sender, mut errors_receiver) = mpsc::unbounded_channel();
+        let mut session = TradingSession::new(Config::default(), data_sender, errors_sender);
+        session.start(&[credentials]);
+        tokio::time::timeout(TIMEOUT, server.play(vec![
+            ScenarioStep::WaitForRequest("orders".to_string()),
+        ])).await.unwrap();
+        let broker = Arc::clone(&session.connections.read().unwrap()[0].broker);
+        let orders = Arc::clone(&session.orders);
+        let working_order = || orders.read().unwrap().iter()
+            .flat_map(|order_store| order_store.orders.iter())
+            .find(|order| order.is_working())
+            .cloned();
+
+        server.hold_limit_orders();
+        session.order_tracker.send_order(Arc::clone(&broker), ticker.to_string(), ActionType::Buy, OrderType::Limit, 500.0, 10, None);
+        eventually("working order", || working_order().is_some()).await;
+        server.fill_held_order(working_order().unwrap().order_id, 4);
+        eventually("partial fill", || working_order().map(|order| order.filled_quantity) == Some(4.0)).await;
+        let order = working_order().unwrap();
+        assert_eq!(order.status, OrderStatus::PartiallyFilled);
+
+        session.order_tracker.modify_order(Arc::clone(&broker), &order, 499.5, order.quantity as u64);
+        eventually("replacement", || server.commands().iter().filter(|(cmd, _)| cmd == "putTradeOrder").count() == 2).await;
+        let commands = server.commands();
+        let replacement = commands.iter().rposition(|(cmd, _)| cmd == "putTradeOrder").unwrap();
+        assert_eq!(commands[replacement].1["qty"], "6");
+        eventually("tracked replacement", || orders.read().unwrap()[0].orders.len() == 2).await;
+        let replacement_order = orders.read().unwrap()[0].orders[1].clone();
+        assert_eq!((replacement_order.quantity, replacement_order.price), (6.0, 499.5));
+        assert!(errors_receiver.try_recv().is_err());
+    }
+}
@@ -1,64 +1,76 @@
 use std::fs::OpenOptions;
 use std::io::Write;
-use std::sync::{Arc, Mutex, RwLock};
+use std::sync::{Arc, RwLock};
 use std::sync::atomic::{AtomicI64, Ordering};
-use tokio::task;
 use tokio::sync::mpsc;
 use crate::market_data::{deserialize_message, MarketData};
 use crate::processed_data::*;
 use crate::trading_utils::*;
 use crate::api::*;
 use crate::api_utils::*;
+use crate::config::Config;
+use crate::candles::CandleBook;
 
+// Server message of an account, deserialized once when it is queued (see bus)
+#[derive(Debug)]
+pub struct ServerMessage {
+    pub timestamp: chrono::DateTime<chrono::Local>, // Arrival time
+    pub data: String, // Raw message, for recorders and logs
+    pub market_data: Option<MarketData>, // None for messages of unknown format
+}
+impl ServerMessage {
+    pub fn new(timestamp: chrono::DateTime<chrono::Local>, data: String) -> Self {
+        let market_data = deserialize_message(&data);
+        Self { timestamp, data, market_data }
+    }
+}
+
+// Subscribers are called in order of the messages, on the worker of the account's queue (see bus).
+// They must not block: network requests are spawned
 pub trait MessageSubscriber: Send + Sync {
-    fn on_data(&mut self, id: &str, timestamp: chrono::DateTime<chrono::Local>, data: &str);
+    fn on_data(&mut self, id: &str, message: &ServerMessage);
 }
 pub trait MessagePublisher {
     fn subscribe(&mut self, subscriber: Box<dyn MessageSubscriber>);
-    fn notify_subscribers(&mut self, id: &str, timestamp: chrono::DateTime<chrono::Local>, data: &str);
+    fn notify_subscribers(&mut self, id: &str, message: &ServerMessage);
 }
+// The timestamp of the stages is the arrival time of the server message, so a replay sees the recorded times
 pub trait MarketDataUpdateSubscriber: Send + Sync {
-    fn on_data(&mut self, id: &str, market_data: &MarketData);
+    fn on_data(&mut self, id: &str, timestamp: chrono::DateTime<chrono::Local>, market_data: &MarketData);
 }
 pub trait ProcessedDataSubscriber: Send + Sync {
-    fn on_data(&mut self, id: &str, positions: Vec<Position>);
+    fn on_data(&mut self, id: &str, timestamp: chrono::DateTime<chrono::Local>, positions: Vec<Position>);
 }
 pub trait PortfolioUpdaterSubscriber: Send + Sync {
     fn on_data(&mut self, id: &str);
 }
 
-// Server messages publisher
-#[derive(Clone)]
+// Server messages publisher, the head of the pipeline
+#[derive(Default)]
 pub struct ServerMessagesPublisher {
-    subscribers: Arc<Mutex<Vec<Box<dyn MessageSubscriber>>>>,
+    subscribers: Vec<Box<dyn MessageSubscriber>>,
 }
 impl ServerMessagesPublisher {
     pub fn new() -> Self {
-        Self { subscribers: Arc::new(Mutex::new(Vec::new())), }
+        Self { subscribers: Vec::new(), }
     }
 }
 impl MessagePublisher for ServerMessagesPublisher {
     fn subscribe(&mut self, subscriber: Box<dyn MessageSubscriber>) {
-        self.subscribers.lock().unwrap().push(subscriber);
+        self.subscribers.push(subscriber);
     }
-    fn notify_subscribers(&mut self, id: &str, timestamp: chrono::DateTime<chrono::Local>, data: &str) {
-        let subscribers = Arc::clone(&self.subscribers);
-        let data = data.to_string();
-        let id = id.to_string();
-        task::spawn(async move {
-            let mut subs = subscribers.lock().unwrap();
-            for subscriber in subs.iter_mut() {
-                subscriber.on_data(&id, timestamp, &data);
-            }
-        });
+    fn notify_subscribers(&mut self, id: &str, message: &ServerMessage) {
+        for subscriber in self.subscribers.iter_mut() {
+            subscriber.on_data(id, message);
+        }
     }
 }
 
 // Console output Subscriber
 pub struct ConsoleOutputSubscriber;
 impl MessageSubscriber for ConsoleOutputSubscriber {
-    fn on_data(&mut self, id: &str, timestamp: chrono::DateTime<chrono::Local>, data: &str) {
-        println!("{} {} {}", id, timestamp, data);
+    fn on_data(&mut self, id: &str, message: &ServerMessage) {
+        println!("{} {} {}", id, message.timestamp, message.data);
     }
 }
 
@@ -72,51 +84,41 @@ impl MessagesToFileSubscriber {
     }
 }
 impl MessageSubscriber for MessagesToFileSubscriber {
-    fn on_data(&mut self, id: &str, timestamp: chrono::DateTime<chrono::Local>, data: &str) {
+    fn on_data(&mut self, id: &str, message: &ServerMessage) {
         let mut file = OpenOptions::new()
             .create(true)
             .append(true)
             .open(&self.file_path)
             .expect("Unable to open file");
-        writeln!(file, "{} {} {}", id, timestamp, data).expect("Failed to write to file");
+        writeln!(file, "{} {} {}", id, message.timestamp, message.data).expect("Failed to write to file");
     }
 }
 
-// Deserialize messages from server Subscriber
-#[derive(Clone)]
-pub struct DataDeserializer {
+// Market data of the server messages Subscriber. Passes the messages deserialized by the bus on to the stages
+pub struct MarketDataPublisher {
     data_sender: mpsc::Sender<String>,
-    subscribers: Arc<Mutex<Vec<Box<dyn MarketDataUpdateSubscriber>>>>,
+    subscribers: Vec<Box<dyn MarketDataUpdateSubscriber>>,
 }
-impl DataDeserializer {
+impl MarketDataPublisher {
     pub fn new(data_sender: mpsc::Sender<String>) -> Self {
         Self { 
             data_sender,
-            subscribers: Arc::new(Mutex::new(Vec::new())),
+            subscribers: Vec::new(),
         }
     }
     pub fn subscribe(&mut self, subscriber: Box<dyn MarketDataUpdateSubscriber>) {
-        self.subscribers.lock().unwrap().push(subscriber);
+        self.subscribers.push(subscriber);
     }
-    pub fn notify_subscribers(&mut self, id: &str, market_data_update: MarketData) {
-        let subscribers = Arc::clone(&self.subscribers);
-        let id = id.to_string();
-        tokio::spawn(async move {
-            let mut subscribers = subscribers.lock().unwrap();
-            for subscriber in subscribers.iter_mut() {
-                subscriber.on_data(&id, &market_data_update);
-            }
-        });
+    pub fn notify_subscribers(&mut self, id: &str, timestamp: chrono::DateTime<chrono::Local>, market_data_update: &MarketData) {
+        for subscriber in self.subscribers.iter_mut() {
+            subscriber.on_data(id, timestamp, market_data_update);
+        }
     }
 }
-impl MessageSubscriber for DataDeserializer {
-    fn on_data(&mut self, id: &str, timestamp: chrono::DateTime<chrono::Local>, data: &str) {
-        match deserialize_message(data) {
-            Some(deserialized_data) => {
-                // let message = serde_json::to_string_pretty(&deserialized_data).unwrap(); 
-                // let _ = self.data_sender.try_send(message);
-                self.notify_subscribers(id, deserialized_data);
-            },
+impl MessageSubscriber for MarketDataPublisher {
+    fn on_data(&mut self, id: &str, message: &ServerMessage) {
+        match &message.market_data {
+            Some(market_data) => self.notify_subscribers(id, message.timestamp, market_data),
             None => {
                 let _ = self.data_sender.try_send("Unable to deserialize message".to_string());
             },
@@ -125,48 +127,60 @@ impl MessageSubscriber for DataDeserializer {
 }
 
 // Update market data Subscriber
-#[derive(Clone)]
 pub struct DataProcessor {
     data_sender: mpsc::Sender<String>,
     order_books: Arc<RwLock<Vec<OrderBook>>>,
     quotes: Arc<RwLock<Vec<QuoteBook>>>,
+    orders: Arc<RwLock<Vec<OrderStore>>>,
     tickers: Arc<RwLock<Vec<TickerOptions>>>,
     days_to_expiration: Arc<AtomicI64>,
-    subscribers: Arc<Mutex<Vec<Box<dyn ProcessedDataSubscriber>>>>,
+    connections: Arc<RwLock<Vec<Connection>>>,
+    subscribers: Vec<Box<dyn ProcessedDataSubscriber>>,
 }
 impl DataProcessor {
     pub fn new(
         data_sender: mpsc::Sender<String>, 
         order_books: Arc<RwLock<Vec<OrderBook>>>, 
         quotes: Arc<RwLock<Vec<QuoteBook>>>,
+        orders: Arc<RwLock<Vec<OrderStore>>>,
         tickers: Arc<RwLock<Vec<TickerOptions>>>,
         days_to_expiration: Arc<AtomicI64>,
+        connections: Arc<RwLock<Vec<Connection>>>,
     ) -> Self {
         Self {
             data_sender,
             order_books,
             quotes,
+            orders,
             tickers,
             days_to_expiration,
-            subscribers: Arc::new(Mutex::new(Vec::new())),
+            connections,
+            subscribers: Vec::new(),
+        }
+    }
+    // Resubscribe the order books of the account to get a consistent snapshot of the ticker.
+    // The books of the other tickers stay in use
+    fn resnapshot(&self, id: &str, ticker: &str) {
+        let _ = self.data_sender.try_send(format!("{} Order book of {} is out of sequence, resubscribing", id, ticker));
+        if let Some(order_book) = self.order_books.write().unwrap().iter_mut().find(|order_book| order_book.id == id) {
+            order_book.expect_snapshot(ticker);
+        }
+        let connections = self.connections.read().unwrap();
+        if let Some(connection) = connections.iter().find(|connection| connection.credentials.id == id) {
+            request_order_books(connection, &self.order_books);
         }
     }
     pub fn subscribe(&mut self, subscriber: Box<dyn ProcessedDataSubscriber>) {
-        self.subscribers.lock().unwrap().push(subscriber);
+        self.subscribers.push(subscriber);
     }
-    pub fn notify_subscribers(&mut self, id: &str, positions: Vec<Position>) {
-        let subscribers = Arc::clone(&self.subscribers);
-        let id = id.to_string();
-        tokio::spawn(async move {
-            let mut subscribers = subscribers.lock().unwrap();
-            for subscriber in subscribers.iter_mut() {
-                subscriber.on_data(&id, positions.clone());
-            }
-        });
+    pub fn notify_subscribers(&mut self, id: &str, timestamp: chrono::DateTime<chrono::Local>, positions: Vec<Position>) {
+        for subscriber in self.subscribers.iter_mut() {
+            subscriber.on_data(id, timestamp, positions.clone());
+        }
     }
 }
 impl MarketDataUpdateSubscriber for DataProcessor {
-    fn on_data(&mut self, id: &str, market_data: &MarketData) {
+    fn on_data(&mut self, id: &str, timestamp: chrono::DateTime<chrono::Local>, market_data: &MarketData) {
         let mut positions = Vec::new();
         match market_data {
             MarketData::OrderBookMessage(order_book_message) => {
@@ -177,47 +191,32 @@ impl MarketDataUpdateSubscriber for DataProcessor {
                     order_books.push(OrderBook::new(id));
                     order_books.last_mut().unwrap()
                 };
-                for ins_entry in &order_book_message.ins {
-                    let row = OrderBookRow {
-                        price: ins_entry.p,
-                        side: match ins_entry.s.as_str() {
-                            "B" => Side::Buy,
-                            "S" => Side::Sell,
-                            _ => continue,
-                        },
-                        quantity: ins_entry.q,
-                        position: ins_entry.k,
-                        message_number: order_book_message.n,
-                    };
-                    let mut tickers = self.tickers.write().unwrap();
-                    // Обновляем тикеры опционов для отслеживаемых базовых активов
-                    if let Some(ticker_row) = tickers.iter_mut().find (|ticker_row| ticker_row.ticker == order_book_message.i) {
-                        ticker_row.update(row.side.clone(), row.price.clone(), self.days_to_expiration.load(Ordering::Relaxed));
+                match order_book.apply(order_book_message) {
+                    DepthUpdate::Applied => {}
+                    DepthUpdate::Ignored => return,
+                    DepthUpdate::Gap => {
+                        drop(order_books);
+                        self.resnapshot(id, &order_book_message.i);
+                        return;
                     }
-
-                    order_book.add_row(&order_book_message.i, row.clone());
-                    if row.side == Side::Buy {
-                        let price_update = Position {
-                            position_id: 0,
-                            ticker: order_book_message.i.clone(),
-                            quantity: 0,
-                            open_price: 0.0,
-                            current_price: ins_entry.p,
-                            pnl: 0.0,
-                            sl_strategy: SLStrategy::InsuranceStops,
-                            sl_type: SLType::None,
-                            sl_price: 0.0,
-                            close_alert: false,
-                            closing: false,
-                        };
-                        positions.push(price_update);
-                    };
                 }
-                for del_entry in &order_book_message.del {
-                    order_book.remove_row(del_entry.p);
+                // Stops and option tickers follow the top of the book, not every level of the message
+                let ticker = &order_book_message.i;
+                let (best_bid, best_ask) = (order_book.best_bid(ticker), order_book.best_ask(ticker));
+                drop(order_books);
+                let mut tickers = self.tickers.write().unwrap();
+                // Обновляем тикеры опционов для отслеживаемых базовых активов
+                if let Some(ticker_row) = tickers.iter_mut().find (|ticker_row| ticker_row.ticker == *ticker) {
+                    let days_to_expiration = self.days_to_expiration.load(Ordering::Relaxed);
+                    if let Some(best_bid) = best_bid {
+                        ticker_row.update(Side::Buy, best_bid, days_to_expiration);
+                    }
+                    if let Some(best_ask) = best_ask {
+                        ticker_row.update(Side::Sell, best_ask, days_to_expiration);
+                    }
                 }
-                for upd_entry in &order_book_message.upd {
-                    order_book.update_row(upd_entry.p, upd_entry.q, order_book_message.n);
+                if let Some(best_bid) = best_bid {
+                    positions.push(Position::price_update(ticker, best_bid));
                 }
             }
             MarketData::QuoteMessage(quote_message) => {
@@ -234,62 +233,101 @@ impl MarketDataUpdateSubscriber for DataProcessor {
                     bid_price: quote_message.bbp,
                     last_trade: quote_message.ltp,
                     last_trade_time: quote_message.clone().ltt,
+                    last_trade_at: quote_message.ltp.map(|_| timestamp),
+                    min_step: quote_message.min_step,
+                    volatility: quote_message.volatility,
                 };
                 quote_book.add_quote(quote_data);
             }
+            MarketData::OrdersMessage(order_messages) => {
+                let mut orders = self.orders.write().unwrap();
+                let order_store = if let Some(order_store) = orders.iter_mut().find (|order_store| order_store.id == id) {
+                    order_store
+                } else {
+                    orders.push(OrderStore::new(id));
+                    orders.last_mut().unwrap()
+                };
+                for order_message in order_messages {
+                    order_store.update(OrderRecord::from_message(order_message));
+                }
+            }
             MarketData::PortfolioMessage(portfolio_message) => {
                 for pos_entry in &portfolio_message.pos {
-                    let position = Position {
-                        position_id: pos_entry.acc_pos_id,
-                        ticker: pos_entry.i.to_string(),
-                        quantity: pos_entry.q,
-                        open_price: pos_entry.price_a,
-                        current_price: 0.0,
-                        pnl: 0.0,
-                        sl_strategy: SLStrategy::InsuranceStops,
-                        sl_type: SLType::None,
-                        sl_price: 0.0,
-                        close_alert: false,
-                        closing: false,
-                    };
-                    positions.push(position);
+                    // The stream has no open time: a new position counts from the message that reported it
+                    positions.push(Position {
+                        opened_at: timestamp,
+                        ..Position::new(pos_entry.acc_pos_id, &pos_entry.i, pos_entry.q, pos_entry.price_a)
+                    });
                 }
             }
         }
-        self.notify_subscribers(id, positions);
+        self.notify_subscribers(id, timestamp, positions);
+    }
+}
+
+// Aggregate the last trades of quotes into candles Subscriber. Subscribed before DataProcessor,
+// so the stops see the bar completed by the same message
+pub struct CandleBuilder {
+    candles: Arc<RwLock<Vec<CandleBook>>>,
+    config: Arc<RwLock<Config>>,
+}
+impl CandleBuilder {
+    pub fn new(candles: Arc<RwLock<Vec<CandleBook>>>, config: Arc<RwLock<Config>>) -> Self {
+        Self { candles, config }
+    }
+}
+impl MarketDataUpdateSubscriber for CandleBuilder {
+    fn on_data(&mut self, id: &str, _timestamp: chrono::DateTime<chrono::Local>, market_data: &MarketData) {
+        let MarketData::QuoteMessage(quote_message) = market_data else { return };
+        let Some(ticker) = &quote_message.c else { return };
+        let interval = self.config.read().unwrap().stop_parameters(ticker).candle_interval;
+        let mut candles = self.candles.write().unwrap();
+        let candle_book = if let Some(candle_book) = candles.iter_mut().find (|candle_book| candle_book.id == id) {
+            candle_book
+        } else {
+            candles.push(CandleBook::new(id));
+            candles.last_mut().unwrap()
+        };
+        candle_book.add_quote(quote_message, interval);
     }
 }
 
-#[derive(Clone)]
 pub struct PortfolioUpdater {
     portfolios: Arc<RwLock<Vec<Portfolio>>>,
-    subscribers: Arc<Mutex<Vec<Box<dyn PortfolioUpdaterSubscriber>>>>,
+    config: Arc<RwLock<Config>>,
+    candles: Arc<RwLock<Vec<CandleBook>>>,
+    quotes: Arc<RwLock<Vec<QuoteBook>>>,
+    orders: Arc<RwLock<Vec<OrderStore>>>,
+    subscribers: Vec<Box<dyn PortfolioUpdaterSubscriber>>,
 }
 impl PortfolioUpdater {
     pub fn new(
-        portfolios: Arc<RwLock<Vec<Portfolio>>>
+        portfolios: Arc<RwLock<Vec<Portfolio>>>,
+        config: Arc<RwLock<Config>>,
+        candles: Arc<RwLock<Vec<CandleBook>>>,
+        quotes: Arc<RwLock<Vec<QuoteBook>>>,
+        orders: Arc<RwLock<Vec<OrderStore>>>,
     ) -> Self {
         Self {
             portfolios,
-            subscribers: Arc::new(Mutex::new(Vec::new())),
+            config,
+            candles,
+            quotes,
+            orders,
+            subscribers: Vec::new(),
         }
     }
     pub fn subscribe(&mut self, subscriber: Box<dyn PortfolioUpdaterSubscriber>) {
-        self.subscribers.lock().unwrap().push(subscriber);
+        self.subscribers.push(subscriber);
     }
     pub fn notify_subscribers(&mut self, id: &str) {
-        let subscribers = Arc::clone(&self.subscribers);
-        let id = id.to_string();
-        tokio::spawn(async move {
-            let mut subscribers = subscribers.lock().unwrap();
-            for subscriber in subscribers.iter_mut() {
-                subscriber.on_data(&id);
-            }
-        });
+        for subscriber in self.subscribers.iter_mut() {
+            subscriber.on_data(id);
+        }
     }
 }
 impl ProcessedDataSubscriber for PortfolioUpdater {
-    fn on_data(&mut self, id: &str, positions: Vec<Position>) {
+    fn on_data(&mut self, id: &str, timestamp: chrono::DateTime<chrono::Local>, positions: Vec<Position>) {
         let mut portfolios = self.portfolios.write().unwrap();
         let portfolio = if let Some(portfolio) = portfolios.iter_mut().find (|portfolio| portfolio.id == id) {
             portfolio
@@ -297,6 +335,12 @@ impl ProcessedDataSubscriber for PortfolioUpdater {
             portfolios.push(Portfolio::new(id));
             portfolios.last_mut().unwrap()
         };
+        portfolio.updated_at = timestamp;
+        let config = self.config.read().unwrap();
+        let candles = self.candles.read().unwrap();
+        let candle_book = candles.iter().find(|candle_book| candle_book.id == id);
+        let quotes = self.quotes.read().unwrap();
+        let quote_book = quotes.iter().find(|quote_book| quote_book.id == id);
         for position_update in positions {
             // If this is an update to the current price
             if position_update.position_id == 0 {
@@ -304,7 +348,24 @@ impl ProcessedDataSubscriber for PortfolioUpdater {
                     position.current_price = position_update.current_price;
                     position.pnl = ( position.current_price - position.open_price ) * position.quantity as f64;
                     // Checking stop-loss
-                    (position.sl_type, position.sl_price, position.close_alert) = check_sl(&position);
+                    let parameters = config.stop_parameters(&position.ticker);
+                    let quote = quote_book.and_then(|quote_book| quote_book.quote(&position.ticker));
+                    let (min_step, volatility) = (quote.and_then(|quote| quote.min_step), quote.and_then(|quote| quote.volatility));
+                    position.distance_mode = parameters.mode;
+                    match parameters.in_points(position.open_price, min_step, volatility) {
+                        Some(parameters) => {
+                            let context = StopContext {
+                                parameters,
+                                rules: &config.rules,
+                                candle_stop: candle_book.and_then(|candle_book| candle_book.series(&position.ticker))
+                                    .and_then(|series| series.tail_stop(parameters.candle_offset)),
+                                now: timestamp,
+                            };
+                            (position.sl_type, position.sl_price, position.close_alert) = check_sl(position, &context);
+                        }
+                        // Until the quote brings the volatility of the ticker the stop is not moved, only checked
+                        None => position.close_alert |= stop_hit(position),
+                    }
                 }
             } else {
                 if let Some(position) = portfolio.portfolio.iter_mut().find(|position| position.ticker == position_update.ticker) {
@@ -313,6 +374,16 @@ impl ProcessedDataSubscriber for PortfolioUpdater {
                         portfolio.portfolio.retain(|position| position.ticker != position_update.ticker);
                     // If the quantity and current price have changed (when adding a position)
                     } else {
+                        // A partial fill of the close order leaves the rest to close again
+                        if position.quantity != position_update.quantity {
+                            position.closing = false;
+                            position.close_order = None;
+                        }
+                        // A flipped position loses on the other side of the underlying stop
+                        if position.quantity.signum() * position_update.quantity.signum() < 0 {
+                            position.underlying_stop = position.underlying_stop.take()
+                                .map(|underlying_stop| UnderlyingStop::new(&position.ticker, position_update.quantity, underlying_stop.level));
+                        }
                         position.open_price = position_update.open_price;
                         position.quantity = position_update.quantity;
                     }
@@ -322,22 +393,59 @@ impl ProcessedDataSubscriber for PortfolioUpdater {
                 }
             }
         };
+        // Close orders rejected or cancelled by the broker
+        let orders = self.orders.read().unwrap();
+        let order_store = orders.iter().find(|order_store| order_store.id == id);
+        for position in portfolio.portfolio.iter_mut().filter(|position| position.closing) {
+            position.check_close_order(order_store);
+        }
+        drop(orders);
+        // Underlying stops, on the recent last trades of the underlyings' quotes
+        for position in portfolio.portfolio.iter_mut() {
+            if let Some(underlying_stop) = &position.underlying_stop {
+                let underlying_price = quote_book.and_then(|quote_book| quote_book.quote(&underlying_stop.ticker))
+                    .filter(|quote| quote.last_trade_at.is_some_and(|last_trade_at| timestamp - last_trade_at <= MAX_UNDERLYING_QUOTE_AGE))
+                    .and_then(|quote| quote.last_trade);
+                position.check_underlying_stop(underlying_price);
+            }
+        }
+        let close_alert = portfolio.portfolio.iter().any(|position| position.close_alert && !position.closing);
+        drop(quotes);
+        drop(candles);
+        drop(config);
+        drop(portfolios);
+        if close_alert {
+            self.notify_subscribers(id);
+        }
     }
 }
 
 #[derive(Clone)]
 pub struct QuotesRequester {
     connections: Arc<RwLock<Vec<Connection>>>,
+    order_books: Arc<RwLock<Vec<OrderBook>>>,
 }
 impl QuotesRequester {
-    pub fn new(connections: Arc<RwLock<Vec<Connection>>>) -> Self {
+    pub fn new(connections: Arc<RwLock<Vec<Connection>>>, order_books: Arc<RwLock<Vec<OrderBook>>>) -> Self {
         Self {
             connections,
+            order_books,
         }
     }
 }
+
+// Subscribe to the order books of the connection's tickers. The server may send their depth again,
+// the books take it as the new baseline
+pub fn request_order_books(connection: &Connection, order_books: &RwLock<Vec<OrderBook>>) {
+    if let Some(order_book) = order_books.write().unwrap().iter_mut().find(|order_book| order_book.id == connection.credentials.id) {
+        order_book.resubscribed(&connection.query_tickers);
+    }
+    if let Err(e) = connection.channels.sender_to_connector.send(Request::order_book(connection.query_tickers.clone()).message()) {
+        eprintln!("Failed to send order book request message: {}", e);
+    }
+}
 impl ProcessedDataSubscriber for QuotesRequester {
-    fn on_data(&mut self, id: &str, positions: Vec<Position>) {
+    fn on_data(&mut self, id: &str, _timestamp: chrono::DateTime<chrono::Local>, positions: Vec<Position>) {
         let mut connections = self.connections.write().unwrap();
         let connection = if let Some(connection) = connections.iter_mut().find (|connection| connection.credentials.id == id) {
             let mut tickers = connection.query_tickers.clone();
@@ -356,18 +464,10 @@ impl ProcessedDataSubscriber for QuotesRequester {
             if !new_tickers.is_empty() {
                 connection.query_tickers = tickers.clone();
                 let quotes_request = Request::quotes(tickers.clone());
-                let quotes_request_message = quotes_request.message();
-                let order_book_request = Request::order_book(tickers.clone());
-                let order_book_request_message = order_book_request.message();
-                let sender = connection.channels.sender_to_connector.clone();
-                tokio::spawn(async move {
-                    if let Err(e) = sender.send(quotes_request_message) {
-                        eprintln!("Failed to send quotes request message: {}", e);
-                    }
-                    if let Err(e) = sender.send(order_book_request_message) {
-                        eprintln!("Failed to send order book request message: {}", e);
-                    }
-                });
+                if let Err(e) = connection.channels.sender_to_connector.send(quotes_request.message()) {
+                    eprintln!("Failed to send quotes request message: {}", e);
+                }
+                request_order_books(connection, &self.order_books);
             }
         };
     }
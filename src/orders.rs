@@ -0,0 +1,193 @@
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use crate::api::Connection;
+use crate::api_utils::*;
+use crate::broker::Broker;
+use crate::observer::PortfolioUpdaterSubscriber;
+use crate::processed_data::{OrderRecord, OrderStatus, OrderStore, Portfolio, Position, Side};
+use crate::snapshot::StateVersions;
+
+// How long a modification waits for the orders stream to confirm the cancel
+const CANCEL_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Sends orders in the background and registers them in the order store of the account.
+// Further status changes come from the orders stream through DataProcessor
+#[derive(Clone)]
+pub struct OrderTracker {
+    orders: Arc<RwLock<Vec<OrderStore>>>,
+    portfolios: Arc<RwLock<Vec<Portfolio>>>,
+    errors_sender: mpsc::UnboundedSender<String>,
+    versions: StateVersions,
+}
+impl OrderTracker {
+    pub fn new(orders: Arc<RwLock<Vec<OrderStore>>>, portfolios: Arc<RwLock<Vec<Portfolio>>>, errors_sender: mpsc::UnboundedSender<String>, versions: StateVersions) -> Self {
+        Self { orders, portfolios, errors_sender, versions }
+    }
+    // closes is the ticker of the position closed by the order. The position gets the order id,
+    // or leaves the closing state if the order fails
+    #[allow(clippy::too_many_arguments)]
+    pub fn send_order(&self, broker: Arc<dyn Broker>, ticker: String, action: ActionType, order: OrderType, price: f64, qty: u64, closes: Option<String>) {
+        let tracker = self.clone();
+        tokio::spawn(async move {
+            let result = broker.send_order(ticker.clone(), action.clone(), order, price, qty, Expirations::Day).await;
+            let side = match action {
+                ActionType::Buy => Side::Buy,
+                ActionType::Sell => Side::Sell,
+            };
+            let close_order = match &result {
+                Ok(order_ack) => order_ack.order_id,
+                Err(_) => None,
+            };
+            {
+                let mut orders = tracker.orders.write().unwrap();
+                let order_store = if let Some(order_store) = orders.iter_mut().find(|order_store| order_store.id == broker.id()) {
+                    order_store
+                } else {
+                    orders.push(OrderStore::new(broker.id()));
+                    orders.last_mut().unwrap()
+                };
+                match result {
+                    Ok(order_ack) => {
+                        if let Some(order_id) = order_ack.order_id {
+                            order_store.add_sent(OrderRecord {
+                                order_id,
+                                ticker,
+                                side,
+                                quantity: qty as f64,
+                                filled_quantity: 0.0,
+                                price,
+                                fill_price: None,
+                                fill_time: None,
+                                status: OrderStatus::Pending,
+                                status_time: chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+                                message: String::new(),
+                            });
+                        }
+                    }
+                    Err(e) => {
+                        let _ = tracker.errors_sender.send(format!("{} order for {} failed: {}", broker.id(), ticker, e));
+                        order_store.add_rejected(&ticker, side, qty as f64, price, e.to_string());
+                    }
+                }
+            }
+            if let Some(closes) = closes {
+                let mut portfolios = tracker.portfolios.write().unwrap();
+                let position = portfolios.iter_mut()
+                    .filter(|portfolio| portfolio.id == broker.id())
+                    .flat_map(|portfolio| portfolio.portfolio.iter_mut())
+                    .find(|position| position.ticker == closes && position.closing);
+                if let Some(position) = position {
+                    position.close_order = close_order;
+                    position.closing = close_order.is_some();
+                    // The orders stream may have rejected the order before the answer came
+                    let orders = tracker.orders.read().unwrap();
+                    position.check_close_order(orders.iter().find(|order_store| order_store.id == broker.id()));
+                }
+            }
+            tracker.versions.orders.mark();
+            tracker.versions.portfolios.mark();
+        });
+    }
+    // Market order for the whole position. The closing flag prevents repeated orders until the order
+    // fails, is rejected or cancelled, or the quantity of the position changes
+    pub fn close_position(&self, broker: Arc<dyn Broker>, position: &mut Position) {
+        if position.closing || position.quantity == 0 {
+            return;
+        }
+        position.closing = true;
+        position.close_order = None;
+        let action = if position.quantity > 0 { ActionType::Sell } else { ActionType::Buy };
+        self.send_order(broker, position.ticker.clone(), action, OrderType::Market, 0.0, position.quantity.unsigned_abs() as u64, Some(position.ticker.clone()));
+    }
+    pub fn cancel_order(&self, broker: Arc<dyn Broker>, order_id: i64) {
+        let tracker = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = broker.cancel_order(order_id).await {
+                let _ = tracker.errors_sender.send(format!("{} cancel of order {} failed: {}", broker.id(), order_id, e));
+            }
+        });
+    }
+    // Cancel the order and place a new limit order for the unfilled rest of qty, once the orders stream
+    // confirms the cancel. The new order is tracked as a sent one
+    pub fn modify_order(&self, broker: Arc<dyn Broker>, order: &OrderRecord, price: f64, qty: u64) {
+        let tracker = self.clone();
+        let order = order.clone();
+        tokio::spawn(async move {
+            let cancelled = Box::pin(tracker.clone().done_order(broker.id().to_string(), order.order_id));
+            let result = broker.modify_order(order.order_id, order.ticker.clone(), order.side.action(), price, qty, cancelled).await;
+            match result {
+                Ok(order_ack) => {
+                    if let Some(order_id) = order_ack.order_id {
+                        let mut orders = tracker.orders.write().unwrap();
+                        if let Some(order_store) = orders.iter_mut().find(|order_store| order_store.id == broker.id()) {
+                            let filled_quantity = order_store.get(order.order_id).map_or(0.0, |old_order| old_order.filled_quantity);
+                            order_store.add_sent(OrderRecord {
+                                order_id,
+                                quantity: (qty as f64 - filled_quantity).max(0.0),
+                                filled_quantity: 0.0,
+                                price,
+                                fill_price: None,
+                                fill_time: None,
+                                status: OrderStatus::Pending,
+                                status_time: chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+                                message: String::new(),
+                                ..order
+                            });
+                        }
+                        tracker.versions.orders.mark();
+                    }
+                }
+                Err(e) => {
+                    let _ = tracker.errors_sender.send(format!("{} modification of order {} failed: {}", broker.id(), order.order_id, e));
+                }
+            }
+        });
+    }
+    // Filled quantity of the order once the orders stream reports it cancelled or filled, None after CANCEL_TIMEOUT
+    async fn done_order(self, id: String, order_id: i64) -> Option<f64> {
+        let started = tokio::time::Instant::now();
+        loop {
+            let order = self.orders.read().unwrap().iter()
+                .find(|order_store| order_store.id == id)
+                .and_then(|order_store| order_store.get(order_id).cloned());
+            if let Some(order) = order.filter(|order| order.status.is_final()) {
+                return Some(order.filled_quantity);
+            }
+            if started.elapsed() >= CANCEL_TIMEOUT {
+                return None;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+}
+
+// Closes positions when the stop-loss raises the close alert. Notified by PortfolioUpdater
+#[derive(Clone)]
+pub struct StopLossCloser {
+    portfolios: Arc<RwLock<Vec<Portfolio>>>,
+    connections: Arc<RwLock<Vec<Connection>>>,
+    order_tracker: OrderTracker,
+}
+impl StopLossCloser {
+    pub fn new(portfolios: Arc<RwLock<Vec<Portfolio>>>, connections: Arc<RwLock<Vec<Connection>>>, order_tracker: OrderTracker) -> Self {
+        Self { portfolios, connections, order_tracker }
+    }
+}
+impl PortfolioUpdaterSubscriber for StopLossCloser {
+    fn on_data(&mut self, id: &str) {
+        let broker = self.connections.read().unwrap().iter()
+            .find(|connection| connection.broker.id() == id)
+            .map(|connection| Arc::clone(&connection.broker));
+        let Some(broker) = broker else {
+            return;
+        };
+        let mut portfolios = self.portfolios.write().unwrap();
+        if let Some(portfolio) = portfolios.iter_mut().find(|portfolio| portfolio.id == id) {
+            for position in portfolio.portfolio.iter_mut().filter(|position| position.close_alert && !position.closing) {
+                println!("{} Stop-loss ({}) hit for {} at {:.2}, closing {}", id, position.sl_type.description(), position.ticker, position.current_price, position.quantity);
+                self.order_tracker.close_position(Arc::clone(&broker), position);
+            }
+        }
+    }
+}
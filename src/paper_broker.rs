@@ -0,0 +1,318 @@
+use std::sync::{Arc, Mutex, RwLock};
+use futures_util::future::BoxFuture;
+use serde_json::json;
+use tokio::sync::{mpsc, watch};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use crate::api::ConnectionStatus;
+use crate::api_utils::*;
+use crate::broker::{Broker, BrokerResult, FreedomBroker};
+use crate::bus::QUEUE_CAPACITY;
+use crate::crypto_utils::Credentials;
+use crate::error::Error;
+use crate::processed_data::{OrderBook, QuoteBook};
+
+// Position of the simulated account
+#[derive(Debug, Clone)]
+struct PaperPosition {
+    acc_pos_id: i64,
+    ticker: String,
+    quantity: i32,
+    open_price: f64,
+}
+
+struct PaperAccount {
+    positions: Vec<PaperPosition>,
+    orders: Vec<serde_json::Value>, // Filled orders in the format of the Tradernet orders stream
+    next_position_id: i64,
+    next_order_id: i64,
+    // Channel of the account messages to the pipeline. Set when the market data stream is connected
+    sender: Option<UnboundedSender<String>>,
+}
+
+// Paper-trading broker. Market data is streamed from Freedom24,
+// orders are filled against the best bid/ask of the local order book and quotes
+// and reported through synthetic orders and portfolio messages
+pub struct PaperBroker {
+    market_data: FreedomBroker,
+    id: String,
+    order_books: Arc<RwLock<Vec<OrderBook>>>,
+    quotes: Arc<RwLock<Vec<QuoteBook>>>,
+    account: Arc<Mutex<PaperAccount>>,
+    data_sender: mpsc::Sender<String>, // Fills are reported as pipeline diagnostics
+}
+impl PaperBroker {
+    pub fn new(credentials: Credentials, order_books: Arc<RwLock<Vec<OrderBook>>>, quotes: Arc<RwLock<Vec<QuoteBook>>>, data_sender: mpsc::Sender<String>) -> Self {
+        Self {
+            id: credentials.id.clone(),
+            market_data: FreedomBroker::new(credentials),
+            order_books,
+            quotes,
+            data_sender,
+            account: Arc::new(Mutex::new(PaperAccount {
+                positions: Vec::new(),
+                orders: Vec::new(),
+                next_position_id: 1,
+                next_order_id: 1,
+                sender: None,
+            })),
+        }
+    }
+
+    // Best bid and best ask for the ticker. Quotes have priority over the order book
+    fn best_prices(&self, ticker: &str) -> (Option<f64>, Option<f64>) {
+        let mut bid = None;
+        let mut ask = None;
+        if let Some(quote_book) = self.quotes.read().unwrap().iter().find(|quote_book| quote_book.id == self.id) {
+            if let Some(quote) = quote_book.quotes_list.iter().find(|quote| quote.ticker.as_deref() == Some(ticker)) {
+                bid = quote.bid_price.filter(|price| *price > 0.0);
+                ask = quote.ask_price.filter(|price| *price > 0.0);
+            }
+        }
+        if bid.is_none() || ask.is_none() {
+            if let Some(order_book) = self.order_books.read().unwrap().iter().find(|order_book| order_book.id == self.id) {
+                bid = bid.or(order_book.best_bid(ticker));
+                ask = ask.or(order_book.best_ask(ticker));
+            }
+        }
+        (bid, ask)
+    }
+
+    // Apply a fill to the simulated account and publish the order and portfolio updates. Returns the order id
+    fn fill(&self, ticker: &str, action: &ActionType, order: &OrderType, price: f64, qty: i32) -> i64 {
+        let mut account = self.account.lock().unwrap();
+        let order_id = account.next_order_id;
+        account.next_order_id += 1;
+        let now = chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
+        let order_message = json!({
+            "id": order_id,
+            "date": now,
+            "stat": 21, // Filled
+            "stat_d": now,
+            "instr": ticker,
+            "oper": match action { ActionType::Buy => 1, ActionType::Sell => 3 },
+            "type": order.ff_code(),
+            "p": price,
+            "q": qty,
+            "leaves_qty": 0,
+            "trade": [{ "id": order_id, "p": price, "q": qty, "date": now }],
+        });
+        account.orders.push(order_message.clone());
+        let signed_qty = match action {
+            ActionType::Buy => qty,
+            ActionType::Sell => -qty,
+        };
+        if let Some(position) = account.positions.iter_mut().find(|position| position.ticker == ticker) {
+            let new_quantity = position.quantity + signed_qty;
+            // Average the open price when the position is increased, the rest of a flipped position is opened at the fill
+            if signed_qty.signum() == position.quantity.signum() {
+                position.open_price = (position.open_price * position.quantity.abs() as f64 + price * qty as f64) / new_quantity.abs() as f64;
+            } else if new_quantity != 0 && new_quantity.signum() != position.quantity.signum() {
+                position.open_price = price;
+            }
+            position.quantity = new_quantity;
+        } else {
+            let acc_pos_id = account.next_position_id;
+            account.next_position_id += 1;
+            account.positions.push(PaperPosition {
+                acc_pos_id,
+                ticker: ticker.to_string(),
+                quantity: signed_qty,
+                open_price: price,
+            });
+        }
+        let _ = self.data_sender.try_send(format!("{} Paper order filled: {:?} {} {} @ {:.2}", self.id, action, qty, ticker, price));
+        // Closed positions are sent once with zero quantity and then forgotten
+        let message = portfolio_message(&account.positions);
+        account.positions.retain(|position| position.quantity != 0);
+        if let Some(sender) = &account.sender {
+            for message in [json!(["orders", [order_message]]).to_string(), message] {
+                if sender.send(message).is_err() {
+                    let _ = self.data_sender.try_send(format!("{} Paper account stream is closed", self.id));
+                }
+            }
+        }
+        order_id
+    }
+}
+
+// Synthetic message in the format of the Tradernet portfolio stream
+fn portfolio_message(positions: &[PaperPosition]) -> String {
+    let pos: Vec<serde_json::Value> = positions.iter().map(|position| json!({
+        "i": position.ticker,
+        "q": position.quantity,
+        "acc_pos_id": position.acc_pos_id,
+        "price_a": position.open_price,
+    })).collect();
+    json!(["portfolio", {
+        "loaded": true,
+        "m_id": "paper",
+        "acc": [],
+        "pos": pos,
+    }]).to_string()
+}
+
+// Messages about the live account state
+fn is_account_message(message: &str) -> bool {
+    serde_json::from_str::<Vec<serde_json::Value>>(message)
+        .ok()
+        .and_then(|values| values.first().and_then(|v| v.as_str()).map(|message_type| message_type == "portfolio" || message_type == "orders"))
+        .unwrap_or(false)
+}
+
+impl Broker for PaperBroker {
+    fn id(&self) -> &str {
+        &self.id
+    }
+    fn login(&self) -> BoxFuture<'_, BrokerResult<String>> {
+        self.market_data.login()
+    }
+    fn connect(&self, mut receiver: UnboundedReceiver<String>, sender: mpsc::Sender<String>, status: watch::Sender<ConnectionStatus>) -> BoxFuture<'_, BrokerResult<()>> {
+        Box::pin(async move {
+            let (sender_to_connector, connector_receiver) = mpsc::unbounded_channel();
+            let (sender_from_connector, mut receiver_from_connector) = mpsc::channel::<String>(QUEUE_CAPACITY);
+            // Messages of the simulated account come from fills, outside of async code
+            let (account_sender, mut account_receiver) = mpsc::unbounded_channel::<String>();
+            self.account.lock().unwrap().sender = Some(account_sender.clone());
+
+            // Portfolio and orders requests are answered by the simulated account, the rest goes to the live connection
+            let portfolio_request = Request::portfolio().message();
+            let orders_request = Request::orders().message();
+            let account = Arc::clone(&self.account);
+            tokio::spawn(async move {
+                while let Some(message) = receiver.recv().await {
+                    if message == portfolio_request {
+                        let positions_message = portfolio_message(&account.lock().unwrap().positions);
+                        let _ = account_sender.send(positions_message);
+                    } else if message == orders_request {
+                        let orders_message = json!(["orders", account.lock().unwrap().orders]).to_string();
+                        let _ = account_sender.send(orders_message);
+                    } else if sender_to_connector.send(message).is_err() {
+                        break;
+                    }
+                }
+            });
+            // The live account portfolio and orders must not leak into the simulated ones
+            tokio::spawn(async move {
+                loop {
+                    let message = tokio::select! {
+                        Some(message) = account_receiver.recv() => message,
+                        Some(message) = receiver_from_connector.recv() => {
+                            if is_account_message(&message) {
+                                continue;
+                            }
+                            message
+                        }
+                        else => break,
+                    };
+                    if sender.send(message).await.is_err() {
+                        break;
+                    }
+                }
+            });
+            self.market_data.connect(connector_receiver, sender_from_connector, status).await
+        })
+    }
+    fn send_order(&self, ticker: String, action: ActionType, order: OrderType, price: f64, qty: u64, _expiration: Expirations) -> BoxFuture<'_, BrokerResult<OrderAck>> {
+        Box::pin(async move {
+            let (bid, ask) = self.best_prices(&ticker);
+            let fill_price = match action {
+                ActionType::Buy => ask,
+                ActionType::Sell => bid,
+            };
+            let Some(fill_price) = fill_price else {
+                return Err(Error::BrokerRejection {
+                    code: None,
+                    message: format!("paper order rejected: no {} price for {}", if action == ActionType::Buy { "ask" } else { "bid" }, ticker),
+                });
+            };
+            // Limit orders are filled only when marketable
+            if order == OrderType::Limit && price != 0.0 {
+                let marketable = match action {
+                    ActionType::Buy => fill_price <= price,
+                    ActionType::Sell => fill_price >= price,
+                };
+                if !marketable {
+                    return Err(Error::BrokerRejection {
+                        code: None,
+                        message: format!("paper order rejected: limit {:.2} for {} is not marketable", price, ticker),
+                    });
+                }
+            }
+            let order_id = self.fill(&ticker, &action, &order, fill_price, qty as i32);
+            Ok(OrderAck::new(json!({ "order_id": order_id, "price": fill_price })))
+        })
+    }
+    fn cancel_order(&self, order_id: i64) -> BoxFuture<'_, BrokerResult<OrderAck>> {
+        Box::pin(async move {
+            Err(Error::BrokerRejection {
+                code: None,
+                message: format!("paper order {} not found: paper orders are filled immediately", order_id),
+            })
+        })
+    }
+    fn portfolio(&self) -> BoxFuture<'_, BrokerResult<serde_json::Value>> {
+        Box::pin(async move {
+            let message = portfolio_message(&self.account.lock().unwrap().positions);
+            Ok(serde_json::from_str(&message)?)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paper_broker() -> (PaperBroker, mpsc::Receiver<String>) {
+        let credentials = Credentials {
+            id: "paper".to_string(),
+            login: String::new(),
+            password: String::new(),
+            public_key: String::new(),
+            secret_key: String::new(),
+            paper_trading: true,
+            endpoints: Endpoints::default(),
+        };
+        let (data_sender, data_receiver) = mpsc::channel(100);
+        let broker = PaperBroker::new(credentials, Arc::new(RwLock::new(Vec::new())), Arc::new(RwLock::new(Vec::new())), data_sender);
+        (broker, data_receiver)
+    }
+
+    fn position(broker: &PaperBroker, ticker: &str) -> Option<(i32, f64)> {
+        broker.account.lock().unwrap().positions.iter()
+            .find(|position| position.ticker == ticker)
+            .map(|position| (position.quantity, position.open_price))
+    }
+
+    #[test]
+    fn fills_average_the_open_price_of_an_increased_position() {
+        let (broker, mut data_receiver) = paper_broker();
+        broker.fill("SPY.US", &ActionType::Buy, &OrderType::Market, 100.0, 10);
+        broker.fill("SPY.US", &ActionType::Buy, &OrderType::Market, 110.0, 30);
+        assert_eq!(position(&broker, "SPY.US"), Some((40, 107.5)));
+        // A decrease keeps the open price
+        broker.fill("SPY.US", &ActionType::Sell, &OrderType::Market, 120.0, 15);
+        assert_eq!(position(&broker, "SPY.US"), Some((25, 107.5)));
+        assert_eq!(data_receiver.try_recv().unwrap(), "paper Paper order filled: Buy 10 SPY.US @ 100.00");
+    }
+
+    #[test]
+    fn flipped_position_is_opened_at_the_fill() {
+        let (broker, _data_receiver) = paper_broker();
+        broker.fill("SPY.US", &ActionType::Buy, &OrderType::Market, 100.0, 5);
+        broker.fill("SPY.US", &ActionType::Sell, &OrderType::Market, 90.0, 8);
+        assert_eq!(position(&broker, "SPY.US"), Some((-3, 90.0)));
+        broker.fill("SPY.US", &ActionType::Sell, &OrderType::Market, 80.0, 3);
+        assert_eq!(position(&broker, "SPY.US"), Some((-6, 85.0)));
+    }
+
+    #[test]
+    fn closed_position_is_forgotten() {
+        let (broker, _data_receiver) = paper_broker();
+        broker.fill("SPY.US", &ActionType::Buy, &OrderType::Market, 100.0, 5);
+        broker.fill("SPY.US", &ActionType::Sell, &OrderType::Market, 95.0, 5);
+        assert_eq!(position(&broker, "SPY.US"), None);
+        broker.fill("SPY.US", &ActionType::Sell, &OrderType::Market, 96.0, 2);
+        assert_eq!(position(&broker, "SPY.US"), Some((-2, 96.0)));
+        assert_eq!(broker.account.lock().unwrap().orders.len(), 3);
+    }
+}
@@ -1,12 +1,23 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use crate::market_data::{OrderBookMessage, OrderMessage};
+use crate::api_utils::ActionType;
+use crate::config::DistanceMode;
 use crate::trading_utils;
-use trading_utils::{SLType, SLStrategy};
+use trading_utils::{SLType, SLStrategy, UnderlyingStop};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Side {
     Buy,
     Sell,
 }
+impl Side {
+    pub fn action(&self) -> ActionType {
+        match self {
+            Side::Buy => ActionType::Buy,
+            Side::Sell => ActionType::Sell,
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct OrderBookRow {
@@ -17,12 +28,162 @@ pub struct OrderBookRow {
     pub message_number: i32,
 }
 
+// Price levels are keyed by the price in 1/10000, so equal prices always hit the same level
+const PRICE_SCALE: f64 = 10_000.0;
+fn price_key(price: f64) -> i64 {
+    (price * PRICE_SCALE).round() as i64
+}
+
+// Number (n) of the first depth message of a subscription
+const SNAPSHOT_NUMBER: i32 = 1;
+
+// Continuity of the message numbers (n) of a ticker's depth
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum DepthState {
+    AwaitingSnapshot, // Waits for the first message of a subscription as the new baseline
+    Live(i32), // Number of the last applied message
+    Resubscribed(i32), // Live, the first message of the new subscription is the new baseline
+    Gap, // Messages are ignored until the ticker is resubscribed
+}
+
+// Result of applying an order book message
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DepthUpdate {
+    Applied,
+    Ignored, // The book waits for a new snapshot
+    Gap, // A message was missed or the depth doesn't match cnt. The ticker has to be resubscribed
+}
+
+// Market depth of one ticker
+#[derive(Clone)]
 pub struct OrderBookBlock {
-    ticker: String,
-    pub buy_rows: Vec<OrderBookRow>,
-    pub sell_rows: Vec<OrderBookRow>,
+    pub ticker: String,
+    buy_levels: BTreeMap<i64, OrderBookRow>,
+    sell_levels: BTreeMap<i64, OrderBookRow>,
+    state: DepthState,
+}
+impl OrderBookBlock {
+    pub fn new(ticker: &str) -> Self {
+        OrderBookBlock {
+            ticker: ticker.to_string(),
+            buy_levels: BTreeMap::new(),
+            sell_levels: BTreeMap::new(),
+            state: DepthState::AwaitingSnapshot,
+        }
+    }
+    // Not consistent with the server until a snapshot is applied
+    pub fn is_stale(&self) -> bool {
+        !matches!(self.state, DepthState::Live(_) | DepthState::Resubscribed(_))
+    }
+    // Drop the levels, the book is stale until the ticker's depth is sent again after a subscription
+    pub fn expect_snapshot(&mut self) {
+        self.buy_levels.clear();
+        self.sell_levels.clear();
+        self.state = DepthState::AwaitingSnapshot;
+    }
+    // The ticker is subscribed again while the book is consistent. The levels stay in use
+    pub fn resubscribed(&mut self) {
+        if let DepthState::Live(last) = self.state {
+            self.state = DepthState::Resubscribed(last);
+        }
+    }
+    // The whole depth as the server sends it after a subscription: numbering starts again from 1,
+    // inserts only, up to cnt levels a side. An insert delta of a running subscription has a later number.
+    // A side may be empty, illiquid options and pre-market books often have no bids or no asks
+    fn is_snapshot(message: &OrderBookMessage) -> bool {
+        let buys = message.ins.iter().filter(|entry| entry.s == "B").count();
+        let sells = message.ins.iter().filter(|entry| entry.s == "S").count();
+        let depth = message.cnt.max(0) as usize;
+        message.n == SNAPSHOT_NUMBER && message.del.is_empty() && message.upd.is_empty() && buys <= depth && sells <= depth
+    }
+    fn apply(&mut self, message: &OrderBookMessage) -> DepthUpdate {
+        let mut resubscribed = false;
+        match self.state {
+            DepthState::Gap => return DepthUpdate::Ignored,
+            DepthState::AwaitingSnapshot if !Self::is_snapshot(message) => return DepthUpdate::Ignored,
+            DepthState::Resubscribed(last) if message.n != last + 1 && Self::is_snapshot(message) => {
+                self.buy_levels.clear();
+                self.sell_levels.clear();
+            }
+            DepthState::Live(last) | DepthState::Resubscribed(last) if message.n != last + 1 => return self.gap(),
+            DepthState::Resubscribed(_) => resubscribed = true,
+            _ => {}
+        }
+        for del_entry in &message.del {
+            self.remove_level(del_entry.p);
+        }
+        let inserts = message.ins.iter().map(|entry| (entry.p, entry.s.as_str(), entry.q, entry.k));
+        let updates = message.upd.iter().map(|entry| (entry.p, entry.s.as_str(), entry.q, entry.k));
+        for (price, side, quantity, position) in inserts.chain(updates) {
+            let side = match side {
+                "B" => Side::Buy,
+                "S" => Side::Sell,
+                _ => continue,
+            };
+            self.set_level(OrderBookRow { price, side, quantity, position, message_number: message.n });
+        }
+        // A side deeper than the depth of the subscription means missed deletes
+        if self.buy_levels.len() > message.cnt as usize || self.sell_levels.len() > message.cnt as usize {
+            return self.gap();
+        }
+        self.state = if resubscribed { DepthState::Resubscribed(message.n) } else { DepthState::Live(message.n) };
+        DepthUpdate::Applied
+    }
+    fn gap(&mut self) -> DepthUpdate {
+        self.buy_levels.clear();
+        self.sell_levels.clear();
+        self.state = DepthState::Gap;
+        DepthUpdate::Gap
+    }
+    // Insert or replace the level of the row's side. A price level belongs to one side only
+    pub fn set_level(&mut self, row: OrderBookRow) {
+        let key = price_key(row.price);
+        match row.side {
+            Side::Buy => {
+                self.sell_levels.remove(&key);
+                self.buy_levels.insert(key, row);
+            }
+            Side::Sell => {
+                self.buy_levels.remove(&key);
+                self.sell_levels.insert(key, row);
+            }
+        }
+    }
+    // Deletes carry no side, the level is removed from the side that has it
+    pub fn remove_level(&mut self, price: f64) {
+        let key = price_key(price);
+        self.buy_levels.remove(&key);
+        self.sell_levels.remove(&key);
+    }
+    // Levels of the side from the best price
+    pub fn rows(&self, side: &Side) -> Box<dyn Iterator<Item = &OrderBookRow> + '_> {
+        match side {
+            Side::Buy => Box::new(self.buy_levels.values().rev()),
+            Side::Sell => Box::new(self.sell_levels.values()),
+        }
+    }
+    pub fn best_bid(&self) -> Option<&OrderBookRow> {
+        self.buy_levels.values().next_back()
+    }
+    pub fn best_ask(&self) -> Option<&OrderBookRow> {
+        self.sell_levels.values().next()
+    }
+    pub fn spread(&self) -> Option<f64> {
+        Some(self.best_ask()?.price - self.best_bid()?.price)
+    }
+    // Best n levels of the side
+    pub fn depth(&self, side: &Side, n: usize) -> Vec<&OrderBookRow> {
+        self.rows(side).take(n).collect()
+    }
+    pub fn total_size(&self, side: &Side) -> i64 {
+        self.rows(side).map(|row| row.quantity as i64).sum()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.buy_levels.is_empty() && self.sell_levels.is_empty()
+    }
 }
 
+#[derive(Clone)]
 pub struct OrderBook {
     pub id: String,
     pub order_book: HashMap<String, OrderBookBlock>,
@@ -34,46 +195,52 @@ impl OrderBook {
             order_book: HashMap::new(),
         }
     }
-    pub fn add_row(&mut self, ticker: &str, row: OrderBookRow) {
-        let order_book_block = self.order_book.entry(ticker.to_string())
-            .or_insert_with(|| OrderBookBlock {
-                ticker: ticker.to_string(),
-                buy_rows: Vec::new(),
-                sell_rows: Vec::new(),
-            });
-        match row.side {
-            Side::Buy => order_book_block.buy_rows.push(row),
-            Side::Sell => order_book_block.sell_rows.push(row),
-        }
+    // Apply deletes, inserts and updates of the message to the depth of its ticker
+    pub fn apply(&mut self, message: &OrderBookMessage) -> DepthUpdate {
+        self.order_book.entry(message.i.clone())
+            .or_insert_with(|| OrderBookBlock::new(&message.i))
+            .apply(message)
     }
-    pub fn remove_row(&mut self, price: f64) {
-        self.order_book.values_mut().for_each(|block| {
-            block.buy_rows.retain(|row| row.price != price);
-            block.sell_rows.retain(|row| row.price != price);
-        });
+    // The depth of the ticker is inconsistent, its book waits for a snapshot
+    pub fn expect_snapshot(&mut self, ticker: &str) {
+        if let Some(block) = self.order_book.get_mut(ticker) {
+            block.expect_snapshot();
+        }
     }
-    pub fn update_row(&mut self, price: f64, quantity: i32, message_number: i32) {
-        for block in self.order_book.values_mut() {
-            for row in block.buy_rows.iter_mut().chain(block.sell_rows.iter_mut()) {
-                if row.price == price {
-                    row.quantity = quantity;
-                    row.message_number = message_number;
-                }
+    // Call when the tickers are (re)subscribed: the server may send their depth again
+    pub fn resubscribed(&mut self, tickers: &[String]) {
+        for ticker in tickers.iter() {
+            if let Some(block) = self.order_book.get_mut(ticker) {
+                block.resubscribed();
             }
         }
     }
-    fn get_orders_for_ticker(&self, ticker: &str) -> Option<&OrderBookBlock> {
+    pub fn block(&self, ticker: &str) -> Option<&OrderBookBlock> {
         self.order_book.get(ticker)
     }
+    pub fn best_bid(&self, ticker: &str) -> Option<f64> {
+        self.block(ticker)?.best_bid().map(|row| row.price)
+    }
+    pub fn best_ask(&self, ticker: &str) -> Option<f64> {
+        self.block(ticker)?.best_ask().map(|row| row.price)
+    }
+    pub fn spread(&self, ticker: &str) -> Option<f64> {
+        self.block(ticker)?.spread()
+    }
 }
 
+#[derive(Clone)]
 pub struct QuoteData {
     pub ticker: Option<String>,
     pub ask_price: Option<f64>,
     pub bid_price: Option<f64>,
     pub last_trade: Option<f64>,
     pub last_trade_time: Option<String>,
+    pub last_trade_at: Option<chrono::DateTime<chrono::Local>>, // Arrival time of the message with the last trade
+    pub min_step: Option<f64>,
+    pub volatility: Option<f64>,
 }
+#[derive(Clone)]
 pub struct QuoteBook {
     pub id: String,
     pub quotes_list: Vec<QuoteData>,
@@ -99,10 +266,32 @@ impl QuoteBook {
             if let Some(last_trade_time) = &quote_data.last_trade_time {
                 existing_quote.last_trade_time = Some(last_trade_time.clone());
             }
+            if quote_data.last_trade_at.is_some() {
+                existing_quote.last_trade_at = quote_data.last_trade_at;
+            }
+            if quote_data.min_step.is_some() {
+                existing_quote.min_step = quote_data.min_step;
+            }
+            if quote_data.volatility.is_some() {
+                existing_quote.volatility = quote_data.volatility;
+            }
         } else {
             self.quotes_list.push(quote_data);
         }        
     }
+    pub fn quote(&self, ticker: &str) -> Option<&QuoteData> {
+        self.quotes_list.iter().find(|quote| quote.ticker.as_deref() == Some(ticker))
+    }
+}
+
+// Change of the stop of a position, kept in its audit trail
+#[derive(Debug, Clone, PartialEq)]
+pub struct StopChange {
+    pub time: chrono::DateTime<chrono::Local>,
+    pub sl_type: SLType,
+    pub from: f64,
+    pub to: f64,
+    pub reason: String, // "manual", "strategy insurance" etc.
 }
 
 #[derive(Debug, Clone)]
@@ -118,17 +307,434 @@ pub struct Position {
     pub sl_price: f64,
     pub close_alert: bool,
     pub closing: bool,
+    pub close_order: Option<i64>, // Order closing the position, once acknowledged by the broker
+    pub stop_changes: Vec<StopChange>, // Changes made by the user, oldest first
+    pub opened_at: chrono::DateTime<chrono::Local>, // Arrival time of the message that first reported the position
+    pub distance_mode: DistanceMode, // Of the stop parameters of the last check
+    pub underlying_stop: Option<UnderlyingStop>,
+    pub underlying_price: Option<f64>, // Last trade of the underlying at the last check of the underlying stop
 }
-#[derive(Debug)]
+impl Position {
+    pub fn new(position_id: i64, ticker: &str, quantity: i32, open_price: f64) -> Self {
+        Position {
+            position_id,
+            ticker: ticker.to_string(),
+            quantity,
+            open_price,
+            current_price: 0.0,
+            pnl: 0.0,
+            sl_strategy: SLStrategy::InsuranceStops,
+            sl_type: SLType::None,
+            sl_price: 0.0,
+            close_alert: false,
+            closing: false,
+            close_order: None,
+            stop_changes: Vec::new(),
+            opened_at: chrono::Local::now(),
+            distance_mode: DistanceMode::Points,
+            underlying_stop: None,
+            underlying_price: None,
+        }
+    }
+    // Set the stop and record the change in the audit trail
+    pub fn change_stop(&mut self, sl_type: SLType, sl_price: f64, reason: &str) {
+        self.record_stop_change(sl_type, self.sl_price, sl_price, reason);
+        self.sl_type = sl_type;
+        self.sl_price = sl_price;
+    }
+    pub fn record_stop_change(&mut self, sl_type: SLType, from: f64, to: f64, reason: &str) {
+        self.stop_changes.push(StopChange {
+            time: chrono::Local::now(),
+            sl_type,
+            from,
+            to,
+            reason: reason.to_string(),
+        });
+    }
+    // Check the underlying stop against the last trade of the underlying
+    pub fn check_underlying_stop(&mut self, underlying_price: Option<f64>) {
+        let Some(underlying_stop) = &self.underlying_stop else { return };
+        self.underlying_price = underlying_price;
+        if underlying_price.is_some_and(|price| underlying_stop.triggered(price)) && !self.close_alert {
+            self.sl_type = SLType::Underlying;
+            self.close_alert = true;
+        }
+    }
+    // Leave the closing state when the close order is rejected or cancelled, so it can be sent again
+    pub fn check_close_order(&mut self, order_store: Option<&OrderStore>) {
+        let Some(order_id) = self.close_order else { return };
+        let status = order_store.and_then(|order_store| order_store.get(order_id)).map(|order| order.status);
+        if matches!(status, Some(OrderStatus::Rejected) | Some(OrderStatus::Cancelled)) {
+            self.closing = false;
+            self.close_order = None;
+        }
+    }
+    // Update of the current price only. Position id 0 marks price updates
+    pub fn price_update(ticker: &str, current_price: f64) -> Self {
+        Position {
+            current_price,
+            ..Position::new(0, ticker, 0, 0.0)
+        }
+    }
+}
+#[derive(Debug, Clone)]
 pub struct Portfolio {
     pub id: String,
     pub portfolio: Vec<Position>,
+    pub updated_at: chrono::DateTime<chrono::Local>, // Arrival time of the last message applied
 }
 impl Portfolio {
     pub fn new(id: &str) -> Self {
         Portfolio {
             id: id.to_string(),
             portfolio: Vec::new(),
+            updated_at: chrono::Local::now(),
         }
     }
-}
\ No newline at end of file
+}
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum OrderStatus {
+    Pending,
+    Accepted,
+    PartiallyFilled,
+    Filled,
+    Rejected,
+    Cancelled,
+}
+impl OrderStatus {
+    pub fn description(&self) -> &str {
+        match self {
+            OrderStatus::Pending => "pending",
+            OrderStatus::Accepted => "accepted",
+            OrderStatus::PartiallyFilled => "partially filled",
+            OrderStatus::Filled => "filled",
+            OrderStatus::Rejected => "rejected",
+            OrderStatus::Cancelled => "cancelled",
+        }
+    }
+    // Tradernet order status codes
+    pub fn from_ff_code(code: i32) -> Self {
+        match code {
+            1 => OrderStatus::Pending, // Received
+            2 => OrderStatus::PartiallyFilled,
+            21 => OrderStatus::Filled,
+            30 | 31 => OrderStatus::Cancelled, // Expired or cancelled
+            70..=79 => OrderStatus::Rejected,
+            _ => OrderStatus::Accepted, // Awaiting execution
+        }
+    }
+    pub fn is_final(&self) -> bool {
+        matches!(self, OrderStatus::Filled | OrderStatus::Rejected | OrderStatus::Cancelled)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OrderRecord {
+    pub order_id: i64, // Broker order id. Negative for orders rejected before reaching the broker
+    pub ticker: String,
+    pub side: Side,
+    pub quantity: f64,
+    pub filled_quantity: f64,
+    pub price: f64, // Limit price, 0.0 for market orders
+    pub fill_price: Option<f64>, // Average price of the trades
+    pub fill_time: Option<String>, // Time of the last trade
+    pub status: OrderStatus,
+    pub status_time: String,
+    pub message: String, // Rejection reason
+}
+impl OrderRecord {
+    pub fn from_message(order_message: &OrderMessage) -> Self {
+        let quantity = order_message.q.unwrap_or(0.0);
+        let traded_quantity: f64 = order_message.trade.iter().map(|trade| trade.q).sum();
+        let filled_quantity = match order_message.leaves_qty {
+            Some(leaves_qty) if traded_quantity == 0.0 => quantity - leaves_qty,
+            _ => traded_quantity,
+        };
+        let fill_price = if traded_quantity > 0.0 {
+            Some(order_message.trade.iter().map(|trade| trade.p * trade.q).sum::<f64>() / traded_quantity)
+        } else {
+            None
+        };
+        OrderRecord {
+            order_id: order_message.id,
+            ticker: order_message.instr.clone().unwrap_or_default(),
+            side: match order_message.oper {
+                Some(3) | Some(4) => Side::Sell,
+                _ => Side::Buy,
+            },
+            quantity,
+            filled_quantity,
+            price: order_message.p.unwrap_or(0.0),
+            fill_price,
+            fill_time: order_message.trade.last().and_then(|trade| trade.date.clone()),
+            status: order_message.stat.map(OrderStatus::from_ff_code).unwrap_or(OrderStatus::Pending),
+            status_time: order_message.stat_d.clone().or_else(|| order_message.date.clone()).unwrap_or_default(),
+            message: String::new(),
+        }
+    }
+    // Working order that can still be cancelled
+    pub fn is_working(&self) -> bool {
+        !self.status.is_final() && self.order_id > 0
+    }
+    // Working limit order that can be repriced
+    pub fn is_modifiable(&self) -> bool {
+        self.is_working() && self.price != 0.0
+    }
+}
+#[derive(Clone)]
+pub struct OrderStore {
+    pub id: String,
+    pub orders: Vec<OrderRecord>,
+    next_local_id: i64,
+}
+impl OrderStore {
+    pub fn new(id: &str) -> Self {
+        OrderStore {
+            id: id.to_string(),
+            orders: Vec::new(),
+            next_local_id: -1,
+        }
+    }
+    // Insert a new order or update the known one from the orders stream
+    pub fn update(&mut self, order: OrderRecord) {
+        if let Some(existing_order) = self.orders.iter_mut().find(|existing_order| existing_order.order_id == order.order_id) {
+            // Final status is never rolled back by a delayed update
+            if existing_order.status.is_final() && !order.status.is_final() {
+                return;
+            }
+            *existing_order = order;
+        } else {
+            self.orders.push(order);
+        }
+    }
+    // Order acknowledged by the broker. The stream may have reported it already
+    pub fn add_sent(&mut self, order: OrderRecord) {
+        if self.get(order.order_id).is_none() {
+            self.orders.push(order);
+        }
+    }
+    // Order rejected before the broker assigned an id
+    pub fn add_rejected(&mut self, ticker: &str, side: Side, quantity: f64, price: f64, message: String) {
+        let order_id = self.next_local_id;
+        self.next_local_id -= 1;
+        self.orders.push(OrderRecord {
+            order_id,
+            ticker: ticker.to_string(),
+            side,
+            quantity,
+            filled_quantity: 0.0,
+            price,
+            fill_price: None,
+            fill_time: None,
+            status: OrderStatus::Rejected,
+            status_time: chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+            message,
+        });
+    }
+    pub fn get(&self, order_id: i64) -> Option<&OrderRecord> {
+        self.orders.iter().find(|order| order.order_id == order_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::market_data::{deserialize_message, MarketData};
+    use crate::recorder::load_messages_log;
+
+    // Depth updates of SPY.US and one of its options in a log of MessagesToFileSubscriber: the first message
+    // of a ticker inserts the whole depth, then levels are updated and deleted by price
+    fn recorded_messages() -> Vec<String> {
+        let messages = load_messages_log(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/order_book.log")).unwrap();
+        messages.into_iter()
+            .map(|message| message.data)
+            .filter(|data| matches!(deserialize_message(data), Some(MarketData::OrderBookMessage(_))))
+            .collect()
+    }
+
+    fn order_book_message(message: &str) -> OrderBookMessage {
+        let Some(MarketData::OrderBookMessage(order_book_message)) = deserialize_message(message) else {
+            panic!("Not an order book message: {}", message);
+        };
+        order_book_message
+    }
+
+    fn order_book(messages: &[String]) -> OrderBook {
+        let mut order_book = OrderBook::new("test");
+        for message in messages {
+            assert_eq!(order_book.apply(&order_book_message(message)), DepthUpdate::Applied);
+        }
+        order_book
+    }
+
+    fn prices(rows: Vec<&OrderBookRow>) -> Vec<f64> {
+        rows.iter().map(|row| row.price).collect()
+    }
+
+    #[test]
+    fn snapshot_is_sorted_from_the_best_price() {
+        let messages = recorded_messages();
+        let order_book = order_book(&messages[..1]);
+        let block = order_book.block("SPY.US").unwrap();
+        assert_eq!(prices(block.depth(&Side::Buy, 10)), vec![582.0, 581.98, 581.95]);
+        assert_eq!(prices(block.depth(&Side::Sell, 2)), vec![582.02, 582.05]);
+        assert_eq!(order_book.best_bid("SPY.US"), Some(582.0));
+        assert_eq!(order_book.best_ask("SPY.US"), Some(582.02));
+        assert!((order_book.spread("SPY.US").unwrap() - 0.02).abs() < 1e-9);
+        assert_eq!(block.total_size(&Side::Buy), 750);
+        assert_eq!(block.total_size(&Side::Sell), 920);
+    }
+
+    #[test]
+    fn delete_touches_only_its_ticker() {
+        let messages = recorded_messages();
+        let order_book = order_book(&messages[..3]);
+        // 582.00 is deleted from SPY.US, the option book is not touched
+        assert_eq!(order_book.best_ask("+SPY.18OCT2024.P580"), Some(1.31));
+        assert_eq!(order_book.best_bid("+SPY.18OCT2024.P580"), Some(1.29));
+        assert_eq!(order_book.best_bid("SPY.US"), Some(581.99));
+        assert_eq!(prices(order_book.block("SPY.US").unwrap().depth(&Side::Buy, 10)), vec![581.99, 581.98, 581.95]);
+    }
+
+    #[test]
+    fn updates_change_the_level_of_their_ticker() {
+        let messages = recorded_messages();
+        let order_book = order_book(&messages[..4]);
+        let spy = order_book.block("SPY.US").unwrap();
+        let best_ask = spy.best_ask().unwrap();
+        assert_eq!((best_ask.price, best_ask.quantity, best_ask.message_number), (582.02, 250, 2));
+        let option = order_book.block("+SPY.18OCT2024.P580").unwrap();
+        assert_eq!(option.depth(&Side::Buy, 2).iter().map(|row| row.quantity).collect::<Vec<_>>(), vec![10, 30]);
+        assert_eq!(option.total_size(&Side::Buy), 40);
+
+        // The best ask is deleted, the next level becomes the best
+        let all_applied = self::order_book(&messages);
+        assert_eq!(all_applied.best_ask("SPY.US"), Some(582.05));
+        assert!((all_applied.spread("SPY.US").unwrap() - 0.06).abs() < 1e-9);
+    }
+
+    #[test]
+    fn level_moves_between_sides() {
+        let mut block = OrderBookBlock::new("SPY.US");
+        block.set_level(OrderBookRow { price: 582.0, side: Side::Buy, quantity: 1, position: 1, message_number: 1 });
+        block.set_level(OrderBookRow { price: 582.0, side: Side::Sell, quantity: 2, position: 1, message_number: 2 });
+        assert!(block.best_bid().is_none());
+        assert_eq!(block.best_ask().map(|row| row.quantity), Some(2));
+        block.remove_level(582.0);
+        assert!(block.is_empty() && block.spread().is_none());
+    }
+
+    #[test]
+    fn missed_message_waits_for_a_snapshot() {
+        let messages = recorded_messages();
+        let mut order_book = order_book(&messages[..2]);
+        // SPY.US message 2 is lost
+        assert_eq!(order_book.apply(&order_book_message(&messages[4])), DepthUpdate::Gap);
+        assert!(order_book.block("SPY.US").unwrap().is_stale());
+        assert_eq!(order_book.best_bid("SPY.US"), None);
+        assert!(!order_book.block("+SPY.18OCT2024.P580").unwrap().is_stale());
+        // Deltas are ignored until the ticker is resubscribed
+        assert_eq!(order_book.apply(&order_book_message(&messages[2])), DepthUpdate::Ignored);
+
+        // The resubscription sends the depth again, numbered from 1
+        order_book.expect_snapshot("SPY.US");
+        assert_eq!(order_book.apply(&order_book_message(&messages[0])), DepthUpdate::Applied);
+        assert!(!order_book.block("SPY.US").unwrap().is_stale());
+        assert_eq!(order_book.best_bid("SPY.US"), Some(582.0));
+        assert_eq!(order_book.apply(&order_book_message(&messages[2])), DepthUpdate::Applied);
+        assert_eq!(order_book.best_bid("SPY.US"), Some(581.99));
+    }
+
+    #[test]
+    fn one_sided_first_book_goes_live() {
+        // No bids on a deep out of the money option
+        let no_bids = r#"["b",{"n":1,"i":"+SPY.18OCT2024.P400","del":[],"ins":[{"p":0.03,"s":"S","q":50,"k":1}],"upd":[],"cnt":3,"x":0}]"#;
+        let mut order_book = OrderBook::new("test");
+        assert_eq!(order_book.apply(&order_book_message(no_bids)), DepthUpdate::Applied);
+        let block = order_book.block("+SPY.18OCT2024.P400").unwrap();
+        assert!(!block.is_stale());
+        assert_eq!((order_book.best_bid("+SPY.18OCT2024.P400"), order_book.best_ask("+SPY.18OCT2024.P400")), (None, Some(0.03)));
+        // Later deltas apply, the bid side fills in
+        let bid = r#"["b",{"n":2,"i":"+SPY.18OCT2024.P400","del":[],"ins":[{"p":0.01,"s":"B","q":10,"k":1}],"upd":[],"cnt":3,"x":0}]"#;
+        assert_eq!(order_book.apply(&order_book_message(bid)), DepthUpdate::Applied);
+        assert_eq!(order_book.best_bid("+SPY.18OCT2024.P400"), Some(0.01));
+
+        // A delta is still not a baseline after a gap
+        order_book.expect_snapshot("+SPY.18OCT2024.P400");
+        let delta = r#"["b",{"n":5,"i":"+SPY.18OCT2024.P400","del":[{"p":0.03,"k":1}],"ins":[],"upd":[],"cnt":3,"x":0}]"#;
+        assert_eq!(order_book.apply(&order_book_message(delta)), DepthUpdate::Ignored);
+        assert!(order_book.block("+SPY.18OCT2024.P400").unwrap().is_stale());
+    }
+
+    #[test]
+    fn insert_delta_is_not_a_snapshot() {
+        let messages = recorded_messages();
+        let mut order_book = order_book(&messages[..1]);
+        order_book.expect_snapshot("SPY.US");
+        // Inserts only and within the depth, but numbered on from the old subscription
+        let insert_delta = r#"["b",{"n":4,"i":"SPY.US","del":[],"ins":[{"p":581.97,"s":"B","q":100,"k":3}],"upd":[],"cnt":3,"x":0}]"#;
+        assert_eq!(order_book.apply(&order_book_message(insert_delta)), DepthUpdate::Ignored);
+        assert!(order_book.block("SPY.US").unwrap().is_stale());
+        assert_eq!(order_book.best_bid("SPY.US"), None);
+        assert_eq!(order_book.apply(&order_book_message(&messages[0])), DepthUpdate::Applied);
+        assert!(!order_book.block("SPY.US").unwrap().is_stale());
+    }
+
+    #[test]
+    fn resubscribed_book_keeps_its_levels() {
+        let messages = recorded_messages();
+        let mut order_book = order_book(&messages[..2]);
+        order_book.resubscribed(&["SPY.US".to_string(), "+SPY.18OCT2024.P580".to_string()]);
+        assert!(!order_book.block("SPY.US").unwrap().is_stale());
+        // Deltas in sequence still apply
+        assert_eq!(order_book.apply(&order_book_message(&messages[2])), DepthUpdate::Applied);
+        assert_eq!(order_book.best_bid("SPY.US"), Some(581.99));
+        // The depth sent again replaces the levels
+        assert_eq!(order_book.apply(&order_book_message(&messages[0])), DepthUpdate::Applied);
+        assert_eq!(order_book.best_bid("SPY.US"), Some(582.0));
+        // From there the numbers have to follow again
+        assert_eq!(order_book.apply(&order_book_message(&messages[4])), DepthUpdate::Gap);
+        // A delta out of sequence is still a gap
+        assert_eq!(order_book.apply(&order_book_message(&messages[3].replace(r#""n":2"#, r#""n":4"#))), DepthUpdate::Gap);
+    }
+
+    #[test]
+    fn depth_deeper_than_cnt_is_a_gap() {
+        let messages = recorded_messages();
+        let mut order_book = order_book(&messages[..1]);
+        // The delete of 582.00 is lost, an insert makes four bid levels of a depth 3 book
+        let message = r#"["b",{"n":2,"i":"SPY.US","del":[],"ins":[{"p":581.99,"s":"B","q":100,"k":1}],"upd":[],"cnt":3,"x":0}]"#;
+        assert_eq!(order_book.apply(&order_book_message(message)), DepthUpdate::Gap);
+        assert!(order_book.block("SPY.US").unwrap().is_stale());
+    }
+
+    #[test]
+    fn underlying_stop_closes_on_the_side_that_hurts_the_option() {
+        let mut put = Position::new(1, "+QQQ.18OCT2024.P480", 1, 2.0);
+        put.underlying_stop = Some(UnderlyingStop::new(&put.ticker, put.quantity, 482.5));
+        assert_eq!(put.underlying_stop.as_ref().map(|stop| (stop.ticker.as_str(), stop.trigger)), Some(("QQQ.US", trading_utils::Trigger::Above)));
+        put.check_underlying_stop(None);
+        put.check_underlying_stop(Some(482.5));
+        assert!(!put.close_alert);
+        assert_eq!(put.underlying_stop.as_ref().unwrap().distance(480.0), 2.5);
+        put.check_underlying_stop(Some(482.51));
+        assert!(put.close_alert);
+        assert_eq!(put.sl_type, SLType::Underlying);
+
+        let mut call = Position::new(2, "+SPY.18OCT2024.C580", 1, 2.0);
+        call.underlying_stop = Some(UnderlyingStop::new(&call.ticker, call.quantity, 578.0));
+        call.check_underlying_stop(Some(579.0));
+        assert!(!call.close_alert);
+        call.check_underlying_stop(Some(577.9));
+        assert!(call.close_alert);
+
+        // Short options lose on the other side
+        let mut short_put = Position::new(3, "+QQQ.18OCT2024.P480", -1, 2.0);
+        short_put.underlying_stop = Some(UnderlyingStop::new(&short_put.ticker, short_put.quantity, 478.0));
+        short_put.check_underlying_stop(Some(482.0));
+        assert!(!short_put.close_alert);
+        short_put.check_underlying_stop(Some(477.9));
+        assert!(short_put.close_alert);
+        assert_eq!(UnderlyingStop::new("+SPY.18OCT2024.C580", -2, 582.0).trigger, trading_utils::Trigger::Above);
+    }
+}
@@ -0,0 +1,221 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Error, ErrorKind, Write};
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+use crate::observer::{MessagePublisher, MessageSubscriber, ServerMessage, ServerMessagesPublisher};
+
+// One line of a recording file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedMessage {
+    pub id: String, // Account id
+    pub ts: u64, // Monotonic time since the start of the recording, microseconds
+    pub data: String, // Raw message from server
+}
+
+// Record messages from server Subscriber. Writes JSON lines of RecordedMessage
+pub struct SessionRecorder {
+    file: File,
+    started: Instant,
+    last_ts: u64,
+}
+impl SessionRecorder {
+    pub fn new(file_path: &str) -> std::io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(file_path)?;
+        Ok(Self {
+            file,
+            started: Instant::now(),
+            last_ts: 0,
+        })
+    }
+}
+impl MessageSubscriber for SessionRecorder {
+    fn on_data(&mut self, id: &str, message: &ServerMessage) {
+        // Timestamps never go back, even if subscribers are notified out of order
+        let ts = u64::max(self.started.elapsed().as_micros() as u64, self.last_ts);
+        self.last_ts = ts;
+        let record = RecordedMessage {
+            id: id.to_string(),
+            ts,
+            data: message.data.clone(),
+        };
+        match serde_json::to_string(&record) {
+            Ok(line) => {
+                if let Err(e) = writeln!(self.file, "{}", line) {
+                    eprintln!("Failed to write recording: {}", e);
+                }
+            }
+            Err(e) => eprintln!("Failed to serialize recorded message: {}", e),
+        }
+    }
+}
+
+// Read a recording made by SessionRecorder
+pub fn load_recording(file_path: &str) -> std::io::Result<Vec<RecordedMessage>> {
+    let file = File::open(file_path)?;
+    let mut messages = Vec::new();
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let message: RecordedMessage = serde_json::from_str(&line)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("line {}: {}", number + 1, e)))?;
+        messages.push(message);
+    }
+    Ok(messages)
+}
+
+// Read a log written by MessagesToFileSubscriber ("id date time offset data" lines)
+pub fn load_messages_log(file_path: &str) -> std::io::Result<Vec<RecordedMessage>> {
+    let file = File::open(file_path)?;
+    let mut messages = Vec::new();
+    let mut first_timestamp = None;
+    let mut last_ts = 0;
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let invalid_line = || Error::new(ErrorKind::InvalidData, format!("line {}: unexpected format", number + 1));
+        let mut parts = line.splitn(5, ' ');
+        let id = parts.next().ok_or_else(invalid_line)?;
+        let date = parts.next().ok_or_else(invalid_line)?;
+        let time = parts.next().ok_or_else(invalid_line)?;
+        let offset = parts.next().ok_or_else(invalid_line)?;
+        let data = parts.next().ok_or_else(invalid_line)?;
+        let timestamp = chrono::DateTime::parse_from_str(&format!("{} {} {}", date, time, offset), "%Y-%m-%d %H:%M:%S%.f %:z")
+            .map_err(|_| invalid_line())?;
+        let first_timestamp = *first_timestamp.get_or_insert(timestamp);
+        let ts = u64::max((timestamp - first_timestamp).num_microseconds().unwrap_or(0).max(0) as u64, last_ts);
+        last_ts = ts;
+        messages.push(RecordedMessage {
+            id: id.to_string(),
+            ts,
+            data: data.to_string(),
+        });
+    }
+    Ok(messages)
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ReplaySpeed {
+    RealTime,
+    Multiplier(f64), // 2.0 replays twice as fast as recorded
+    Stepped, // Messages are published one by one with step(), run() does not pause
+}
+
+// Feeds a recording into ServerMessagesPublisher. The pipeline processes the messages in the recorded order
+pub struct ReplaySource {
+    messages: Vec<RecordedMessage>,
+    position: usize,
+    speed: ReplaySpeed,
+    started: chrono::DateTime<chrono::Local>,
+}
+impl ReplaySource {
+    pub fn new(messages: Vec<RecordedMessage>, speed: ReplaySpeed) -> Self {
+        Self {
+            messages,
+            position: 0,
+            speed,
+            started: chrono::Local::now(),
+        }
+    }
+    pub fn from_file(file_path: &str, speed: ReplaySpeed) -> std::io::Result<Self> {
+        Ok(Self::new(load_recording(file_path)?, speed))
+    }
+    pub fn is_finished(&self) -> bool {
+        self.position >= self.messages.len()
+    }
+    pub fn messages(&self) -> &[RecordedMessage] {
+        &self.messages
+    }
+    // Publish the next message. Returns false when the recording is over
+    pub fn step(&mut self, publisher: &mut ServerMessagesPublisher) -> bool {
+        let Some(message) = self.messages.get(self.position) else {
+            return false;
+        };
+        let timestamp = self.started + chrono::Duration::microseconds(message.ts as i64);
+        publisher.notify_subscribers(&message.id, &ServerMessage::new(timestamp, message.data.clone()));
+        self.position += 1;
+        true
+    }
+    // Publish the rest of the recording keeping the recorded pauses scaled by the speed
+    pub async fn run(&mut self, publisher: &mut ServerMessagesPublisher) {
+        let replay_started = Instant::now();
+        let first_ts = self.messages.get(self.position).map(|message| message.ts).unwrap_or(0);
+        while let Some(message) = self.messages.get(self.position) {
+            let scale = match self.speed {
+                ReplaySpeed::RealTime => Some(1.0),
+                ReplaySpeed::Multiplier(multiplier) if multiplier > 0.0 => Some(1.0 / multiplier),
+                _ => None,
+            };
+            if let Some(scale) = scale {
+                let due = Duration::from_micros(((message.ts - first_ts) as f64 * scale) as u64);
+                let elapsed = replay_started.elapsed();
+                if due > elapsed {
+                    tokio::time::sleep(due - elapsed).await;
+                }
+            }
+            self.step(publisher);
+            tokio::task::yield_now().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+    use super::*;
+    use crate::config::Config;
+    use crate::mock_server::order_book_messages;
+    use crate::session::TradingSession;
+    use crate::trading_utils::SLType;
+
+    fn session() -> TradingSession {
+        let (data_sender, _data_receiver) = mpsc::channel(100);
+        let (errors_sender, _errors_receiver) = mpsc::unbounded_channel();
+        TradingSession::new(Config::default(), data_sender, errors_sender)
+    }
+
+    // Stop, close alert and closing state of the positions
+    fn stops(session: &TradingSession) -> Vec<(String, SLType, f64, bool, bool)> {
+        session.portfolios.read().unwrap().iter()
+            .flat_map(|portfolio| portfolio.portfolio.iter())
+            .map(|position| (position.ticker.clone(), position.sl_type, position.sl_price, position.close_alert, position.closing))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn recording_replays_to_the_same_close_alert() {
+        let file_path = std::env::temp_dir().join(format!("traderapp-replay-{}.jsonl", std::process::id())).to_string_lossy().to_string();
+        let _ = std::fs::remove_file(&file_path);
+        let portfolio = r#"["portfolio",{"loaded":true,"m_id":"test","acc":[],"pos":[{"i":"SPY.US","q":10,"acc_pos_id":1,"price_a":500.0}]}]"#;
+        let messages = std::iter::once(portfolio.to_string())
+            .chain(order_book_messages("SPY.US", &[(500.0, 500.05), (499.85, 499.9)]))
+            .enumerate()
+            .map(|(number, data)| RecordedMessage { id: "test".to_string(), ts: number as u64 * 1000, data })
+            .collect();
+
+        // The first session records the messages it processes
+        let mut recorded = session();
+        recorded.subscribe(Box::new(SessionRecorder::new(&file_path).unwrap()));
+        recorded.replay(&mut ReplaySource::new(messages, ReplaySpeed::Stepped)).await;
+        let recorded_stops = stops(&recorded);
+        assert_eq!(recorded_stops.len(), 1);
+        assert_eq!((recorded_stops[0].1, recorded_stops[0].3), (SLType::LossLimiter, true));
+
+        let mut replayed = session();
+        let mut source = ReplaySource::from_file(&file_path, ReplaySpeed::Stepped).unwrap();
+        assert_eq!(source.messages().len(), 3);
+        replayed.replay(&mut source).await;
+        assert!(source.is_finished());
+        assert_eq!(stops(&replayed), recorded_stops);
+        // The alert closes nothing during a replay
+        assert!(!stops(&replayed)[0].4);
+        assert!(replayed.orders.read().unwrap().iter().all(|order_store| order_store.orders.is_empty()));
+        let _ = std::fs::remove_file(&file_path);
+    }
+}
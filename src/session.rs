@@ -0,0 +1,460 @@
+use std::sync::{Arc, RwLock};
+use arc_swap::ArcSwap;
+use std::sync::atomic::AtomicI64;
+use tokio::sync::{mpsc, watch};
+use crate::api::{Connection, ConnectionStatus};
+use crate::api_utils::{Endpoints, Request};
+use crate::bus::{EventBus, QueueStats, QUEUE_CAPACITY};
+use crate::candles::CandleBook;
+use crate::broker::{Broker, FreedomBroker};
+use crate::config::Config;
+use crate::crypto_utils::Credentials;
+use crate::observer::{request_order_books, CandleBuilder, DataProcessor, MarketDataPublisher, MessagePublisher, MessageSubscriber, PortfolioUpdater, QuotesRequester, ServerMessagesPublisher};
+use crate::orders::{OrderTracker, StopLossCloser};
+use crate::paper_broker::PaperBroker;
+use crate::processed_data::{OrderBook, OrderStore, Portfolio, Position, QuoteBook};
+use crate::recorder::ReplaySource;
+use crate::snapshot::{SnapshotPublisher, StateSnapshot, StateVersions};
+use crate::error::{Error, Result};
+use crate::trading_utils::{underlying_ticker, upgrade_sl, ManualStop, SLStrategy, SLType, StopContext, TickerOptions, UnderlyingStop};
+
+// Shared state of all accounts and the processing pipeline that fills it:
+// EventBus -> ServerMessagesPublisher -> MarketDataPublisher -> CandleBuilder, DataProcessor -> PortfolioUpdater (-> StopLossCloser), QuotesRequester
+pub struct TradingSession {
+    pub connections: Arc<RwLock<Vec<Connection>>>,
+    pub order_books: Arc<RwLock<Vec<OrderBook>>>,
+    pub quotes: Arc<RwLock<Vec<QuoteBook>>>,
+    pub candles: Arc<RwLock<Vec<CandleBook>>>,
+    pub portfolios: Arc<RwLock<Vec<Portfolio>>>,
+    pub orders: Arc<RwLock<Vec<OrderStore>>>,
+    pub tickers: Arc<RwLock<Vec<TickerOptions>>>,
+    pub days_to_expiration: Arc<AtomicI64>,
+    pub config: Arc<RwLock<Config>>,
+    pub order_tracker: OrderTracker,
+    pub versions: StateVersions, // Mark the collections changed outside of the pipeline
+
+    snapshot: Arc<ArcSwap<StateSnapshot>>,
+    on_snapshot: Option<Box<dyn Fn() + Send + Sync>>,
+    server_messages_publisher: ServerMessagesPublisher, // Until start, then owned by the bus
+    bus: Option<EventBus>,
+    data_sender: mpsc::Sender<String>,
+    errors_sender: mpsc::UnboundedSender<String>,
+}
+
+impl TradingSession {
+    // data_sender receives pipeline diagnostics, errors_sender failures of connections and orders
+    pub fn new(config: Config, data_sender: mpsc::Sender<String>, errors_sender: mpsc::UnboundedSender<String>) -> Self {
+        let tickers = config.tickers.iter().map(|ticker| TickerOptions::new(ticker.to_string())).collect();
+        let connections = Arc::new(RwLock::new(Vec::new()));
+        let order_books = Arc::new(RwLock::new(Vec::new()));
+        let quotes = Arc::new(RwLock::new(Vec::new()));
+        let portfolios = Arc::new(RwLock::new(Vec::new()));
+        let orders = Arc::new(RwLock::new(Vec::new()));
+        let tickers = Arc::new(RwLock::new(tickers));
+        let days_to_expiration = Arc::new(AtomicI64::new(config.ui.days_to_expiration));
+        let config = Arc::new(RwLock::new(config));
+        let versions = StateVersions::default();
+        Self {
+            connections: Arc::clone(&connections),
+            order_books: Arc::clone(&order_books),
+            quotes: Arc::clone(&quotes),
+            candles: Arc::new(RwLock::new(Vec::new())),
+            portfolios: Arc::clone(&portfolios),
+            orders: Arc::clone(&orders),
+            tickers: Arc::clone(&tickers),
+            days_to_expiration: Arc::clone(&days_to_expiration),
+            config: Arc::clone(&config),
+            order_tracker: OrderTracker::new(Arc::clone(&orders), Arc::clone(&portfolios), errors_sender.clone(), versions.clone()),
+            versions,
+            snapshot: Arc::new(ArcSwap::from_pointee(StateSnapshot::default())),
+            on_snapshot: None,
+            server_messages_publisher: ServerMessagesPublisher::new(),
+            bus: None,
+            data_sender,
+            errors_sender,
+        }
+    }
+
+    // Subscribe to raw server messages (recorder, logs). Call before start
+    pub fn subscribe(&mut self, subscriber: Box<dyn MessageSubscriber>) {
+        self.server_messages_publisher.subscribe(subscriber);
+    }
+
+    // Called after each new snapshot (UI repaint). Call before start
+    pub fn on_snapshot(&mut self, callback: Box<dyn Fn() + Send + Sync>) {
+        self.on_snapshot = Some(callback);
+    }
+
+    // Latest published copy of the state. Cheap, takes no locks
+    pub fn snapshot(&self) -> Arc<StateSnapshot> {
+        self.snapshot.load_full()
+    }
+
+    // Build the pipeline and connect all accounts
+    pub fn start(&mut self, credentials: &[Credentials]) {
+        let publisher = self.pipeline(true);
+        let bus = EventBus::new(publisher, self.versions.clone());
+        let snapshot_publisher = SnapshotPublisher {
+            connections: Arc::clone(&self.connections),
+            portfolios: Arc::clone(&self.portfolios),
+            orders: Arc::clone(&self.orders),
+            order_books: Arc::clone(&self.order_books),
+            quotes: Arc::clone(&self.quotes),
+            tickers: Arc::clone(&self.tickers),
+            bus: bus.clone(),
+            versions: self.versions.clone(),
+            snapshot: Arc::clone(&self.snapshot),
+        };
+        tokio::spawn(snapshot_publisher.run(self.on_snapshot.take()));
+        self.bus = Some(bus);
+        for credentials in credentials.iter() {
+            if let Err(e) = self.connect(credentials) {
+                let _ = self.errors_sender.send(format!("Failed to connect to {}: {}", credentials.id, e));
+            }
+        }
+    }
+
+    // Replay a recording through the pipeline instead of connecting accounts. Stop-losses raise
+    // their close alerts, but no orders are sent
+    pub async fn replay(&mut self, source: &mut ReplaySource) {
+        let mut publisher = self.pipeline(false);
+        source.run(&mut publisher).await;
+        self.versions.mark_all();
+    }
+
+    // Stages of the pipeline under the raw message subscribers. Without closer the stop-losses only alert
+    fn pipeline(&mut self, closer: bool) -> ServerMessagesPublisher {
+        let mut portfolio_updater = PortfolioUpdater::new(Arc::clone(&self.portfolios), Arc::clone(&self.config), Arc::clone(&self.candles), Arc::clone(&self.quotes), Arc::clone(&self.orders));
+        if closer {
+            portfolio_updater.subscribe(Box::new(StopLossCloser::new(Arc::clone(&self.portfolios), Arc::clone(&self.connections), self.order_tracker.clone())));
+        }
+        let mut data_processor = DataProcessor::new(
+            self.data_sender.clone(),
+            Arc::clone(&self.order_books),
+            Arc::clone(&self.quotes),
+            Arc::clone(&self.orders),
+            Arc::clone(&self.tickers),
+            Arc::clone(&self.days_to_expiration),
+            Arc::clone(&self.connections),
+        );
+        data_processor.subscribe(Box::new(portfolio_updater));
+        data_processor.subscribe(Box::new(QuotesRequester::new(Arc::clone(&self.connections), Arc::clone(&self.order_books))));
+        let mut market_data_publisher = MarketDataPublisher::new(self.data_sender.clone());
+        market_data_publisher.subscribe(Box::new(CandleBuilder::new(Arc::clone(&self.candles), Arc::clone(&self.config))));
+        market_data_publisher.subscribe(Box::new(data_processor));
+        let mut publisher = std::mem::take(&mut self.server_messages_publisher);
+        publisher.subscribe(Box::new(market_data_publisher));
+        publisher
+    }
+
+    // Queue counters of the accounts
+    pub fn queue_stats(&self) -> Vec<(String, QueueStats)> {
+        self.bus.as_ref().map(|bus| bus.stats()).unwrap_or_default()
+    }
+
+    // Connect the account to the bus. The session must be started
+    fn connect(&mut self, credentials: &Credentials) -> Result<()> {
+        let Some(bus) = self.bus.clone() else {
+            return Err(Error::Config(format!("account {} is connected before the session is started", credentials.id)));
+        };
+        let config = self.config.read().unwrap().clone();
+        // Accounts without their own endpoints use the configured ones
+        let mut credentials = credentials.clone();
+        if credentials.endpoints == Endpoints::default() {
+            credentials.endpoints = config.endpoints.clone();
+        }
+        let credentials = &credentials;
+        let (sender_to_connector, connector_receiver) = mpsc::unbounded_channel();
+        let (sender_to_ui, ui_receiver) = mpsc::channel(QUEUE_CAPACITY);
+        let (status_sender, status_receiver) = watch::channel(ConnectionStatus::Disconnected);
+        let broker: Arc<dyn Broker> = if credentials.paper_trading {
+            Arc::new(PaperBroker::new(credentials.clone(), Arc::clone(&self.order_books), Arc::clone(&self.quotes), self.data_sender.clone()))
+        } else {
+            Arc::new(FreedomBroker::new(credentials.clone()))
+        };
+        let connection = Connection::new(credentials.clone(), Arc::clone(&broker), sender_to_connector.clone(), sender_to_ui.clone(), status_receiver, config.tickers.clone());
+
+        // initialising connections with broker
+        let mut connections_write = self.connections.write().unwrap();
+        let tickers_for_initial_requests = connection.query_tickers.clone();
+        connections_write.push(connection);
+        let credentials_clone = credentials.clone();
+        let errors_sender = self.errors_sender.clone();
+        tokio::spawn(async move {
+            if let Err(e) = broker.connect(connector_receiver, sender_to_ui.clone(), status_sender).await {
+                let _ = errors_sender.send(format!("Failed to connect to {}: {}", credentials_clone.id, e));
+            }
+        });
+        // Receiving messages from connector
+        bus.add_account(&credentials.id, ui_receiver);
+        // Sending initial requests
+        let sender = sender_to_connector.clone();
+        let quotes_message = Request::quotes(tickers_for_initial_requests.clone()).message();
+        let order_book_message = Request::order_book(tickers_for_initial_requests.clone()).message();
+        let portfolio_message = Request::portfolio().message();
+        let orders_message = Request::orders().message();
+        for message in [quotes_message, order_book_message, portfolio_message, orders_message] {
+            if let Err(e) = sender.send(message) {
+                eprintln!("Failed to send initial request to {}: {}", credentials.id, e);
+            }
+        }
+        Ok(())
+    }
+
+    // Apply an edited config: watched underlyings are resubscribed, stop parameters are used from the next price update.
+    // Endpoints and UI defaults are used at the next start
+    pub fn apply_config(&self, config: Config) {
+        let old_tickers = self.config.read().unwrap().tickers.clone();
+        {
+            let mut tickers = self.tickers.write().unwrap();
+            tickers.retain(|ticker_options| config.tickers.contains(&ticker_options.ticker));
+            for ticker in config.tickers.iter() {
+                if !tickers.iter().any(|ticker_options| &ticker_options.ticker == ticker) {
+                    tickers.push(TickerOptions::new(ticker.clone()));
+                }
+            }
+        }
+        if old_tickers != config.tickers {
+            // Underlyings of active stops by account
+            let stop_tickers: Vec<(String, String)> = self.portfolios.read().unwrap().iter()
+                .flat_map(|portfolio| portfolio.portfolio.iter()
+                    .filter_map(|position| position.underlying_stop.as_ref())
+                    .map(|underlying_stop| (portfolio.id.clone(), underlying_stop.ticker.clone())))
+                .collect();
+            let mut connections = self.connections.write().unwrap();
+            for connection in connections.iter_mut() {
+                // Tickers of positions and underlyings of their stops stay subscribed
+                let mut query_tickers = config.tickers.clone();
+                let stop_tickers = stop_tickers.iter()
+                    .filter(|(id, _)| id == &connection.credentials.id)
+                    .map(|(_, ticker)| ticker);
+                for ticker in connection.query_tickers.iter().filter(|ticker| !old_tickers.contains(ticker)).chain(stop_tickers) {
+                    if !query_tickers.contains(ticker) {
+                        query_tickers.push(ticker.clone());
+                    }
+                }
+                if let Err(e) = connection.channels.sender_to_connector.send(Request::quotes(query_tickers.clone()).message()) {
+                    eprintln!("Failed to resubscribe {}: {}", connection.credentials.id, e);
+                }
+                connection.query_tickers = query_tickers;
+                request_order_books(connection, &self.order_books);
+            }
+        }
+        *self.config.write().unwrap() = config;
+        self.versions.tickers.mark();
+        self.versions.order_books.mark();
+    }
+
+    // Apply a change to a position of the account
+    fn update_position(&self, id: &str, ticker: &str, update: impl FnOnce(&mut Position)) {
+        let mut portfolios = self.portfolios.write().unwrap();
+        let position = portfolios.iter_mut()
+            .find(|portfolio| portfolio.id == id)
+            .and_then(|portfolio| portfolio.portfolio.iter_mut().find(|position| position.ticker == ticker));
+        if let Some(position) = position {
+            update(position);
+        }
+        drop(portfolios);
+        self.versions.portfolios.mark();
+    }
+    pub fn set_sl_strategy(&self, id: &str, ticker: &str, sl_strategy: SLStrategy) {
+        self.update_position(id, ticker, |position| {
+            // Manual stops start from the current stop, which is no longer moved
+            if sl_strategy == SLStrategy::ManualStops && position.sl_price != 0.0 {
+                position.change_stop(SLType::Manual, position.sl_price, "strategy manual");
+            }
+            position.sl_strategy = sl_strategy;
+        });
+    }
+    // Stop of a position with manual stops. Zero removes it
+    pub fn set_manual_stop(&self, id: &str, ticker: &str, stop: ManualStop) -> Result<()> {
+        let mut result = Err(Error::InvalidInput(format!("No position {} on account {}", ticker, id)));
+        self.update_position(id, ticker, |position| {
+            let sl_price = stop.price(position.open_price);
+            result = if position.sl_strategy != SLStrategy::ManualStops {
+                Err(Error::InvalidInput(format!("Stops of {} are not manual", ticker)))
+            } else if sl_price < 0.0 || (position.current_price != 0.0 && sl_price >= position.current_price) {
+                Err(Error::InvalidInput(format!("Stop {:.2} of {} must be between 0 and the current price {:.2}", sl_price, ticker, position.current_price)))
+            } else {
+                let sl_type = if sl_price == 0.0 { SLType::None } else { SLType::Manual };
+                position.change_stop(sl_type, sl_price, "manual");
+                Ok(())
+            };
+        });
+        result
+    }
+    // Close the position when its underlying trades beyond the level. None removes the stop
+    pub fn set_underlying_stop(&self, id: &str, ticker: &str, level: Option<f64>) -> Result<()> {
+        let underlying = underlying_ticker(ticker);
+        let underlying_price = self.quotes.read().unwrap().iter()
+            .find(|quote_book| quote_book.id == id)
+            .and_then(|quote_book| quote_book.quote(&underlying))
+            .and_then(|quote| quote.last_trade);
+        let mut result = Err(Error::InvalidInput(format!("No position {} on account {}", ticker, id)));
+        self.update_position(id, ticker, |position| {
+            let underlying_stop = level.map(|level| UnderlyingStop::new(ticker, position.quantity, level));
+            result = match &underlying_stop {
+                Some(underlying_stop) if underlying_price.is_some_and(|price| underlying_stop.triggered(price)) =>
+                    Err(Error::InvalidInput(format!("{} already trades {} {:.2}", underlying, underlying_stop.trigger.description(), underlying_stop.level))),
+                _ => {
+                    let from = position.underlying_stop.as_ref().map_or(0.0, |underlying_stop| underlying_stop.level);
+                    let reason = match &underlying_stop {
+                        Some(underlying_stop) => format!("underlying {} {}", underlying, underlying_stop.trigger.description()),
+                        None => format!("underlying {} removed", underlying),
+                    };
+                    position.record_stop_change(SLType::Underlying, from, level.unwrap_or(0.0), &reason);
+                    position.underlying_stop = underlying_stop;
+                    position.underlying_price = underlying_price;
+                    Ok(())
+                }
+            };
+        });
+        if result.is_ok() && level.is_some() {
+            self.subscribe_quotes(id, &underlying);
+        }
+        result
+    }
+    // Quotes of a ticker that is not subscribed yet, the underlying of a stop
+    fn subscribe_quotes(&self, id: &str, ticker: &str) {
+        let mut connections = self.connections.write().unwrap();
+        if let Some(connection) = connections.iter_mut().find(|connection| connection.credentials.id == id) {
+            if !connection.query_tickers.iter().any(|query_ticker| query_ticker == ticker) {
+                connection.query_tickers.push(ticker.to_string());
+                if let Err(e) = connection.channels.sender_to_connector.send(Request::quotes(connection.query_tickers.clone()).message()) {
+                    eprintln!("Failed to send quotes request message: {}", e);
+                }
+            }
+        }
+    }
+    // Move the stop one step closer to the price
+    pub fn upgrade_stop(&self, id: &str, ticker: &str) -> Result<()> {
+        let config = self.config.read().unwrap().clone();
+        let (min_step, volatility) = self.quotes.read().unwrap().iter()
+            .find(|quote_book| quote_book.id == id)
+            .and_then(|quote_book| quote_book.quote(ticker))
+            .map_or((None, None), |quote| (quote.min_step, quote.volatility));
+        // Stops are at the time of the account's last message, as in the pipeline
+        let now = self.portfolios.read().unwrap().iter()
+            .find(|portfolio| portfolio.id == id)
+            .map_or_else(chrono::Local::now, |portfolio| portfolio.updated_at);
+        let mut result = Err(Error::InvalidInput(format!("No position {} on account {}", ticker, id)));
+        self.update_position(id, ticker, |position| {
+            // Without the volatility of a ticker in the volatility mode there is no step to move by
+            let Some(parameters) = config.stop_parameters(ticker).in_points(position.open_price, min_step, volatility) else {
+                result = Err(Error::InvalidInput(format!("Stops of {} are in volatility, which is not yet available", ticker)));
+                return;
+            };
+            let context = StopContext {
+                parameters,
+                rules: &config.rules,
+                candle_stop: None,
+                now,
+            };
+            (position.sl_type, position.sl_price) = upgrade_sl(position, &context);
+            result = Ok(());
+        });
+        result
+    }
+    // Market order closing the position
+    pub fn close_position(&self, id: &str, ticker: &str) {
+        let broker = self.connections.read().unwrap().iter()
+            .find(|connection| connection.broker.id() == id)
+            .map(|connection| Arc::clone(&connection.broker));
+        if let Some(broker) = broker {
+            self.update_position(id, ticker, |position| self.order_tracker.close_position(broker, position));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{DistanceMode, StopParameters};
+    use crate::mock_server::order_book_messages;
+    use crate::recorder::{load_messages_log, RecordedMessage, ReplaySpeed};
+
+    // Session with one position of the insurance stops strategy at 2.10, opened at 2.00
+    fn test_session() -> TradingSession {
+        let (data_sender, _data_receiver) = mpsc::channel(100);
+        let (errors_sender, _errors_receiver) = mpsc::unbounded_channel();
+        let session = TradingSession::new(Config::default(), data_sender, errors_sender);
+        let mut portfolio = Portfolio::new("test");
+        let mut position = Position::new(1, "+SPY.18OCT2024.P580", 2, 2.00);
+        position.current_price = 2.10;
+        position.pnl = (position.current_price - position.open_price) * position.quantity as f64;
+        portfolio.portfolio.push(position);
+        session.portfolios.write().unwrap().push(portfolio);
+        session
+    }
+
+    fn session_position(session: &TradingSession) -> Position {
+        session.portfolios.read().unwrap()[0].portfolio[0].clone()
+    }
+
+    #[test]
+    fn manual_stop_is_validated() {
+        let session = test_session();
+        let ticker = "+SPY.18OCT2024.P580";
+        let invalid_input = |result: Result<()>| matches!(result, Err(Error::InvalidInput(_)));
+        // Insurance stops are not set by hand
+        assert!(invalid_input(session.set_manual_stop("test", ticker, ManualStop::Price(1.90))));
+        session.set_sl_strategy("test", ticker, SLStrategy::ManualStops);
+        assert!(invalid_input(session.set_manual_stop("test", "SPY.US", ManualStop::Price(1.90))));
+        assert!(invalid_input(session.set_manual_stop("other", ticker, ManualStop::Price(1.90))));
+        // At or above the current price, and below zero
+        assert!(invalid_input(session.set_manual_stop("test", ticker, ManualStop::Price(2.10))));
+        assert!(invalid_input(session.set_manual_stop("test", ticker, ManualStop::Offset(2.50))));
+        let rejected = session_position(&session);
+        assert_eq!((rejected.sl_type, rejected.sl_price), (SLType::None, 0.0));
+        assert!(rejected.stop_changes.is_empty());
+
+        session.set_manual_stop("test", ticker, ManualStop::Offset(0.15)).unwrap();
+        let position = session_position(&session);
+        assert_eq!(position.sl_type, SLType::Manual);
+        assert!((position.sl_price - 1.85).abs() < 1e-9);
+        assert_eq!(position.stop_changes.last().map(|change| change.reason.as_str()), Some("manual"));
+        // Zero removes the stop
+        session.set_manual_stop("test", ticker, ManualStop::Price(0.0)).unwrap();
+        assert_eq!(session_position(&session).sl_type, SLType::None);
+    }
+
+    #[tokio::test]
+    async fn stop_is_checked_before_the_volatility_arrives() {
+        let (data_sender, _data_receiver) = mpsc::channel(100);
+        let (errors_sender, _errors_receiver) = mpsc::unbounded_channel();
+        let config = Config { stops: StopParameters { mode: DistanceMode::Volatility, ..StopParameters::default() }, ..Config::default() };
+        let mut session = TradingSession::new(config, data_sender, errors_sender);
+        let replay = |messages: Vec<String>| ReplaySource::new(messages.into_iter()
+            .map(|data| RecordedMessage { id: "test".to_string(), ts: 0, data })
+            .collect(), ReplaySpeed::Stepped);
+        let portfolio = r#"["portfolio",{"loaded":true,"m_id":"test","acc":[],"pos":[{"i":"SPY.US","q":10,"acc_pos_id":1,"price_a":500.0}]}]"#;
+        let order_book = order_book_messages("SPY.US", &[(500.0, 500.05), (499.85, 499.9)]);
+        session.replay(&mut replay(vec![portfolio.to_string(), order_book[0].clone()])).await;
+        session.set_sl_strategy("test", "SPY.US", SLStrategy::ManualStops);
+        session.set_manual_stop("test", "SPY.US", ManualStop::Price(499.9)).unwrap();
+        // No quote has brought the volatility, the stop can't be moved
+        assert!(matches!(session.upgrade_stop("test", "SPY.US"), Err(Error::InvalidInput(_))));
+        assert!(!session_position(&session).close_alert);
+
+        session.replay(&mut replay(vec![order_book[1].clone()])).await;
+        let position = session_position(&session);
+        assert_eq!((position.sl_type, position.sl_price, position.close_alert), (SLType::Manual, 499.9, true));
+    }
+
+    #[tokio::test]
+    async fn positions_get_the_best_bid() {
+        let (data_sender, _data_receiver) = mpsc::channel(100);
+        let (errors_sender, _errors_receiver) = mpsc::unbounded_channel();
+        let mut session = TradingSession::new(Config::default(), data_sender, errors_sender);
+        let portfolio = r#"["portfolio",{"loaded":true,"m_id":"test","acc":[],"pos":[{"i":"SPY.US","q":10,"acc_pos_id":1,"price_a":582.0}]}]"#;
+        // The quote and the SPY.US snapshot of three levels a side
+        let recording = load_messages_log(concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/order_book.log")).unwrap();
+        let messages = std::iter::once(portfolio.to_string())
+            .chain(recording.into_iter().take(2).map(|message| message.data))
+            .map(|data| RecordedMessage { id: "test".to_string(), ts: 0, data })
+            .collect();
+        session.replay(&mut ReplaySource::new(messages, ReplaySpeed::Stepped)).await;
+        // Not the deepest bid inserted last
+        assert_eq!(session_position(&session).current_price, 582.0);
+    }
+}
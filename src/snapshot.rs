@@ -0,0 +1,153 @@
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use arc_swap::ArcSwap;
+use crate::api::{Connection, ConnectionStatus};
+use crate::broker::Broker;
+use crate::bus::{EventBus, QueueStats};
+use crate::crypto_utils::Credentials;
+use crate::market_data::MarketData;
+use crate::processed_data::{OrderBook, OrderStore, Portfolio, QuoteBook};
+use crate::trading_utils::TickerOptions;
+
+// Shortest time between two snapshots
+pub const SNAPSHOT_INTERVAL: Duration = Duration::from_millis(50);
+
+// Counter of state changes. Writers mark it, the snapshot publisher compares it with the published version
+#[derive(Clone, Default)]
+pub struct StateVersion(Arc<AtomicU64>);
+impl StateVersion {
+    pub fn mark(&self) {
+        self.0.fetch_add(1, Ordering::Release);
+    }
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+// Versions of the collections of the state. A snapshot copies only the collections marked since the previous one
+#[derive(Clone, Default)]
+pub struct StateVersions {
+    pub portfolios: StateVersion,
+    pub orders: StateVersion,
+    pub order_books: StateVersion,
+    pub quotes: StateVersion,
+    pub tickers: StateVersion,
+}
+impl StateVersions {
+    pub fn mark_all(&self) {
+        for version in self.all() {
+            version.mark();
+        }
+    }
+    // Collections changed by a processed server message. PortfolioUpdater sees every message of the account
+    pub fn mark_message(&self, market_data: Option<&MarketData>) {
+        let Some(market_data) = market_data else { return };
+        match market_data {
+            MarketData::OrderBookMessage(_) => {
+                self.order_books.mark();
+                self.tickers.mark();
+            }
+            MarketData::QuoteMessage(_) => self.quotes.mark(),
+            MarketData::OrdersMessage(_) => self.orders.mark(),
+            MarketData::PortfolioMessage(_) => {}
+        }
+        self.portfolios.mark();
+    }
+    fn all(&self) -> [&StateVersion; 5] {
+        [&self.portfolios, &self.orders, &self.order_books, &self.quotes, &self.tickers]
+    }
+    fn get(&self) -> [u64; 5] {
+        self.all().map(|version| version.get())
+    }
+}
+
+#[derive(Clone)]
+pub struct ConnectionView {
+    pub credentials: Credentials,
+    pub status: ConnectionStatus,
+    pub broker: Arc<dyn Broker>,
+}
+
+// Immutable copy of the session state for readers that must not compete with the pipeline for the locks.
+// Collections unchanged since the previous snapshot are shared with it
+#[derive(Clone, Default)]
+pub struct StateSnapshot {
+    pub version: u64, // Sum of the collection versions
+    pub connections: Vec<ConnectionView>,
+    pub portfolios: Arc<Vec<Portfolio>>,
+    pub orders: Arc<Vec<OrderStore>>,
+    pub order_books: Arc<Vec<OrderBook>>,
+    pub quotes: Arc<Vec<QuoteBook>>,
+    pub tickers: Arc<Vec<TickerOptions>>,
+    pub queue_stats: Vec<(String, QueueStats)>,
+}
+
+// The collection of the previous snapshot if it is unchanged, a new copy otherwise
+fn copy<T: Clone>(changed: bool, previous: &Arc<Vec<T>>, collection: &RwLock<Vec<T>>) -> Arc<Vec<T>> {
+    if changed {
+        Arc::new(collection.read().unwrap().clone())
+    } else {
+        Arc::clone(previous)
+    }
+}
+
+// Copies the changed collections into a new snapshot, at most once per SNAPSHOT_INTERVAL
+pub struct SnapshotPublisher {
+    pub connections: Arc<RwLock<Vec<Connection>>>,
+    pub portfolios: Arc<RwLock<Vec<Portfolio>>>,
+    pub orders: Arc<RwLock<Vec<OrderStore>>>,
+    pub order_books: Arc<RwLock<Vec<OrderBook>>>,
+    pub quotes: Arc<RwLock<Vec<QuoteBook>>>,
+    pub tickers: Arc<RwLock<Vec<TickerOptions>>>,
+    pub bus: EventBus,
+    pub versions: StateVersions,
+    pub snapshot: Arc<ArcSwap<StateSnapshot>>,
+}
+impl SnapshotPublisher {
+    // versions are read before the copies, so a change made during the capture is copied again next time
+    fn capture(&self, versions: [u64; 5], published: Option<[u64; 5]>, statuses: Vec<ConnectionStatus>) -> StateSnapshot {
+        let changed = |index: usize| published.is_none_or(|published| published[index] != versions[index]);
+        let previous = self.snapshot.load();
+        let connections = self.connections.read().unwrap().iter().zip(statuses)
+            .map(|(connection, status)| ConnectionView {
+                credentials: connection.credentials.clone(),
+                status,
+                broker: Arc::clone(&connection.broker),
+            })
+            .collect();
+        StateSnapshot {
+            version: versions.iter().sum(),
+            connections,
+            portfolios: copy(changed(0), &previous.portfolios, &self.portfolios),
+            orders: copy(changed(1), &previous.orders, &self.orders),
+            order_books: copy(changed(2), &previous.order_books, &self.order_books),
+            quotes: copy(changed(3), &previous.quotes, &self.quotes),
+            tickers: copy(changed(4), &previous.tickers, &self.tickers),
+            queue_stats: self.bus.stats(),
+        }
+    }
+
+    // Publish snapshots until the runtime stops. on_snapshot is called after each one (UI repaint)
+    pub async fn run(self, on_snapshot: Option<Box<dyn Fn() + Send + Sync>>) {
+        let mut interval = tokio::time::interval(SNAPSHOT_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut published: Option<([u64; 5], Vec<ConnectionStatus>)> = None;
+        loop {
+            interval.tick().await;
+            let versions = self.versions.get();
+            let statuses: Vec<ConnectionStatus> = self.connections.read().unwrap().iter()
+                .map(|connection| *connection.status.borrow())
+                .collect();
+            if published.as_ref() == Some(&(versions, statuses.clone())) {
+                continue;
+            }
+            let snapshot = self.capture(versions, published.as_ref().map(|(versions, _)| *versions), statuses.clone());
+            self.snapshot.store(Arc::new(snapshot));
+            published = Some((versions, statuses));
+            if let Some(on_snapshot) = &on_snapshot {
+                on_snapshot();
+            }
+        }
+    }
+}
@@ -0,0 +1,303 @@
+use serde::{Deserialize, Serialize};
+use crate::config::StopParameters;
+use crate::error::{Error, Result};
+use crate::processed_data::Position;
+use crate::trading_utils::SLType;
+
+// Tolerance of profit comparisons against rounding of the prices (2.11 - 2.00 < 0.11)
+const PRICE_EPSILON: f64 = 1e-9;
+
+// Condition of entering a stage. Profits are per unit, above the open price
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Condition {
+    Profit(f64), // Price points
+    Ticks(u32), // Ticks of the rule set's tick size
+    Percent(f64), // Percent of the open price
+    TimeInTrade(u64), // Seconds since the position was opened
+}
+impl Condition {
+    fn holds(&self, position: &Position, tick_size: f64, now: chrono::DateTime<chrono::Local>) -> bool {
+        let profit = position.current_price - position.open_price;
+        match self {
+            Condition::Profit(points) => profit + PRICE_EPSILON >= *points,
+            Condition::Ticks(ticks) => profit + PRICE_EPSILON >= *ticks as f64 * tick_size,
+            Condition::Percent(percent) => position.open_price > 0.0 && profit + PRICE_EPSILON >= position.open_price * percent / 100.0,
+            Condition::TimeInTrade(seconds) => (now - position.opened_at).num_seconds() >= *seconds as i64,
+        }
+    }
+}
+
+// Stop price of a stage
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum StopFormula {
+    BelowPrice(f64), // Under the current price
+    BelowOpen(f64), // Under the open price
+    AboveOpen(f64), // Over the open price
+    LockProfit(f64), // Share of the profit per unit over the open price
+    Lowest(Vec<StopFormula>),
+}
+impl StopFormula {
+    fn price(&self, position: &Position) -> f64 {
+        match self {
+            StopFormula::BelowPrice(offset) => position.current_price - offset,
+            StopFormula::BelowOpen(offset) => position.open_price - offset,
+            StopFormula::AboveOpen(offset) => position.open_price + offset,
+            StopFormula::LockProfit(share) => position.open_price + (position.current_price - position.open_price) * share,
+            StopFormula::Lowest(formulas) => formulas.iter().map(|formula| formula.price(position)).fold(f64::INFINITY, f64::min),
+        }
+    }
+    fn validate(&self) -> std::result::Result<(), String> {
+        match self {
+            StopFormula::BelowPrice(value) | StopFormula::BelowOpen(value) | StopFormula::AboveOpen(value) if !value.is_finite() || *value < 0.0 =>
+                Err(format!("offset {} must be a positive number", value)),
+            StopFormula::LockProfit(share) if !(0.0..=1.0).contains(share) => Err(format!("share {} must be between 0 and 1", share)),
+            StopFormula::Lowest(formulas) if formulas.is_empty() => Err("lowest of no formulas".to_string()),
+            StopFormula::Lowest(formulas) => formulas.iter().try_for_each(|formula| formula.validate()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Stage {
+    pub sl_type: SLType, // Shown for the positions in the stage, unique in the rule set
+    #[serde(default)]
+    pub when: Vec<Condition>, // All of them move the position from the previous stage
+    pub stop: StopFormula, // Set on entering the stage
+    #[serde(default)]
+    pub trail: Option<StopFormula>, // Recomputed on every price, the stop only moves up
+}
+
+// Stop-loss strategy as a sequence of stages. A position enters at most one stage per price
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RuleSet {
+    #[serde(default = "default_tick_size")]
+    pub tick_size: f64,
+    pub stages: Vec<Stage>,
+}
+fn default_tick_size() -> f64 {
+    0.01
+}
+impl RuleSet {
+    // Insurance stops: loss limiter, break-even, then trailing half of the profit
+    pub fn insurance(parameters: &StopParameters) -> Self {
+        RuleSet {
+            tick_size: default_tick_size(),
+            stages: vec![
+                Stage {
+                    sl_type: SLType::LossLimiter,
+                    when: Vec::new(),
+                    stop: StopFormula::Lowest(vec![StopFormula::BelowPrice(parameters.loss_limit), StopFormula::BelowOpen(parameters.loss_limit)]),
+                    trail: Some(StopFormula::BelowPrice(parameters.loss_limit)),
+                },
+                Stage {
+                    sl_type: SLType::BreakEven,
+                    when: vec![Condition::Profit(parameters.break_even_trigger)],
+                    stop: StopFormula::AboveOpen(parameters.break_even_offset),
+                    trail: None,
+                },
+                Stage {
+                    sl_type: SLType::TrailingStop,
+                    when: vec![Condition::Profit(parameters.trailing_trigger)],
+                    stop: StopFormula::LockProfit(0.5),
+                    trail: Some(StopFormula::LockProfit(0.5)),
+                },
+            ],
+        }
+    }
+
+    pub fn validate(&self, name: &str) -> Result<()> {
+        let invalid = |message: String| Error::Config(format!("Rule set {}: {}", name, message));
+        if self.stages.is_empty() {
+            return Err(invalid("no stages".to_string()));
+        }
+        if !self.tick_size.is_finite() || self.tick_size <= 0.0 {
+            return Err(invalid(format!("tick size {} must be positive", self.tick_size)));
+        }
+        for (number, stage) in self.stages.iter().enumerate() {
+            if stage.sl_type == SLType::None || self.stages[..number].iter().any(|other| other.sl_type == stage.sl_type) {
+                return Err(invalid(format!("stop type {} of stage {} must be unique and not none", stage.sl_type.description(), number + 1)));
+            }
+            for condition in stage.when.iter() {
+                if let Condition::Profit(value) | Condition::Percent(value) = condition {
+                    if !value.is_finite() {
+                        return Err(invalid(format!("condition {:?} of stage {} is not a number", condition, number + 1)));
+                    }
+                }
+            }
+            for formula in std::iter::once(&stage.stop).chain(stage.trail.iter()) {
+                formula.validate().map_err(|message| invalid(format!("stage {}: {}", number + 1, message)))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn stage(&self, sl_type: SLType) -> Option<usize> {
+        self.stages.iter().position(|stage| stage.sl_type == sl_type)
+    }
+
+    // Stop type and price after a price update
+    pub fn check(&self, position: &Position, now: chrono::DateTime<chrono::Local>) -> (SLType, f64) {
+        let current = self.stage(position.sl_type);
+        let next = current.map_or(0, |number| number + 1);
+        if let Some(stage) = self.stages.get(next) {
+            if stage.when.iter().all(|condition| condition.holds(position, self.tick_size, now)) {
+                let mut sl_price = stage.stop.price(position);
+                // A stop set outside of the rule set (manual, candle tail) is not lowered
+                if current.is_none() && position.sl_type != SLType::None {
+                    sl_price = f64::max(sl_price, position.sl_price);
+                }
+                return (stage.sl_type, sl_price);
+            }
+        }
+        match current.and_then(|number| self.stages[number].trail.as_ref()) {
+            Some(trail) => (position.sl_type, f64::max(position.sl_price, trail.price(position))),
+            None => (position.sl_type, position.sl_price),
+        }
+    }
+
+    // Enter the next stage without its conditions, if its stop is under the current price
+    pub fn upgrade(&self, position: &Position) -> (SLType, f64) {
+        let next = self.stage(position.sl_type).map_or(0, |number| number + 1);
+        match self.stages.get(next) {
+            Some(stage) if stage.stop.price(position) < position.current_price => (stage.sl_type, stage.stop.price(position)),
+            _ => (position.sl_type, position.sl_price),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn position(open_price: f64) -> Position {
+        let mut position = Position::new(1, "+SPY.18OCT2024.P580", 2, open_price);
+        position.opened_at = chrono::Local::now() - chrono::Duration::seconds(10);
+        position
+    }
+
+    fn set_price(position: &mut Position, current_price: f64) {
+        position.current_price = current_price;
+        position.pnl = (current_price - position.open_price) * position.quantity as f64;
+    }
+
+    // Insurance stops as they were hand-coded before the rule sets
+    fn hand_coded_insurance(position: &Position, parameters: &StopParameters) -> (SLType, f64) {
+        match position.sl_type {
+            SLType::LossLimiter if position.current_price - position.open_price >= parameters.break_even_trigger =>
+                (SLType::BreakEven, position.open_price + parameters.break_even_offset),
+            SLType::LossLimiter if position.current_price - position.sl_price > parameters.loss_limit =>
+                (SLType::LossLimiter, position.current_price - parameters.loss_limit),
+            SLType::BreakEven if position.current_price - position.open_price >= parameters.trailing_trigger =>
+                (SLType::TrailingStop, position.open_price + position.pnl / position.quantity as f64 / 2.0),
+            SLType::TrailingStop => {
+                (SLType::TrailingStop, f64::max(position.sl_price, position.open_price + position.pnl / position.quantity as f64 / 2.0))
+            }
+            SLType::None => (SLType::LossLimiter, f64::min(position.current_price, position.open_price) - parameters.loss_limit),
+            sl_type => (sl_type, position.sl_price),
+        }
+    }
+
+    #[test]
+    fn insurance_rule_set_matches_the_hand_coded_stops() {
+        let parameters = StopParameters::default();
+        let rule_set = RuleSet::insurance(&parameters);
+        let prices = [2.00, 1.95, 1.97, 2.05, 2.08, 2.12, 2.10, 2.15, 2.21, 2.30, 2.26, 2.40, 2.35, 2.31];
+        let mut expected = position(2.00);
+        let mut actual = position(2.00);
+        let mut types = Vec::new();
+        for price in prices {
+            set_price(&mut expected, price);
+            set_price(&mut actual, price);
+            (expected.sl_type, expected.sl_price) = hand_coded_insurance(&expected, &parameters);
+            (actual.sl_type, actual.sl_price) = rule_set.check(&actual, chrono::Local::now());
+            assert_eq!(actual.sl_type, expected.sl_type, "at {}", price);
+            assert!((actual.sl_price - expected.sl_price).abs() < 1e-9, "at {}: {} != {}", price, actual.sl_price, expected.sl_price);
+            types.push(actual.sl_type);
+        }
+        // The path goes through every stage
+        for sl_type in [SLType::LossLimiter, SLType::BreakEven, SLType::TrailingStop] {
+            assert!(types.contains(&sl_type));
+        }
+        assert!((actual.sl_price - 2.20).abs() < 1e-9);
+    }
+
+    #[test]
+    fn one_stage_is_entered_per_price() {
+        let rule_set = RuleSet::insurance(&StopParameters::default());
+        let mut position = position(2.00);
+        set_price(&mut position, 2.50);
+        (position.sl_type, position.sl_price) = rule_set.check(&position, chrono::Local::now());
+        assert_eq!(position.sl_type, SLType::LossLimiter);
+        (position.sl_type, position.sl_price) = rule_set.check(&position, chrono::Local::now());
+        assert_eq!(position.sl_type, SLType::BreakEven);
+        (position.sl_type, position.sl_price) = rule_set.check(&position, chrono::Local::now());
+        assert_eq!((position.sl_type, position.sl_price), (SLType::TrailingStop, 2.25));
+    }
+
+    #[test]
+    fn custom_rule_set_from_config() {
+        let rule_set: RuleSet = serde_json::from_str(r#"{
+            "tick_size": 0.05,
+            "stages": [
+                { "sl_type": "LossLimiter", "stop": { "below_open": 0.2 } },
+                { "sl_type": "BreakEven", "when": [{ "ticks": 3 }, { "time_in_trade": 60 }], "stop": { "above_open": 0.0 } },
+                { "sl_type": { "Stage": 3 }, "when": [{ "percent": 20.0 }], "stop": { "lock_profit": 0.75 }, "trail": { "lock_profit": 0.75 } }
+            ]
+        }"#).unwrap();
+        rule_set.validate("scalp").unwrap();
+        let mut position = position(1.00);
+        set_price(&mut position, 1.20);
+        let now = position.opened_at + chrono::Duration::seconds(30);
+        (position.sl_type, position.sl_price) = rule_set.check(&position, now);
+        assert_eq!((position.sl_type, position.sl_price), (SLType::LossLimiter, 0.8));
+        // Three ticks of profit, but not a minute in the trade yet
+        (position.sl_type, position.sl_price) = rule_set.check(&position, now);
+        assert_eq!(position.sl_type, SLType::LossLimiter);
+        let now = position.opened_at + chrono::Duration::seconds(60);
+        (position.sl_type, position.sl_price) = rule_set.check(&position, now);
+        assert_eq!((position.sl_type, position.sl_price), (SLType::BreakEven, 1.0));
+        (position.sl_type, position.sl_price) = rule_set.check(&position, now);
+        assert_eq!(position.sl_type, SLType::Stage(3));
+        assert!((position.sl_price - 1.15).abs() < 1e-9);
+        // Trails up, never down
+        set_price(&mut position, 1.40);
+        (position.sl_type, position.sl_price) = rule_set.check(&position, now);
+        assert!((position.sl_price - 1.30).abs() < 1e-9);
+        set_price(&mut position, 1.32);
+        (position.sl_type, position.sl_price) = rule_set.check(&position, now);
+        assert!((position.sl_price - 1.30).abs() < 1e-9);
+    }
+
+    #[test]
+    fn upgrade_moves_to_the_next_stage_only_under_the_price() {
+        let rule_set = RuleSet::insurance(&StopParameters::default());
+        let mut position = position(2.00);
+        set_price(&mut position, 2.01);
+        (position.sl_type, position.sl_price) = rule_set.upgrade(&position);
+        assert_eq!(position.sl_type, SLType::LossLimiter);
+        // Break-even at 2.02 would be over the price
+        assert_eq!(rule_set.upgrade(&position).0, SLType::LossLimiter);
+        set_price(&mut position, 2.05);
+        assert_eq!(rule_set.upgrade(&position), (SLType::BreakEven, 2.02));
+    }
+
+    #[test]
+    fn invalid_rule_sets_are_rejected() {
+        let stage = |sl_type, stop| Stage { sl_type, when: Vec::new(), stop, trail: None };
+        let invalid = [
+            RuleSet { tick_size: 0.01, stages: Vec::new() },
+            RuleSet { tick_size: 0.0, stages: vec![stage(SLType::LossLimiter, StopFormula::BelowPrice(0.1))] },
+            RuleSet { tick_size: 0.01, stages: vec![stage(SLType::None, StopFormula::BelowPrice(0.1))] },
+            RuleSet { tick_size: 0.01, stages: vec![stage(SLType::LossLimiter, StopFormula::BelowPrice(0.1)), stage(SLType::LossLimiter, StopFormula::AboveOpen(0.0))] },
+            RuleSet { tick_size: 0.01, stages: vec![stage(SLType::TrailingStop, StopFormula::LockProfit(1.5))] },
+            RuleSet { tick_size: 0.01, stages: vec![stage(SLType::LossLimiter, StopFormula::Lowest(vec![StopFormula::BelowOpen(-0.1)]))] },
+        ];
+        for rule_set in invalid.iter() {
+            assert!(rule_set.validate("invalid").is_err(), "{:?}", rule_set);
+        }
+        assert!(RuleSet::insurance(&StopParameters::default()).validate("insurance").is_ok());
+    }
+}
@@ -1,5 +1,10 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
 use chrono::{Datelike, Local, Weekday, Duration};
+use serde::{Deserialize, Serialize};
+use crate::config::StopParameters;
 use crate::processed_data::{Side, Position};
+use crate::stop_rules::RuleSet;
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct TickerOptions {
@@ -43,12 +48,13 @@ impl TickerOptions {
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum SLStrategy {
     WithoutStops,
     InsuranceStops,
     ManualStops,
     CandleTailsStops,
+    Rules(String), // Rule set of the config
 }
 impl SLStrategy {
     pub fn description(&self) -> &str {
@@ -57,6 +63,7 @@ impl SLStrategy {
             SLStrategy::InsuranceStops => "insurance",
             SLStrategy::ManualStops => "manual",
             SLStrategy::CandleTailsStops => "candle tails",
+            SLStrategy::Rules(name) => name,
         }
     }
     pub const ALL: [SLStrategy; 4] = [
@@ -67,12 +74,16 @@ impl SLStrategy {
     ];
 }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub enum SLType {
     None,
     LossLimiter,
     BreakEven,
     TrailingStop,
+    CandleTail,
+    Manual,
+    Stage(u8), // Stage of a rule set without a type of its own
+    Underlying, // Underlying traded beyond the level of the underlying stop
 }
 impl SLType {
     pub fn description(&self) -> &str {
@@ -81,47 +92,145 @@ impl SLType {
             SLType::LossLimiter => "loss limiter",
             SLType::BreakEven => "break-even",
             SLType::TrailingStop => "trailing stop",
+            SLType::CandleTail => "candle tail",
+            SLType::Manual => "manual",
+            SLType::Stage(_) => "stage",
+            SLType::Underlying => "underlying",
+        }
+    }
+}
+
+// Underlying of an option, QQQ.US for +QQQ.18OCT2024.P480. Other tickers are their own underlying
+pub fn underlying_ticker(ticker: &str) -> String {
+    match ticker.strip_prefix('+').and_then(|option| option.split('.').next()) {
+        Some(root) => format!("{}.US", root),
+        None => ticker.to_string(),
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Trigger {
+    Above,
+    Below,
+}
+impl Trigger {
+    pub fn description(&self) -> &str {
+        match self {
+            Trigger::Above => "above",
+            Trigger::Below => "below",
+        }
+    }
+}
+
+// Underlying stops are not checked on a last trade older than this, the underlying may have moved since
+pub const MAX_UNDERLYING_QUOTE_AGE: Duration = Duration::seconds(60);
+
+// Stop on the price of the underlying: "close the +QQQ put if QQQ.US trades above 482.50"
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnderlyingStop {
+    pub ticker: String, // Underlying
+    pub trigger: Trigger,
+    pub level: f64,
+}
+impl UnderlyingStop {
+    // Long puts lose when the underlying rises, calls and shares when it falls. Short positions lose the other way
+    pub fn new(position_ticker: &str, quantity: i32, level: f64) -> Self {
+        let is_put = position_ticker.starts_with('+') && position_ticker.rsplit('.').next().is_some_and(|strike| strike.starts_with('P'));
+        UnderlyingStop {
+            ticker: underlying_ticker(position_ticker),
+            trigger: if is_put == (quantity >= 0) { Trigger::Above } else { Trigger::Below },
+            level,
+        }
+    }
+    pub fn triggered(&self, underlying_price: f64) -> bool {
+        match self.trigger {
+            Trigger::Above => underlying_price > self.level,
+            Trigger::Below => underlying_price < self.level,
+        }
+    }
+    // How far the underlying may move before the stop triggers
+    pub fn distance(&self, underlying_price: f64) -> f64 {
+        match self.trigger {
+            Trigger::Above => self.level - underlying_price,
+            Trigger::Below => underlying_price - self.level,
+        }
+    }
+}
+
+// Stop entered by the user for manual stops
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ManualStop {
+    Price(f64), // Absolute stop price
+    Offset(f64), // Distance below the open price
+}
+impl ManualStop {
+    pub fn price(&self, open_price: f64) -> f64 {
+        match self {
+            ManualStop::Price(price) => *price,
+            ManualStop::Offset(offset) => open_price - offset,
+        }
+    }
+}
+
+// Everything the stops of a position are computed from, besides the position
+pub struct StopContext<'a> {
+    pub parameters: StopParameters, // Of the position's ticker
+    pub rules: &'a BTreeMap<String, RuleSet>, // Custom rule sets of the config
+    pub candle_stop: Option<f64>, // Tail stop of the ticker's completed candles
+    pub now: chrono::DateTime<Local>,
+}
+impl StopContext<'_> {
+    // Rule set of the strategy, None for strategies that are not staged. Rule sets of the config are borrowed
+    pub fn rule_set(&self, sl_strategy: &SLStrategy) -> Option<Cow<'_, RuleSet>> {
+        match sl_strategy {
+            SLStrategy::InsuranceStops => Some(Cow::Owned(RuleSet::insurance(&self.parameters))),
+            SLStrategy::Rules(name) => self.rules.get(name).map(Cow::Borrowed),
+            _ => None,
         }
     }
 }
 
-pub fn check_sl(position: &Position) -> (SLType, f64, bool) {
-    let sl_strategy = position.sl_strategy;
+// The price is at or through the current stop
+pub fn stop_hit(position: &Position) -> bool {
+    position.current_price <= position.sl_price
+}
+
+pub fn check_sl(position: &Position, context: &StopContext) -> (SLType, f64, bool) {
     let mut sl_type = position.sl_type;
     let mut sl_price = position.sl_price;
     let mut close_alert = position.close_alert;
 
-    if position.current_price <= sl_price {
+    if stop_hit(position) {
         close_alert = true;
     } else {
-        match sl_strategy {
-            SLStrategy::InsuranceStops => {
-                match sl_type {
-                    SLType::None => {
-                        sl_type = SLType::LossLimiter;
-                        sl_price = f64::min(position.current_price, position.open_price) - 0.1;
-                    },
-                    SLType::LossLimiter => {
-                        if (position.current_price - position.open_price) >= 0.11 {
-                            sl_type = SLType::BreakEven;
-                            sl_price = position.open_price + 0.02;
-                        } else if (position.current_price - position.sl_price) > 0.10 {
-                            sl_price = position.current_price - 0.1;
-                        }
+        match &position.sl_strategy {
+            SLStrategy::InsuranceStops | SLStrategy::Rules(_) => {
+                // Rule set removed from the config: the stop stays where it is
+                if let Some(rule_set) = context.rule_set(&position.sl_strategy) {
+                    (sl_type, sl_price) = rule_set.check(position, context.now);
+                }
+            }
+            SLStrategy::CandleTailsStops => {
+                match (sl_type, context.candle_stop) {
+                    // The stop is placed under the tail once, then only trails up bar by bar
+                    (SLType::CandleTail, Some(candle_stop)) => {
+                        sl_price = f64::max(sl_price, candle_stop);
                     },
-                    SLType::BreakEven => {
-                        if (position.current_price - position.open_price) >= 0.2 {
-                            sl_type = SLType::TrailingStop;
-                            sl_price = position.open_price + position.pnl / position.quantity as f64 / 2.0;
-                        }
+                    (_, Some(candle_stop)) if candle_stop < position.current_price => {
+                        sl_type = SLType::CandleTail;
+                        sl_price = candle_stop;
                     },
-                    SLType::TrailingStop => {
-                        let new_sl_price = position.open_price + position.pnl / position.quantity as f64 / 2.0;
-                        if new_sl_price > sl_price { sl_price = new_sl_price; }
+                    // The loss limiter protects the position until a candle tail is below the price
+                    (SLType::None, _) => {
+                        sl_type = SLType::LossLimiter;
+                        sl_price = f64::min(position.current_price, position.open_price) - context.parameters.loss_limit;
                     },
+                    _ => {}
                 }
             }
-            _ => {
+            // The stop is set by the user, only the alert is checked
+            SLStrategy::ManualStops => {}
+            SLStrategy::WithoutStops => {
                 sl_type = SLType::None;
                 sl_price = 0.0;
                 close_alert = false;
@@ -130,34 +239,43 @@ pub fn check_sl(position: &Position) -> (SLType, f64, bool) {
     }
     (sl_type, sl_price, close_alert)
 }
-pub fn upgrade_sl(position: &Position) -> (SLType, f64) {
-    let sl_strategy = position.sl_strategy;
-    let mut sl_type = position.sl_type;
-    let mut sl_price = position.sl_price;
+// Move the stop to the next stage of the rule set
+pub fn upgrade_sl(position: &Position, context: &StopContext) -> (SLType, f64) {
+    match context.rule_set(&position.sl_strategy) {
+        Some(rule_set) => rule_set.upgrade(position),
+        None => (position.sl_type, position.sl_price),
+    }
+}
 
-    match sl_strategy {
-        SLStrategy::InsuranceStops => {
-            match sl_type {
-                SLType::None => {
-                    sl_type = SLType::LossLimiter;
-                    sl_price = f64::min(position.current_price, position.open_price) - 0.1;
-                },
-                SLType::LossLimiter => {
-                    sl_type = SLType::BreakEven;
-                    sl_price = position.open_price + 0.02;
-                },
-                SLType::BreakEven => {
-                    if position.pnl >= 0.02 {
-                        sl_type = SLType::TrailingStop;
-                        sl_price = position.open_price + position.pnl / position.quantity as f64 / 2.0;
-                    }
-                },
-                SLType::TrailingStop => {
-                },
-            }
-        }
-        _ => {}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set_price(position: &mut Position, current_price: f64) {
+        position.current_price = current_price;
+        position.pnl = (current_price - position.open_price) * position.quantity as f64;
     }
 
-    (sl_type, sl_price)
-}
\ No newline at end of file
+    #[test]
+    fn manual_stop_offset_is_below_the_open_price() {
+        assert!((ManualStop::Offset(0.15).price(2.00) - 1.85).abs() < 1e-9);
+        assert_eq!(ManualStop::Price(1.80).price(2.00), 1.80);
+        assert!(ManualStop::Offset(2.50).price(2.00) < 0.0);
+    }
+
+    #[test]
+    fn manual_stop_raises_the_close_alert() {
+        let mut position = Position::new(1, "+SPY.18OCT2024.P580", 2, 2.00);
+        position.sl_strategy = SLStrategy::ManualStops;
+        position.change_stop(SLType::Manual, 1.95, "manual");
+        let rules = BTreeMap::new();
+        let context = StopContext { parameters: StopParameters::default(), rules: &rules, candle_stop: None, now: Local::now() };
+        // The stop is not moved by the price
+        set_price(&mut position, 2.50);
+        (position.sl_type, position.sl_price, position.close_alert) = check_sl(&position, &context);
+        assert_eq!((position.sl_type, position.sl_price, position.close_alert), (SLType::Manual, 1.95, false));
+        set_price(&mut position, 1.95);
+        (position.sl_type, position.sl_price, position.close_alert) = check_sl(&position, &context);
+        assert_eq!((position.sl_type, position.close_alert), (SLType::Manual, true));
+    }
+}